@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use touch_rust::socks::socks5::{ParseError, Socks5};
+
+fuzz_target!(|data: &[u8]| {
+    match Socks5::parse_addr(data) {
+        Ok((info, size)) => {
+            assert!(size <= data.len());
+            // The parsed address is encoded back to the same bytes.
+            let encoded = Socks5::socks5_addr_arr(&info.address, info.port, &info.address_type).unwrap();
+            assert_eq!(&*encoded, &data[..size]);
+        }
+        Err(ParseError::Incomplete(size)) => assert!(size > data.len()),
+        Err(_) => {}
+    }
+});
@@ -6,10 +6,11 @@ use std::result::Result::Err;
 
 use log::error;
 
-use crate::core::profile::{Profile, ProtocolConf};
+use crate::core::profile::{DnsConfig, Profile, ProtocolConf};
 
 pub struct ConfigReader {
-    pub input: ProtocolConf,
+    pub dns: Option<DnsConfig>,
+    pub input: Vec<ProtocolConf>,
     pub output: ProtocolConf,
 }
 
@@ -18,14 +19,15 @@ impl ConfigReader {
     pub fn read_config(path: &Path) -> io::Result<Self> {
         let profile = read_file(path)?;
         Ok(Self {
-            input: profile.input,
+            dns: profile.dns.map(|dns| dns.into_config()),
+            input: profile.input.into_vec(),
             output: profile.output,
         })
     }
 }
 
 fn read_file(path: &Path) -> io::Result<Profile> {
-    let file_max_size: u64 = 1 * 1024 * 1024;
+    let file_max_size: u64 = 1024 * 1024;
     let file = File::open(path)?;
     let metadata = file.metadata()?;
     if metadata.len() > file_max_size {
@@ -1,26 +1,73 @@
 use std::io;
 use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+
+use futures::future::try_join_all;
+use log::info;
 
 use crate::core::config::ConfigReader;
-use crate::core::profile::{BaseActiveConfig, BasePassiveConfig, ConnectMode, ProtocalType, ProtocolConf, RawActiveConfig};
+#[cfg(feature = "rust-crypto")]
+use crate::core::profile::VmessActiveConfig;
+use crate::core::profile::{
+    BaseActiveConfig, BasePassiveConfig, ConnectMode, DialConfig, DnsPassiveConfig, HttpActiveConfig, OneOrMany, ProtocalType,
+    ProtocolConf, RawActiveConfig,
+};
 use crate::encrypt::aead::AeadType;
-use crate::net::proxy::{InputProxy, OutputProxy};
+use crate::encrypt::stream::StreamCipherType;
+use crate::net::dialer::Dialer;
+use crate::net::dns::{DnsResolver, DnsServer, FakeIpOutProxy};
+use crate::net::http::HttpActive;
+use crate::net::plugin::Plugin;
+use crate::net::proxy::{InputProxy, OutProxyStarter, OutputProxy};
 use crate::net::raw::RawActive;
-use crate::net::socks5::Socks5Passive;
+use crate::net::socks4::Socks4Passive;
+use crate::net::socks5::{Socks5Active, Socks5Passive};
 use crate::net::ss_stream::{SsInputProxy, SsOutProxy};
+use crate::net::ss_stream_cipher::{SsCipherInputProxy, SsCipherOutProxy};
+use crate::net::transport::ClientTransport;
+use crate::net::trojan::{TrojanActive, TrojanPassive};
+use crate::net::vless::{VlessActive, VlessPassive};
+#[cfg(feature = "rust-crypto")]
+use crate::net::vmess::VmessActive;
 
 pub struct ProtocolSelector {}
 
 impl ProtocolSelector {
-    pub async fn select(config_reader: &ConfigReader) -> io::Result<()> {
-        let output_proxy = select_output(&config_reader.output)?;
-        let mut input_proxy = select_input(&config_reader.input, output_proxy).await?;
-        // Start proxy
-        input_proxy.start().await?;
+    pub async fn select(config_reader: ConfigReader) -> io::Result<()> {
+        if let Some(dns) = config_reader.dns {
+            DnsResolver::init_global(dns)?;
+        }
+        // The output is built once, so a plugin process is started once for all inputs.
+        let output_proxy = SharedOutput(Arc::new(Mutex::new(select_output(&config_reader.output)?)));
+        let mut input_proxies = Vec::with_capacity(config_reader.input.len());
+        for input in &config_reader.input {
+            input_proxies.push(select_input(input, Box::new(output_proxy.clone())).await?);
+        }
+        // Start proxies, stop all of them if one fails.
+        // They are also dropped on a signal, which kills the plugins.
+        tokio::select! {
+            result = try_join_all(input_proxies.iter_mut().map(|input_proxy| input_proxy.start())) => {
+                result?;
+            }
+            result = shutdown_signal() => {
+                result?;
+                info!("Shutdown");
+            }
+        }
         Ok(())
     }
 }
 
+/// One output proxy used by every input.
+#[derive(Clone)]
+struct SharedOutput(Arc<Mutex<Box<dyn OutputProxy + Send>>>);
+
+impl OutputProxy for SharedOutput {
+    fn gen_connector(&mut self) -> io::Result<Box<dyn OutProxyStarter>> {
+        self.0.lock().unwrap().gen_connector()
+    }
+}
+
 /// Select the output proxy and initialize it.
 fn select_output(output: &ProtocolConf) -> io::Result<Box<dyn OutputProxy + Send>> {
     let output_name = &output.name;
@@ -29,32 +76,109 @@ fn select_output(output: &ProtocolConf) -> io::Result<Box<dyn OutputProxy + Send
         ConnectMode::Active => {
             match output_name {
                 // Shadowsocks AEAD
-                ProtocalType::SsAes256Gcm | ProtocalType::SsAes128Gcm | ProtocalType::Chacha20Poly1305 => {
+                ProtocalType::SsAes128Gcm
+                | ProtocalType::SsAes192Gcm
+                | ProtocalType::SsAes256Gcm
+                | ProtocalType::Chacha20Poly1305
+                | ProtocalType::XChacha20Poly1305 => {
                     let config: BaseActiveConfig = serde_json::from_value(output.config.clone())?;
-                    Box::new(SsOutProxy::new(
-                        config.remote_host,
-                        config.remote_port,
-                        config.password.unwrap(),
-                        &change_ss_type(output_name),
-                    ))
+                    let transport = ClientTransport::new(config.transport.as_ref(), &config.remote_host, config.remote_port)?;
+                    let plugin = start_plugin(&config)?;
+                    Box::new(
+                        SsOutProxy::new(
+                            config.remote_host,
+                            config.remote_port,
+                            ss_password(config.password)?,
+                            &change_ss_type(output_name),
+                            new_dialer(&config.dial)?,
+                            transport,
+                        )
+                        .with_plugin(plugin),
+                    )
+                }
+                // Shadowsocks stream cipher
+                ProtocalType::SsNone => {
+                    let config: BaseActiveConfig = serde_json::from_value(output.config.clone())?;
+                    let transport = ClientTransport::new(config.transport.as_ref(), &config.remote_host, config.remote_port)?;
+                    let plugin = start_plugin(&config)?;
+                    Box::new(
+                        SsCipherOutProxy::new(
+                            config.remote_host,
+                            config.remote_port,
+                            config.password.unwrap_or_default(),
+                            &StreamCipherType::None,
+                            new_dialer(&config.dial)?,
+                            transport,
+                        )
+                        .with_plugin(plugin),
+                    )
+                }
+                #[cfg(feature = "stream-cipher")]
+                ProtocalType::SsAes128Cfb
+                | ProtocalType::SsAes192Cfb
+                | ProtocalType::SsAes256Cfb
+                | ProtocalType::SsAes128Ctr
+                | ProtocalType::SsAes192Ctr
+                | ProtocalType::SsAes256Ctr
+                | ProtocalType::SsChacha20Ietf
+                | ProtocalType::SsRc4Md5 => {
+                    let config: BaseActiveConfig = serde_json::from_value(output.config.clone())?;
+                    let transport = ClientTransport::new(config.transport.as_ref(), &config.remote_host, config.remote_port)?;
+                    let plugin = start_plugin(&config)?;
+                    Box::new(
+                        SsCipherOutProxy::new(
+                            config.remote_host,
+                            config.remote_port,
+                            ss_password(config.password)?,
+                            &change_ss_stream_type(output_name),
+                            new_dialer(&config.dial)?,
+                            transport,
+                        )
+                        .with_plugin(plugin),
+                    )
                 }
                 ProtocalType::Raw => {
                     let config: RawActiveConfig = serde_json::from_value(output.config.clone())?;
-                    Box::new(RawActive::new(config.dns)?)
+                    Box::new(RawActive::new(
+                        config.dns.map(OneOrMany::into_vec),
+                        new_dialer(&config.dial)?,
+                    )?)
+                }
+                ProtocalType::Socks5 => {
+                    let config: BaseActiveConfig = serde_json::from_value(output.config.clone())?;
+                    Box::new(Socks5Active::new(&config, new_dialer(&config.dial)?)?)
+                }
+                ProtocalType::Trojan => {
+                    let config: BaseActiveConfig = serde_json::from_value(output.config.clone())?;
+                    Box::new(TrojanActive::new(&config, new_dialer(&config.dial)?)?)
+                }
+                ProtocalType::Vless => {
+                    let config: BaseActiveConfig = serde_json::from_value(output.config.clone())?;
+                    Box::new(VlessActive::new(&config, new_dialer(&config.dial)?)?)
+                }
+                ProtocalType::Http => {
+                    let config: HttpActiveConfig = serde_json::from_value(output.config.clone())?;
+                    Box::new(HttpActive::new(&config, new_dialer(&config.base.dial)?)?)
+                }
+                #[cfg(feature = "rust-crypto")]
+                ProtocalType::Vmess => {
+                    let config: VmessActiveConfig = serde_json::from_value(output.config.clone())?;
+                    Box::new(VmessActive::new(&config, new_dialer(&config.base.dial)?)?)
                 }
                 //ProtocalType::Original => {}
-                // ProtocalType::Socks5 => {}
                 _ => return Err(unsupport_err(output_name, output_mode)),
             }
         }
-        ConnectMode::Passive => match output_name {
-            _ => return Err(unsupport_err(output_name, output_mode)),
-        },
+        ConnectMode::Passive => return Err(unsupport_err(output_name, output_mode)),
     };
+    // Connections to fake addresses come from the DNS input answers.
+    if let Some(pool) = DnsResolver::global().fake_ip_pool() {
+        return Ok(Box::new(FakeIpOutProxy::new(output_proxy, pool)));
+    }
     Ok(output_proxy)
 }
 
-///
+/// Select the input proxy and initialize it.
 async fn select_input(input_conf: &ProtocolConf, output_proxy: Box<dyn OutputProxy + Send>) -> io::Result<Box<dyn InputProxy>> {
     let input_name = &input_conf.name;
     let input_mode = input_conf.mode.as_ref().unwrap_or(&ConnectMode::Passive);
@@ -64,19 +188,78 @@ async fn select_input(input_conf: &ProtocolConf, output_proxy: Box<dyn OutputPro
             match input_name {
                 //ProtocolType::Original => {}
                 ProtocalType::Socks5 => Box::new(Socks5Passive::new(&config, output_proxy).await?),
-                ProtocalType::SsAes128Gcm | ProtocalType::SsAes256Gcm | ProtocalType::Chacha20Poly1305 => {
+                ProtocalType::Socks4 => Box::new(Socks4Passive::new(&config, output_proxy).await?),
+                ProtocalType::Trojan => Box::new(TrojanPassive::new(&config, output_proxy).await?),
+                ProtocalType::Vless => Box::new(VlessPassive::new(&config, output_proxy).await?),
+                ProtocalType::Dns => {
+                    let config: DnsPassiveConfig = serde_json::from_value(input_conf.config.clone())?;
+                    Box::new(DnsServer::new(&config, output_proxy).await?)
+                }
+                ProtocalType::SsAes128Gcm
+                | ProtocalType::SsAes192Gcm
+                | ProtocalType::SsAes256Gcm
+                | ProtocalType::Chacha20Poly1305
+                | ProtocalType::XChacha20Poly1305 => {
                     Box::new(SsInputProxy::new(change_ss_type(input_name), &config, output_proxy).await?)
                 }
+                ProtocalType::SsNone => Box::new(SsCipherInputProxy::new(StreamCipherType::None, &config, output_proxy).await?),
+                #[cfg(feature = "stream-cipher")]
+                ProtocalType::SsAes128Cfb
+                | ProtocalType::SsAes192Cfb
+                | ProtocalType::SsAes256Cfb
+                | ProtocalType::SsAes128Ctr
+                | ProtocalType::SsAes192Ctr
+                | ProtocalType::SsAes256Ctr
+                | ProtocalType::SsChacha20Ietf
+                | ProtocalType::SsRc4Md5 => {
+                    Box::new(SsCipherInputProxy::new(change_ss_stream_type(input_name), &config, output_proxy).await?)
+                }
                 _ => return Err(unsupport_err(input_name, input_mode)),
             }
         }
-        ConnectMode::Active => match input_name {
-            _ => return Err(unsupport_err(input_name, input_mode)),
-        },
+        ConnectMode::Active => return Err(unsupport_err(input_name, input_mode)),
     };
     Ok(input_proxy)
 }
 
+fn new_dialer(dial: &Option<DialConfig>) -> io::Result<Dialer> {
+    Ok(dial.as_ref().map(Dialer::new).transpose()?.unwrap_or_default())
+}
+
+/// Wait for Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+fn start_plugin(config: &BaseActiveConfig) -> io::Result<Option<Plugin>> {
+    // The plugin connects to the remote by itself.
+    let has_via = config.dial.as_ref().is_some_and(|dial| dial.via.is_some());
+    if config.plugin.is_some() && has_via {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "A plugin can't connect through `via`",
+        ));
+    }
+    config
+        .plugin
+        .as_ref()
+        .map(|plugin| Plugin::start_client(plugin, config.plugin_opts.as_deref(), &config.remote_host, config.remote_port))
+        .transpose()
+}
+
+fn ss_password(password: Option<String>) -> io::Result<String> {
+    password.ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Shadowsocks must have a password"))
+}
+
 fn unsupport_err(name: &ProtocalType, mode: &ConnectMode) -> io::Error {
     let err = format!("Not support type: {:?} - {:?}", name, mode);
     io::Error::new(ErrorKind::InvalidInput, err)
@@ -85,8 +268,24 @@ fn unsupport_err(name: &ProtocalType, mode: &ConnectMode) -> io::Error {
 pub fn change_ss_type(t: &ProtocalType) -> AeadType {
     match t {
         ProtocalType::SsAes128Gcm => AeadType::AES128GCM,
+        ProtocalType::SsAes192Gcm => AeadType::AES192GCM,
         ProtocalType::SsAes256Gcm => AeadType::AES256GCM,
         ProtocalType::Chacha20Poly1305 => AeadType::Chacha20Poly1305,
+        ProtocalType::XChacha20Poly1305 => AeadType::XChacha20Poly1305,
         _ => AeadType::AES128GCM,
     }
 }
+
+#[cfg(feature = "stream-cipher")]
+pub fn change_ss_stream_type(t: &ProtocalType) -> StreamCipherType {
+    match t {
+        ProtocalType::SsAes128Cfb => StreamCipherType::AES128CFB,
+        ProtocalType::SsAes192Cfb => StreamCipherType::AES192CFB,
+        ProtocalType::SsAes256Cfb => StreamCipherType::AES256CFB,
+        ProtocalType::SsAes128Ctr => StreamCipherType::AES128CTR,
+        ProtocalType::SsAes192Ctr => StreamCipherType::AES192CTR,
+        ProtocalType::SsAes256Ctr => StreamCipherType::AES256CTR,
+        ProtocalType::SsChacha20Ietf => StreamCipherType::Chacha20Ietf,
+        _ => StreamCipherType::Rc4Md5,
+    }
+}
@@ -0,0 +1,159 @@
+#[cfg(not(feature = "rust-crypto"))]
+use crate::encrypt::error::EncryptError;
+use crate::encrypt::error::Result;
+
+#[cfg(not(any(feature = "ring", feature = "rust-crypto")))]
+compile_error!("The AEAD ciphers need the `ring` or the `rust-crypto` feature");
+
+#[cfg(feature = "ring")]
+mod ring_cipher;
+#[cfg(feature = "rust-crypto")]
+mod rust_crypto_cipher;
+
+#[derive(Copy, Clone, Debug)]
+pub enum AeadType {
+    AES128GCM,
+    AES192GCM,
+    AES256GCM,
+    Chacha20Poly1305,
+    XChacha20Poly1305,
+}
+
+impl AeadType {
+    /// Length of the key, Shadowsocks uses the same length for the salt.
+    pub fn key_len(&self) -> usize {
+        match self {
+            AeadType::AES128GCM => 16,
+            AeadType::AES192GCM => 24,
+            AeadType::AES256GCM | AeadType::Chacha20Poly1305 | AeadType::XChacha20Poly1305 => 32,
+        }
+    }
+
+    pub fn nonce_len(&self) -> usize {
+        match self {
+            AeadType::XChacha20Poly1305 => 24,
+            _ => 12,
+        }
+    }
+}
+
+/// AEAD default tag size
+pub const AEAD_TAG_SIZE: usize = 16;
+
+/// AEAD encryption of a connection.
+/// The sealing and opening nonces are counted separately and increase by one on every call.
+pub trait AeadCipher: Send {
+    /// Encrypt the data in place and write the tag into `tag`.
+    fn seal_in_place(&mut self, data: &mut [u8], tag: &mut [u8]) -> Result<()>;
+
+    /// Decrypt the data in place, `en_data` is the cipher text followed by the tag.
+    /// ## Return
+    /// The decrypted part of `en_data`.
+    fn open_in_place<'a>(&mut self, en_data: &'a mut [u8]) -> Result<&'a mut [u8]>;
+}
+
+/// Create a new AEAD cipher.
+/// `ring` is used when the `ring` feature is enabled and it supports the algorithm,
+/// otherwise the pure Rust implementation of the `rust-crypto` feature is used.
+pub fn new_aead_cipher(aead_type: &AeadType, key: &[u8]) -> Result<Box<dyn AeadCipher>> {
+    #[cfg(feature = "ring")]
+    if let Some(cipher) = ring_cipher::AeadEncryptRing::new(aead_type, key)? {
+        return Ok(Box::new(cipher));
+    }
+    #[cfg(feature = "rust-crypto")]
+    return rust_crypto_cipher::new_rust_crypto_cipher(aead_type, key);
+    #[cfg(not(feature = "rust-crypto"))]
+    Err(EncryptError::Unsupported)
+}
+
+#[cfg(all(test, feature = "rust-crypto"))]
+mod tests {
+    use crate::encrypt::aead::{new_aead_cipher, rust_crypto_cipher, AeadType, AEAD_TAG_SIZE};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    const ALL_TYPES: [AeadType; 5] = [
+        AeadType::AES128GCM,
+        AeadType::AES192GCM,
+        AeadType::AES256GCM,
+        AeadType::Chacha20Poly1305,
+        AeadType::XChacha20Poly1305,
+    ];
+
+    /// GCM spec test cases 2, 8 and 14. The first nonce of a connection is all zero.
+    #[test]
+    fn gcm_vectors() {
+        let vectors = [
+            (
+                AeadType::AES128GCM,
+                "0388dace60b6a392f328c2b971b2fe78ab6e47d42cec13bdf53a67b21257bddf",
+            ),
+            (
+                AeadType::AES192GCM,
+                "98e7247c07f0fe411c267e4384b0f6002ff58d80033927ab8ef4d4587514f0fb",
+            ),
+            (
+                AeadType::AES256GCM,
+                "cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919",
+            ),
+        ];
+        for (aead_type, expect) in vectors {
+            let key = vec![0u8; aead_type.key_len()];
+            let mut backends = vec![new_aead_cipher(&aead_type, &key).unwrap()];
+            backends.push(rust_crypto_cipher::new_rust_crypto_cipher(&aead_type, &key).unwrap());
+            for mut cipher in backends {
+                let mut data = [0u8; 16];
+                let mut tag = [0u8; AEAD_TAG_SIZE];
+                cipher.seal_in_place(&mut data, &mut tag).unwrap();
+                assert_eq!([data, tag].concat(), hex(expect), "{:?}", aead_type);
+            }
+        }
+    }
+
+    /// Everything sealed by one backend must be opened by the other one.
+    #[test]
+    fn cross_backend() {
+        for aead_type in ALL_TYPES {
+            let key: Vec<u8> = (0..aead_type.key_len() as u8).collect();
+            let mut sealer = new_aead_cipher(&aead_type, &key).unwrap();
+            let mut reference = rust_crypto_cipher::new_rust_crypto_cipher(&aead_type, &key).unwrap();
+            let mut opener = rust_crypto_cipher::new_rust_crypto_cipher(&aead_type, &key).unwrap();
+            for round in 0..3u8 {
+                let plain = vec![round; 100];
+                let mut data = plain.clone();
+                let mut tag = [0u8; AEAD_TAG_SIZE];
+                sealer.seal_in_place(&mut data, &mut tag).unwrap();
+
+                let mut expect = plain.clone();
+                let mut expect_tag = [0u8; AEAD_TAG_SIZE];
+                reference.seal_in_place(&mut expect, &mut expect_tag).unwrap();
+                assert_eq!((&data, tag), (&expect, expect_tag), "{:?}", aead_type);
+
+                let mut en_data = [data, tag.to_vec()].concat();
+                assert_eq!(
+                    opener.open_in_place(&mut en_data).unwrap(),
+                    plain.as_slice(),
+                    "{:?}",
+                    aead_type
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reject_modified_data() {
+        for aead_type in ALL_TYPES {
+            let key = vec![1u8; aead_type.key_len()];
+            let mut sealer = new_aead_cipher(&aead_type, &key).unwrap();
+            let mut opener = new_aead_cipher(&aead_type, &key).unwrap();
+            let mut data = *b"touch the internet";
+            let mut tag = [0u8; AEAD_TAG_SIZE];
+            sealer.seal_in_place(&mut data, &mut tag).unwrap();
+            let mut en_data = [data.to_vec(), tag.to_vec()].concat();
+            en_data[0] ^= 1;
+            assert!(opener.open_in_place(&mut en_data).is_err(), "{:?}", aead_type);
+        }
+    }
+}
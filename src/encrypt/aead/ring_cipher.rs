@@ -0,0 +1,46 @@
+use ring::aead::{Aad, BoundKey, OpeningKey, SealingKey, UnboundKey};
+
+use crate::encrypt::aead::{AeadCipher, AeadType};
+use crate::encrypt::error::EncryptError;
+use crate::encrypt::error::Result;
+use crate::encrypt::Nonce;
+
+pub struct AeadEncryptRing {
+    sealing_key: SealingKey<Nonce>,
+    opening_key: OpeningKey<Nonce>,
+}
+
+impl AeadEncryptRing {
+    /// ## Return
+    /// `None` if `ring` doesn't support this algorithm.
+    pub fn new(aead_type: &AeadType, key: &[u8]) -> Result<Option<Self>> {
+        let algorithm = match aead_type {
+            AeadType::AES128GCM => &ring::aead::AES_128_GCM,
+            AeadType::AES256GCM => &ring::aead::AES_256_GCM,
+            AeadType::Chacha20Poly1305 => &ring::aead::CHACHA20_POLY1305,
+            AeadType::AES192GCM | AeadType::XChacha20Poly1305 => return Ok(None),
+        };
+        let key_err = |_| EncryptError::InvalidLength(key.len() as u8);
+        let seal_unbound_key = UnboundKey::new(algorithm, key).map_err(key_err)?;
+        let sealing_key = SealingKey::new(seal_unbound_key, Nonce::new(aead_type.nonce_len()));
+        let open_unbound_key = UnboundKey::new(algorithm, key).map_err(key_err)?;
+        let opening_key = OpeningKey::new(open_unbound_key, Nonce::new(aead_type.nonce_len()));
+        Ok(Some(Self {
+            sealing_key,
+            opening_key,
+        }))
+    }
+}
+
+impl AeadCipher for AeadEncryptRing {
+    fn seal_in_place(&mut self, data: &mut [u8], tag: &mut [u8]) -> Result<()> {
+        self.sealing_key
+            .seal_in_place_separate_tag(Aad::empty(), data)
+            .map(|en_tag| tag.copy_from_slice(en_tag.as_ref()))
+            .or(Err(EncryptError::EncryptErr))
+    }
+
+    fn open_in_place<'a>(&mut self, en_data: &'a mut [u8]) -> Result<&'a mut [u8]> {
+        self.opening_key.open_in_place(Aad::empty(), en_data).or(Err(EncryptError::DecryptErr))
+    }
+}
@@ -0,0 +1,61 @@
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::aes::Aes192;
+use aes_gcm::{Aes128Gcm, Aes256Gcm, AesGcm};
+use chacha20poly1305::aead::consts::U12;
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+
+use crate::encrypt::aead::{AeadCipher, AeadType, AEAD_TAG_SIZE};
+use crate::encrypt::error::EncryptError;
+use crate::encrypt::error::Result;
+use crate::encrypt::Nonce;
+
+/// Pure Rust implementation from RustCrypto, it supports all algorithms.
+pub struct AeadEncryptRustCrypto<C: AeadInPlace + Send> {
+    cipher: C,
+    seal_nonce: Nonce,
+    open_nonce: Nonce,
+}
+
+impl<C: AeadInPlace + KeyInit + Send> AeadEncryptRustCrypto<C> {
+    pub fn new(aead_type: &AeadType, key: &[u8]) -> Result<Self> {
+        let cipher = C::new_from_slice(key).map_err(|_| EncryptError::InvalidLength(key.len() as u8))?;
+        Ok(Self {
+            cipher,
+            seal_nonce: Nonce::new(aead_type.nonce_len()),
+            open_nonce: Nonce::new(aead_type.nonce_len()),
+        })
+    }
+}
+
+impl<C: AeadInPlace + Send> AeadCipher for AeadEncryptRustCrypto<C> {
+    fn seal_in_place(&mut self, data: &mut [u8], tag: &mut [u8]) -> Result<()> {
+        let nonce = GenericArray::from_slice(self.seal_nonce.get_and_increment());
+        let en_tag = self.cipher.encrypt_in_place_detached(nonce, &[], data).or(Err(EncryptError::EncryptErr))?;
+        tag.copy_from_slice(&en_tag);
+        Ok(())
+    }
+
+    fn open_in_place<'a>(&mut self, en_data: &'a mut [u8]) -> Result<&'a mut [u8]> {
+        if en_data.len() < AEAD_TAG_SIZE {
+            return Err(EncryptError::DecryptErr);
+        }
+        let nonce = GenericArray::from_slice(self.open_nonce.get_and_increment());
+        let (data, tag) = en_data.split_at_mut(en_data.len() - AEAD_TAG_SIZE);
+        self.cipher
+            .decrypt_in_place_detached(nonce, &[], data, GenericArray::from_slice(&*tag))
+            .or(Err(EncryptError::DecryptErr))?;
+        Ok(data)
+    }
+}
+
+pub fn new_rust_crypto_cipher(aead_type: &AeadType, key: &[u8]) -> Result<Box<dyn AeadCipher>> {
+    let cipher: Box<dyn AeadCipher> = match aead_type {
+        AeadType::AES128GCM => Box::new(AeadEncryptRustCrypto::<Aes128Gcm>::new(aead_type, key)?),
+        AeadType::AES192GCM => Box::new(AeadEncryptRustCrypto::<AesGcm<Aes192, U12>>::new(aead_type, key)?),
+        AeadType::AES256GCM => Box::new(AeadEncryptRustCrypto::<Aes256Gcm>::new(aead_type, key)?),
+        AeadType::Chacha20Poly1305 => Box::new(AeadEncryptRustCrypto::<ChaCha20Poly1305>::new(aead_type, key)?),
+        AeadType::XChacha20Poly1305 => Box::new(AeadEncryptRustCrypto::<XChaCha20Poly1305>::new(aead_type, key)?),
+    };
+    Ok(cipher)
+}
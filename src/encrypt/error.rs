@@ -1,6 +1,6 @@
 use std::{fmt, result};
 
-use crate::encrypt::error::EncryptError::InvalidSaltSize;
+use crate::encrypt::error::EncryptError::{InvalidLength, InvalidSaltSize};
 
 pub type Result<T> = result::Result<T, EncryptError>;
 
@@ -12,7 +12,9 @@ pub enum EncryptError {
     InvalidSaltSize(usize),
     EncryptErr,
     DecryptErr,
-    NotSupport,
+    /// The algorithm needs the `rust-crypto` feature.
+    #[cfg(not(feature = "rust-crypto"))]
+    Unsupported,
 }
 
 impl fmt::Display for EncryptError {
@@ -23,6 +25,7 @@ impl fmt::Display for EncryptError {
 
 fn fmt_err(aead_err: &EncryptError, f: &mut fmt::Formatter) -> fmt::Result {
     match aead_err {
+        InvalidLength(len) => write!(f, "Invalid key length:{}", len),
         InvalidSaltSize(size) => write!(f, "Invalid salt size.Right size:{}", size),
         n => write!(f, "Aead encrypt error: {:?}", n),
     }
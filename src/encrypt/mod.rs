@@ -1,11 +1,14 @@
-use ring::error::Unspecified;
-
 pub mod aead;
 pub mod error;
 pub mod ss;
+pub mod stream;
+#[cfg(feature = "rust-crypto")]
+pub mod vmess;
 
+/// Little-endian counter used as the AEAD nonce, 12 or 24 bytes long.
 pub struct Nonce {
-    base_arr: [u8; 12],
+    base_arr: [u8; 24],
+    len: usize,
     first: bool,
 }
 
@@ -14,10 +17,10 @@ impl Nonce {
     pub fn get_and_increment(&mut self) -> &[u8] {
         if self.first {
             self.first = false;
-            &self.base_arr
+            &self.base_arr[..self.len]
         } else {
             self.increment(0);
-            &self.base_arr
+            &self.base_arr[..self.len]
         }
     }
 
@@ -31,16 +34,18 @@ impl Nonce {
         }
     }
 
-    pub fn new() -> Self {
+    pub fn new(len: usize) -> Self {
         Nonce {
-            base_arr: [0u8; 12],
+            base_arr: [0u8; 24],
+            len,
             first: true,
         }
     }
 }
 
+#[cfg(feature = "ring")]
 impl ring::aead::NonceSequence for Nonce {
-    fn advance(&mut self) -> Result<ring::aead::Nonce, Unspecified> {
+    fn advance(&mut self) -> Result<ring::aead::Nonce, ring::error::Unspecified> {
         ring::aead::Nonce::try_assume_unique_for_key(self.get_and_increment())
     }
 }
@@ -5,6 +5,7 @@ use sha1::Sha1;
 use crate::encrypt::error::Result;
 
 pub mod ss_aead;
+pub mod ss_stream;
 
 pub const DIGEST_LEN: usize = 16;
 
@@ -17,7 +18,7 @@ pub fn openssl_bytes_to_key(password: &[u8], key_buffer: &mut [u8]) {
     while offset < key_len {
         let mut m = Md5::new();
         if let Some(digest) = last_digest {
-            m.update(&digest);
+            m.update(digest);
         }
         m.update(password);
         let digest = m.finalize();
@@ -1,45 +1,39 @@
-use crate::encrypt::aead::{AeadEncryptRing, AeadType, AEAD_TAG_SIZE};
-use crate::encrypt::error::Result;
+use crate::encrypt::aead::{new_aead_cipher, AeadCipher, AeadType};
+use crate::encrypt::error::{EncryptError, Result};
 use crate::encrypt::ss::{generate_subkey, openssl_bytes_to_key};
 
+/// The payload length of a chunk, the two highest bits of the length header are reserved.
+pub const MAX_PAYLOAD_SIZE: usize = 0x3FFF;
+
 pub struct SsAead {
-    encryption: AeadEncryptRing,
+    encryption: Box<dyn AeadCipher>,
     pub salt: Vec<u8>,
-    buffer: Vec<u8>,
 }
 
 /// Shadowsocks protocol supporting AEAD encryption
 impl SsAead {
     /// Initialize according to the specified enum
     ///
-    /// * `salt` - 16/32 bytes of each TCP connection header
+    /// * `salt` - 16/24/32 bytes of each TCP connection header
     /// * `password` - User's simple password
     /// * `aead_type` - Aead type
     pub fn new(salt: Vec<u8>, password: &[u8], aead_type: &AeadType) -> Result<Self> {
-        let mut master_key = match aead_type {
-            AeadType::AES128GCM => vec![0u8; 16],
-            AeadType::AES256GCM | AeadType::Chacha20Poly1305 => vec![0u8; 32],
-        };
+        let mut master_key = vec![0u8; aead_type.key_len()];
+        if salt.len() != master_key.len() {
+            return Err(EncryptError::InvalidSaltSize(master_key.len()));
+        }
         openssl_bytes_to_key(password, master_key.as_mut());
         let aead_key = generate_subkey(&salt, master_key.as_mut())?;
-        let encryption = AeadEncryptRing::new(aead_type, &aead_key);
-        Ok(SsAead {
-            encryption,
-            salt,
-            buffer: vec![0u8; 32 * 1024],
-        })
+        let encryption = new_aead_cipher(aead_type, &aead_key)?;
+        Ok(SsAead { encryption, salt })
     }
 
-    pub fn ss_encrypt(&mut self, data: &mut [u8]) -> Result<&mut [u8]> {
-        if self.buffer.len() < data.len() + AEAD_TAG_SIZE {
-            self.buffer = vec![0u8; data.len() + AEAD_TAG_SIZE]
-        }
-        // let tag_arr = self.encryption.encrypt_replace(self.buffer.as_mut())?;
-        let size = self.encryption.encrypt(data, self.buffer.as_mut())?;
-        Ok(&mut self.buffer[..size])
+    /// Encrypt the data in place and write the tag into `tag`.
+    pub fn seal_in_place(&mut self, data: &mut [u8], tag: &mut [u8]) -> Result<()> {
+        self.encryption.seal_in_place(data, tag)
     }
 
     pub fn ss_decrypt<'a>(&mut self, en_data: &'a mut [u8]) -> Result<&'a mut [u8]> {
-        self.encryption.decrypt(en_data)
+        self.encryption.open_in_place(en_data)
     }
 }
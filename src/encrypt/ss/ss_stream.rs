@@ -0,0 +1,81 @@
+use crate::encrypt::error::Result;
+use crate::encrypt::ss::openssl_bytes_to_key;
+use crate::encrypt::stream::{new_stream_crypt, StreamCipherType, StreamCrypt};
+
+pub struct SsStreamCipher {
+    crypt: Box<dyn StreamCrypt>,
+    pub iv: Vec<u8>,
+}
+
+/// Shadowsocks protocol supporting the stream ciphers and the "none" method.
+/// Every direction of a TCP connection starts with its own IV.
+impl SsStreamCipher {
+    /// Initialize an encryptor for the data we send.
+    ///
+    /// * `iv` - 12/16 bytes of each TCP connection header
+    /// * `password` - User's simple password
+    /// * `cipher_type` - Stream cipher type
+    pub fn new_encryptor(iv: Vec<u8>, password: &[u8], cipher_type: &StreamCipherType) -> Result<Self> {
+        Self::new(iv, password, cipher_type, true)
+    }
+
+    /// Initialize a decryptor for the data we receive.
+    pub fn new_decryptor(iv: Vec<u8>, password: &[u8], cipher_type: &StreamCipherType) -> Result<Self> {
+        Self::new(iv, password, cipher_type, false)
+    }
+
+    fn new(iv: Vec<u8>, password: &[u8], cipher_type: &StreamCipherType, encrypt: bool) -> Result<Self> {
+        let mut key = vec![0u8; cipher_type.key_len()];
+        openssl_bytes_to_key(password, key.as_mut());
+        let crypt = new_stream_crypt(cipher_type, &key, &iv, encrypt)?;
+        Ok(SsStreamCipher { crypt, iv })
+    }
+
+    /// Encrypt or decrypt the data in place.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        self.crypt.apply(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encrypt::ss::ss_stream::SsStreamCipher;
+    use crate::encrypt::stream::StreamCipherType;
+
+    #[test]
+    fn none_cipher() {
+        let mut encryptor = SsStreamCipher::new_encryptor(vec![], b"", &StreamCipherType::None).unwrap();
+        let mut data = *b"touch the internet";
+        encryptor.apply(&mut data);
+        assert_eq!(&data, b"touch the internet");
+    }
+
+    #[test]
+    #[cfg(feature = "stream-cipher")]
+    fn stream_cipher_round_trip() {
+        let types = [
+            StreamCipherType::AES128CFB,
+            StreamCipherType::AES192CFB,
+            StreamCipherType::AES256CFB,
+            StreamCipherType::AES128CTR,
+            StreamCipherType::AES192CTR,
+            StreamCipherType::AES256CTR,
+            StreamCipherType::Chacha20Ietf,
+            StreamCipherType::Rc4Md5,
+        ];
+        for cipher_type in types {
+            let iv = vec![7u8; cipher_type.iv_len()];
+            let mut encryptor = SsStreamCipher::new_encryptor(iv.clone(), b"test", &cipher_type).unwrap();
+            let mut decryptor = SsStreamCipher::new_decryptor(iv, b"test", &cipher_type).unwrap();
+            let plain: Vec<u8> = (0..100u8).collect();
+            let mut data = plain.clone();
+            // Split into odd sizes to make sure the cipher state is kept between calls.
+            encryptor.apply(&mut data[..7]);
+            encryptor.apply(&mut data[7..]);
+            assert_ne!(plain, data, "{:?}", cipher_type);
+            decryptor.apply(&mut data[..50]);
+            decryptor.apply(&mut data[50..]);
+            assert_eq!(plain, data, "{:?}", cipher_type);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use aes::cipher::{BlockCipher, BlockEncryptMut, InvalidLength, KeyIvInit, StreamCipher};
+use aes::{Aes128, Aes192, Aes256};
+use cfb_mode::{BufDecryptor, BufEncryptor};
+use chacha20::ChaCha20;
+use md5::{Digest, Md5};
+
+use crate::encrypt::error::{EncryptError, Result};
+use crate::encrypt::stream::{Plain, StreamCipherType, StreamCrypt};
+
+struct CfbEncrypt<C: BlockEncryptMut + BlockCipher>(BufEncryptor<C>);
+
+struct CfbDecrypt<C: BlockEncryptMut + BlockCipher>(BufDecryptor<C>);
+
+impl<C: BlockEncryptMut + BlockCipher + Send> StreamCrypt for CfbEncrypt<C> {
+    fn apply(&mut self, data: &mut [u8]) {
+        self.0.encrypt(data)
+    }
+}
+
+impl<C: BlockEncryptMut + BlockCipher + Send> StreamCrypt for CfbDecrypt<C> {
+    fn apply(&mut self, data: &mut [u8]) {
+        self.0.decrypt(data)
+    }
+}
+
+struct KeyStream<S: StreamCipher>(S);
+
+impl<S: StreamCipher + Send> StreamCrypt for KeyStream<S> {
+    fn apply(&mut self, data: &mut [u8]) {
+        self.0.apply_keystream(data)
+    }
+}
+
+/// RC4, there is no maintained crate for it anymore.
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, x) in state.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+}
+
+impl StreamCrypt for Rc4 {
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+/// Create a new deprecated stream cipher.
+pub fn new_legacy_crypt(cipher_type: &StreamCipherType, key: &[u8], iv: &[u8], encrypt: bool) -> Result<Box<dyn StreamCrypt>> {
+    let key_err = |_: InvalidLength| EncryptError::InvalidLength(key.len() as u8);
+    let crypt: Box<dyn StreamCrypt> = match (cipher_type, encrypt) {
+        (StreamCipherType::None, _) => Box::new(Plain),
+        (StreamCipherType::AES128CFB, true) => {
+            Box::new(CfbEncrypt(BufEncryptor::<Aes128>::new_from_slices(key, iv).map_err(key_err)?))
+        }
+        (StreamCipherType::AES128CFB, false) => {
+            Box::new(CfbDecrypt(BufDecryptor::<Aes128>::new_from_slices(key, iv).map_err(key_err)?))
+        }
+        (StreamCipherType::AES192CFB, true) => {
+            Box::new(CfbEncrypt(BufEncryptor::<Aes192>::new_from_slices(key, iv).map_err(key_err)?))
+        }
+        (StreamCipherType::AES192CFB, false) => {
+            Box::new(CfbDecrypt(BufDecryptor::<Aes192>::new_from_slices(key, iv).map_err(key_err)?))
+        }
+        (StreamCipherType::AES256CFB, true) => {
+            Box::new(CfbEncrypt(BufEncryptor::<Aes256>::new_from_slices(key, iv).map_err(key_err)?))
+        }
+        (StreamCipherType::AES256CFB, false) => {
+            Box::new(CfbDecrypt(BufDecryptor::<Aes256>::new_from_slices(key, iv).map_err(key_err)?))
+        }
+        (StreamCipherType::AES128CTR, _) => {
+            Box::new(KeyStream(ctr::Ctr128BE::<Aes128>::new_from_slices(key, iv).map_err(key_err)?))
+        }
+        (StreamCipherType::AES192CTR, _) => {
+            Box::new(KeyStream(ctr::Ctr128BE::<Aes192>::new_from_slices(key, iv).map_err(key_err)?))
+        }
+        (StreamCipherType::AES256CTR, _) => {
+            Box::new(KeyStream(ctr::Ctr128BE::<Aes256>::new_from_slices(key, iv).map_err(key_err)?))
+        }
+        (StreamCipherType::Chacha20Ietf, _) => Box::new(KeyStream(ChaCha20::new_from_slices(key, iv).map_err(key_err)?)),
+        (StreamCipherType::Rc4Md5, _) => {
+            // rc4-md5 uses MD5(key + iv) as the real key.
+            let mut m = Md5::new();
+            m.update(key);
+            m.update(iv);
+            Box::new(Rc4::new(&m.finalize()))
+        }
+    };
+    Ok(crypt)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encrypt::stream::legacy::Rc4;
+    use crate::encrypt::stream::{new_stream_crypt, StreamCipherType, StreamCrypt};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn rc4_vector() {
+        let mut data = *b"Plaintext";
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data.to_vec(), hex("bbf316e8d940af0ad3"));
+    }
+
+    #[test]
+    fn aes_128_cfb_vector() {
+        // NIST SP 800-38A F.3.13
+        let key = hex("2b7e151628aed2a6abf7158809cf4f3c");
+        let iv = hex("000102030405060708090a0b0c0d0e0f");
+        let mut data = hex("6bc1bee22e409f96e93d7e117393172a");
+        new_stream_crypt(&StreamCipherType::AES128CFB, &key, &iv, true).unwrap().apply(&mut data);
+        assert_eq!(data, hex("3b3fd92eb72dad20333449f8e83cfb4a"));
+    }
+
+    #[test]
+    fn aes_128_ctr_vector() {
+        // NIST SP 800-38A F.5.1
+        let key = hex("2b7e151628aed2a6abf7158809cf4f3c");
+        let iv = hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff");
+        let mut data = hex("6bc1bee22e409f96e93d7e117393172a");
+        new_stream_crypt(&StreamCipherType::AES128CTR, &key, &iv, true).unwrap().apply(&mut data);
+        assert_eq!(data, hex("874d6191b620e3261bef6864990db6ce"));
+    }
+}
@@ -0,0 +1,80 @@
+use crate::encrypt::error::{EncryptError, Result};
+
+#[cfg(feature = "stream-cipher")]
+mod legacy;
+
+/// Shadowsocks ciphers without chunks, the data is encrypted as a plain stream.
+#[derive(Copy, Clone, Debug)]
+pub enum StreamCipherType {
+    /// No encryption at all, only use it inside an already encrypted transport.
+    None,
+    #[cfg(feature = "stream-cipher")]
+    AES128CFB,
+    #[cfg(feature = "stream-cipher")]
+    AES192CFB,
+    #[cfg(feature = "stream-cipher")]
+    AES256CFB,
+    #[cfg(feature = "stream-cipher")]
+    AES128CTR,
+    #[cfg(feature = "stream-cipher")]
+    AES192CTR,
+    #[cfg(feature = "stream-cipher")]
+    AES256CTR,
+    #[cfg(feature = "stream-cipher")]
+    Chacha20Ietf,
+    #[cfg(feature = "stream-cipher")]
+    Rc4Md5,
+}
+
+impl StreamCipherType {
+    /// Length of the master key.
+    pub fn key_len(&self) -> usize {
+        match self {
+            StreamCipherType::None => 0,
+            #[cfg(feature = "stream-cipher")]
+            StreamCipherType::AES128CFB | StreamCipherType::AES128CTR | StreamCipherType::Rc4Md5 => 16,
+            #[cfg(feature = "stream-cipher")]
+            StreamCipherType::AES192CFB | StreamCipherType::AES192CTR => 24,
+            #[cfg(feature = "stream-cipher")]
+            StreamCipherType::AES256CFB | StreamCipherType::AES256CTR | StreamCipherType::Chacha20Ietf => 32,
+        }
+    }
+
+    /// Length of the IV sent at the beginning of every TCP connection.
+    pub fn iv_len(&self) -> usize {
+        match self {
+            StreamCipherType::None => 0,
+            #[cfg(feature = "stream-cipher")]
+            StreamCipherType::Chacha20Ietf => 12,
+            #[cfg(feature = "stream-cipher")]
+            _ => 16,
+        }
+    }
+}
+
+/// A cipher that encrypts or decrypts data of any length in place.
+pub trait StreamCrypt: Send {
+    fn apply(&mut self, data: &mut [u8]);
+}
+
+/// The "none" cipher, leaves the data untouched.
+struct Plain;
+
+impl StreamCrypt for Plain {
+    fn apply(&mut self, _data: &mut [u8]) {}
+}
+
+/// Create a new stream cipher.
+///
+/// * `encrypt` - CFB mode is not symmetric, so the direction must be known.
+#[cfg_attr(not(feature = "stream-cipher"), allow(unused_variables))]
+pub fn new_stream_crypt(cipher_type: &StreamCipherType, key: &[u8], iv: &[u8], encrypt: bool) -> Result<Box<dyn StreamCrypt>> {
+    if iv.len() != cipher_type.iv_len() {
+        return Err(EncryptError::InvalidSaltSize(cipher_type.iv_len()));
+    }
+    match cipher_type {
+        StreamCipherType::None => Ok(Box::new(Plain)),
+        #[cfg(feature = "stream-cipher")]
+        _ => legacy::new_legacy_crypt(cipher_type, key, iv, encrypt),
+    }
+}
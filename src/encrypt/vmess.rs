@@ -0,0 +1,403 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::aes::cipher::BlockEncrypt;
+use aes_gcm::aes::Aes128;
+use aes_gcm::Aes128Gcm;
+use chacha20poly1305::ChaCha20Poly1305;
+use md5::{Digest, Md5};
+use rand::RngCore;
+use sha2::Sha256;
+use sha3::digest::{ExtendableOutput, XofReader};
+use sha3::{Shake128, Shake128Reader};
+
+use crate::encrypt::error::{EncryptError, Result};
+
+pub const TAG_SIZE: usize = 16;
+/// The sealed length of the response header.
+pub const SEALED_LENGTH_SIZE: usize = 2 + TAG_SIZE;
+
+const CMD_KEY_SALT: &[u8] = b"c48619fe-8f02-49e0-b9e9-edf763e17e21";
+const KDF_SALT: &[u8] = b"VMess AEAD KDF";
+const AUTH_ID_KEY: &[u8] = b"AES Auth ID Encryption";
+const HEADER_LENGTH_KEY: &[u8] = b"VMess Header AEAD Key_Length";
+const HEADER_LENGTH_NONCE: &[u8] = b"VMess Header AEAD Nonce_Length";
+const HEADER_KEY: &[u8] = b"VMess Header AEAD Key";
+const HEADER_NONCE: &[u8] = b"VMess Header AEAD Nonce";
+const RESPONSE_LENGTH_KEY: &[u8] = b"AEAD Resp Header Len Key";
+const RESPONSE_LENGTH_NONCE: &[u8] = b"AEAD Resp Header Len IV";
+const RESPONSE_KEY: &[u8] = b"AEAD Resp Header Key";
+const RESPONSE_NONCE: &[u8] = b"AEAD Resp Header IV";
+const HMAC_BLOCK_SIZE: usize = 64;
+/// Padding lengths of the global padding are less than it.
+const MAX_PADDING: u16 = 64;
+
+/// Encryption of the body chunks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Security {
+    Aes128Gcm,
+    Chacha20Poly1305,
+    None,
+}
+
+impl Security {
+    /// The value in the request header.
+    pub fn id(&self) -> u8 {
+        match self {
+            Security::Aes128Gcm => 0x03,
+            Security::Chacha20Poly1305 => 0x04,
+            Security::None => 0x05,
+        }
+    }
+}
+
+/// The key of a user, which seals the request header.
+pub fn cmd_key(uuid: &[u8; 16]) -> [u8; 16] {
+    let mut md5 = Md5::new();
+    md5.update(uuid);
+    md5.update(CMD_KEY_SALT);
+    md5.finalize().into()
+}
+
+/// The key derivation of VMess AEAD, HMAC-SHA256 nested by the salt and each path.
+fn kdf(key: &[u8], paths: &[&[u8]]) -> [u8; 32] {
+    let mut keys = vec![KDF_SALT];
+    keys.extend_from_slice(paths);
+    nested_hmac(&keys, key)
+}
+
+/// HMAC keyed by the last key, whose hash is the HMAC of the keys before it, or SHA256 without them.
+fn nested_hmac(keys: &[&[u8]], data: &[u8]) -> [u8; 32] {
+    let (key, parents) = match keys.split_last() {
+        Some(n) => n,
+        None => return Sha256::digest(data).into(),
+    };
+    let mut block = [0u8; HMAC_BLOCK_SIZE];
+    if key.len() > HMAC_BLOCK_SIZE {
+        block[..32].copy_from_slice(&nested_hmac(parents, key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&nested_hmac(parents, &inner));
+    nested_hmac(parents, &outer)
+}
+
+/// The time, a random number and their CRC32, encrypted by AES-128 with the key derived from the cmd key.
+fn auth_id(cmd_key: &[u8; 16], time: u64, random: [u8; 4]) -> [u8; 16] {
+    let mut block = [0u8; 16];
+    block[..8].copy_from_slice(&time.to_be_bytes());
+    block[8..12].copy_from_slice(&random);
+    let crc = crc32fast::hash(&block[..12]);
+    block[12..].copy_from_slice(&crc.to_be_bytes());
+    let cipher = Aes128::new(GenericArray::from_slice(&kdf(cmd_key, &[AUTH_ID_KEY])[..16]));
+    let mut block = GenericArray::from(block);
+    cipher.encrypt_block(&mut block);
+    block.into()
+}
+
+/// Seal the request header, the server finds the user by the auth ID at the start.
+pub fn seal_header(cmd_key: &[u8; 16], header: &[u8]) -> Result<Vec<u8>> {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |n| n.as_secs());
+    seal_header_with(cmd_key, header, auth_id(cmd_key, time, rand::random()), rand::random())
+}
+
+/// The auth ID, the sealed length, the nonce and the sealed header.
+fn seal_header_with(cmd_key: &[u8; 16], header: &[u8], auth_id: [u8; 16], nonce: [u8; 8]) -> Result<Vec<u8>> {
+    let header_len = u16::try_from(header.len()).map_err(|_| EncryptError::EncryptErr)?;
+    let mut length = header_len.to_be_bytes().to_vec();
+    let length_key = kdf(cmd_key, &[HEADER_LENGTH_KEY, &auth_id, &nonce]);
+    let length_nonce = kdf(cmd_key, &[HEADER_LENGTH_NONCE, &auth_id, &nonce]);
+    gcm_seal(&length_key, &length_nonce, &auth_id, &mut length)?;
+    let mut payload = header.to_vec();
+    let payload_key = kdf(cmd_key, &[HEADER_KEY, &auth_id, &nonce]);
+    let payload_nonce = kdf(cmd_key, &[HEADER_NONCE, &auth_id, &nonce]);
+    gcm_seal(&payload_key, &payload_nonce, &auth_id, &mut payload)?;
+    Ok([&auth_id[..], &length, &nonce, &payload].concat())
+}
+
+/// AES-128-GCM with the first 16 bytes of the key and the first 12 bytes of the nonce.
+fn gcm_seal(key: &[u8], nonce: &[u8], aad: &[u8], data: &mut Vec<u8>) -> Result<()> {
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(&key[..16]));
+    cipher.encrypt_in_place(GenericArray::from_slice(&nonce[..12]), aad, data).or(Err(EncryptError::EncryptErr))
+}
+
+fn gcm_open<'a>(key: &[u8], nonce: &[u8], en_data: &'a mut [u8]) -> Result<&'a mut [u8]> {
+    if en_data.len() < TAG_SIZE {
+        return Err(EncryptError::DecryptErr);
+    }
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(&key[..16]));
+    let (data, tag) = en_data.split_at_mut(en_data.len() - TAG_SIZE);
+    cipher
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(&nonce[..12]),
+            &[],
+            data,
+            GenericArray::from_slice(tag),
+        )
+        .or(Err(EncryptError::DecryptErr))?;
+    Ok(data)
+}
+
+/// The key or the IV of the response body, derived from the ones of the request body.
+pub fn response_key(request_key: &[u8; 16]) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key.copy_from_slice(&Sha256::digest(request_key)[..16]);
+    key
+}
+
+/// Open the sealed length of the response header, it doesn't include the tag.
+pub fn open_response_length(key: &[u8; 16], iv: &[u8; 16], sealed: &mut [u8; SEALED_LENGTH_SIZE]) -> Result<usize> {
+    let length = gcm_open(&kdf(key, &[RESPONSE_LENGTH_KEY]), &kdf(iv, &[RESPONSE_LENGTH_NONCE]), sealed)?;
+    Ok(u16::from_be_bytes([length[0], length[1]]) as usize)
+}
+
+/// Open the response header with the key and the IV of the response body.
+pub fn open_response_header<'a>(key: &[u8; 16], iv: &[u8; 16], sealed: &'a mut [u8]) -> Result<&'a mut [u8]> {
+    gcm_open(&kdf(key, &[RESPONSE_KEY]), &kdf(iv, &[RESPONSE_NONCE]), sealed)
+}
+
+enum ChunkCipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Chacha20Poly1305(Box<ChaCha20Poly1305>),
+    None,
+}
+
+/// One direction of the body. Each chunk has a 2 bytes length, then the sealed data and the padding.
+pub struct BodyCipher {
+    cipher: ChunkCipher,
+    /// The chunk count, then the bytes 2..12 of the IV.
+    nonce: [u8; 12],
+    count: u16,
+    /// SHAKE128 of the IV, which masks the lengths and makes the padding lengths.
+    shake: Option<Shake128Reader>,
+    padding: bool,
+}
+
+impl BodyCipher {
+    /// The global padding only works with the chunk masking.
+    pub fn new(security: Security, key: &[u8; 16], iv: &[u8; 16], masking: bool, padding: bool) -> Self {
+        let cipher = match security {
+            Security::Aes128Gcm => ChunkCipher::Aes128Gcm(Box::new(Aes128Gcm::new(GenericArray::from_slice(key)))),
+            Security::Chacha20Poly1305 => {
+                let first = Md5::digest(key);
+                let second = Md5::digest(first);
+                let key = [first, second].concat();
+                ChunkCipher::Chacha20Poly1305(Box::new(ChaCha20Poly1305::new(GenericArray::from_slice(&key))))
+            }
+            Security::None => ChunkCipher::None,
+        };
+        let mut nonce = [0u8; 12];
+        nonce[2..].copy_from_slice(&iv[2..12]);
+        let shake = masking.then(|| {
+            let mut shake = Shake128::default();
+            sha3::digest::Update::update(&mut shake, iv);
+            shake.finalize_xof()
+        });
+        Self {
+            cipher,
+            nonce,
+            count: 0,
+            padding: padding && shake.is_some(),
+            shake,
+        }
+    }
+
+    pub fn overhead(&self) -> usize {
+        match self.cipher {
+            ChunkCipher::None => 0,
+            _ => TAG_SIZE,
+        }
+    }
+
+    fn next_shake(&mut self) -> u16 {
+        let mut bytes = [0u8; 2];
+        if let Some(shake) = &mut self.shake {
+            shake.read(&mut bytes);
+        }
+        u16::from_be_bytes(bytes)
+    }
+
+    /// The padding length is taken before the mask of the length.
+    fn next_padding(&mut self) -> usize {
+        if self.padding {
+            (self.next_shake() % MAX_PADDING) as usize
+        } else {
+            0
+        }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        self.nonce[..2].copy_from_slice(&self.count.to_be_bytes());
+        self.count = self.count.wrapping_add(1);
+        self.nonce
+    }
+
+    /// Append the chunk of the data to the buffer, an empty chunk is the end of the body.
+    pub fn seal_chunk(&mut self, data: &[u8], buf: &mut Vec<u8>) -> Result<()> {
+        let padding = self.next_padding();
+        let size = u16::try_from(data.len() + self.overhead() + padding).map_err(|_| EncryptError::EncryptErr)?;
+        let size = size ^ self.next_shake();
+        buf.extend_from_slice(&size.to_be_bytes());
+        let start = buf.len();
+        buf.extend_from_slice(data);
+        let nonce = self.next_nonce();
+        let nonce = GenericArray::from_slice(&nonce);
+        let tag = match &self.cipher {
+            ChunkCipher::Aes128Gcm(cipher) => cipher.encrypt_in_place_detached(nonce, &[], &mut buf[start..]),
+            ChunkCipher::Chacha20Poly1305(cipher) => cipher.encrypt_in_place_detached(nonce, &[], &mut buf[start..]),
+            ChunkCipher::None => return Ok(()),
+        }
+        .or(Err(EncryptError::EncryptErr))?;
+        buf.extend_from_slice(&tag);
+        let start = buf.len();
+        buf.resize(start + padding, 0);
+        rand::thread_rng().fill_bytes(&mut buf[start..]);
+        Ok(())
+    }
+
+    /// Decode the length of a chunk. Return the size of the whole chunk, and the padding at its end.
+    pub fn open_size(&mut self, size: [u8; 2]) -> Result<(usize, usize)> {
+        let padding = self.next_padding();
+        let size = (u16::from_be_bytes(size) ^ self.next_shake()) as usize;
+        if size < self.overhead() + padding {
+            return Err(EncryptError::DecryptErr);
+        }
+        Ok((size, padding))
+    }
+
+    /// Open a chunk without the padding, an empty one is the end of the body.
+    pub fn open_chunk<'a>(&mut self, en_data: &'a mut [u8]) -> Result<&'a mut [u8]> {
+        if en_data.len() < self.overhead() {
+            return Err(EncryptError::DecryptErr);
+        }
+        let nonce = self.next_nonce();
+        let nonce = GenericArray::from_slice(&nonce);
+        let (data, tag) = en_data.split_at_mut(en_data.len() - self.overhead());
+        match &self.cipher {
+            ChunkCipher::Aes128Gcm(cipher) => cipher.decrypt_in_place_detached(nonce, &[], data, GenericArray::from_slice(tag)),
+            ChunkCipher::Chacha20Poly1305(cipher) => {
+                cipher.decrypt_in_place_detached(nonce, &[], data, GenericArray::from_slice(tag))
+            }
+            ChunkCipher::None => return Ok(data),
+        }
+        .or(Err(EncryptError::DecryptErr))?;
+        Ok(data)
+    }
+}
+
+/// The vectors are made by an independent Python implementation, with hashlib, hmac and `cryptography`,
+/// following the VMess AEAD of v2ray-core. The padding bytes there are 0xee.
+#[cfg(test)]
+mod tests {
+    use crate::encrypt::vmess::{
+        auth_id, cmd_key, kdf, open_response_header, open_response_length, response_key, seal_header_with, BodyCipher, Security,
+        SEALED_LENGTH_SIZE,
+    };
+
+    const UUID: [u8; 16] = [
+        0xb8, 0x31, 0x38, 0x1d, 0x63, 0x24, 0x4d, 0x53, 0xad, 0x4f, 0x8c, 0xda, 0x48, 0xb3, 0x08, 0x11,
+    ];
+    const KEY: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+    const IV: [u8; 16] = [16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31];
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn sealed_header() {
+        let cmd_key = cmd_key(&UUID);
+        assert_eq!(hex(&cmd_key), "b50d916ac0cec067981af8e5f38a758f");
+        assert_eq!(
+            hex(&kdf(&cmd_key, &[b"a", b"bb"])),
+            "ae74585b1a76d8ebf476eeef3cd3dd04918266e4252737eca1f2b89d2eb04636"
+        );
+        let auth_id = auth_id(&cmd_key, 1700000000, [1, 2, 3, 4]);
+        assert_eq!(hex(&auth_id), "4774fe5cc901ea4f81f2159909767a36");
+        let header =
+            unhex("01101112131415161718191a1b1c1d1e1f000102030405060708090a0b0c0d0e0f2a0d33000101bb0205612e636f6d000000190b235c");
+        let sealed = seal_header_with(&cmd_key, &header, auth_id, [0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+        assert_eq!(
+            hex(&sealed),
+            "4774fe5cc901ea4f81f2159909767a36ca36e8a9db61c0c05f7c44faba1ce8245a7a00010203040506078e3573dd1b58552f2be579\
+             8b573e8f5286d9a21eee1f11de837336ec9ff33deac40412ebc15bbb91160583493caab6806a6c4e10b0c1468963ab0c8f7fa10b39\
+             fffddd5e6f7c"
+        );
+    }
+
+    #[test]
+    fn response_header() {
+        let key = response_key(&KEY);
+        let iv = response_key(&IV);
+        assert_eq!(hex(&key), "be45cb2605bf36bebde684841a28f0fd");
+        assert_eq!(hex(&iv), "fc2e2c73072bfa2bda03ff9307472deb");
+        let mut response = unhex("936c422a7a3f0c4835561f50648898c4d6e3da10e4a6a796c6b2adda265894aa176da8de0a92");
+        let mut length = [0u8; SEALED_LENGTH_SIZE];
+        length.copy_from_slice(&response[..SEALED_LENGTH_SIZE]);
+        assert_eq!(open_response_length(&key, &iv, &mut length).unwrap(), 4);
+        let header = open_response_header(&key, &iv, &mut response[SEALED_LENGTH_SIZE..]).unwrap();
+        assert_eq!(header, [0x2a, 0, 0, 0]);
+    }
+
+    /// Seal the chunks, and open them again.
+    fn assert_chunks(security: Security, masking: bool, expected: &str) {
+        let mut cipher = BodyCipher::new(security, &KEY, &IV, masking, false);
+        let mut buf = vec![];
+        cipher.seal_chunk(b"hello", &mut buf).unwrap();
+        cipher.seal_chunk(b"", &mut buf).unwrap();
+        assert_eq!(hex(&buf), expected);
+        assert_eq!(
+            open_chunks(BodyCipher::new(security, &KEY, &IV, masking, false), &mut buf),
+            [&b"hello"[..], b""]
+        );
+    }
+
+    fn open_chunks(mut cipher: BodyCipher, mut data: &mut [u8]) -> Vec<Vec<u8>> {
+        let mut chunks = vec![];
+        while !data.is_empty() {
+            let (size, padding) = cipher.open_size([data[0], data[1]]).unwrap();
+            let (chunk, rest) = data[2..].split_at_mut(size);
+            chunks.push(cipher.open_chunk(&mut chunk[..size - padding]).unwrap().to_vec());
+            data = rest;
+        }
+        chunks
+    }
+
+    #[test]
+    fn body_chunks() {
+        assert_chunks(
+            Security::Aes128Gcm,
+            true,
+            "fc84a316c4d586054e854b4a74fc9040de5bd5eb74d1778e5335c1d866430ee2a52d77f94eb568da9f",
+        );
+        assert_chunks(
+            Security::Chacha20Poly1305,
+            false,
+            "0015d7f66656047edbebabfc02352cca6f96fcc82771bc0010864bcceef142cb6018451fb914f07b7a",
+        );
+        assert_chunks(Security::None, true, "fc9468656c6c6f8e43");
+
+        let mut padded = unhex(
+            "8e65a316c4d586054e854b4a74fc9040de5bd5eb74d177eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeb6b440387f8943863177611c\
+             700a6d10a08b2d5bffd7b1b9eeeeeeeea3f8841527decc4faf420d9a660346dd1a99eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee\
+             eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee",
+        );
+        let cipher = BodyCipher::new(Security::Aes128Gcm, &KEY, &IV, true, true);
+        assert_eq!(open_chunks(cipher, &mut padded), [&b"hello"[..], b"world!", b""]);
+        // Only the padding bytes are random.
+        let mut cipher = BodyCipher::new(Security::Aes128Gcm, &KEY, &IV, true, true);
+        let mut buf = vec![];
+        for chunk in [&b"hello"[..], b"world!", b""] {
+            cipher.seal_chunk(chunk, &mut buf).unwrap();
+        }
+        assert_eq!(buf.len(), padded.len());
+    }
+}
@@ -0,0 +1,8 @@
+//! Touch the 'Internet'. The library is used by the binary and the fuzz targets.
+
+pub mod core;
+pub mod encrypt;
+pub mod net;
+pub mod socks;
+pub mod ss;
+pub mod util;
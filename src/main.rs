@@ -1,21 +1,13 @@
 use std::io;
 use std::path::Path;
 
-use crate::core::config::ConfigReader;
-use crate::core::selector::ProtocolSelector;
-
-mod core;
-mod encrypt;
-mod net;
-mod socks;
-mod ss;
-mod util;
+use touch_rust::core::config::ConfigReader;
+use touch_rust::core::selector::ProtocolSelector;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
     env_logger::init();
 
-
     // test_bytes();
     // Ok(())
 
@@ -23,7 +15,7 @@ async fn main() -> io::Result<()> {
 
     let path = Path::new("./conf/config.json");
     let reader = ConfigReader::read_config(path)?;
-    ProtocolSelector::select(&reader).await
+    ProtocolSelector::select(reader).await
 }
 
 // async fn listen() -> io::Result<()> {
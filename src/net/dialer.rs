@@ -0,0 +1,306 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tokio::time::{sleep, timeout};
+
+use crate::core::profile::{DialConfig, IpPreference};
+use crate::net::dns::DnsResolver;
+use crate::net::http::{authority, HttpConnector};
+use crate::net::stream::{unix_path, BoxStream};
+
+/// RFC 8305 recommends 250ms between two connection attempts.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Open outbound TCP connections with the socket options of an output proxy.
+#[derive(Clone, Default)]
+pub struct Dialer {
+    bind_address: Option<IpAddr>,
+    bind_interface: Option<String>,
+    mark: Option<u32>,
+    no_delay: Option<bool>,
+    keepalive: Option<TcpKeepalive>,
+    fast_open: bool,
+    connect_timeout: Option<Duration>,
+    /// The HTTP proxy in front of the connections of [Dialer::dial].
+    via: Option<HttpConnector>,
+}
+
+impl Dialer {
+    pub fn new(config: &DialConfig) -> io::Result<Self> {
+        let linux_only = config.bind_interface.is_some() || config.mark.is_some() || config.fast_open == Some(true);
+        if linux_only && !cfg!(target_os = "linux") {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "bind_interface, mark and fast_open are only supported on Linux",
+            ));
+        }
+        let keepalive = config.keepalive.map(|idle| {
+            let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(idle));
+            match config.keepalive_interval {
+                Some(interval) => keepalive.with_interval(Duration::from_secs(interval)),
+                None => keepalive,
+            }
+        });
+        Ok(Self {
+            bind_address: config.bind_address,
+            bind_interface: config.bind_interface.clone(),
+            mark: config.mark,
+            no_delay: config.no_delay,
+            keepalive,
+            fast_open: config.fast_open.unwrap_or(false),
+            connect_timeout: config.connect_timeout.map(Duration::from_secs),
+            via: config.via.as_deref().map(HttpConnector::parse).transpose()?,
+        })
+    }
+
+    /// Connect to one address.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        self.set_options(&socket)?;
+        if let Some(ip) = self.bind_address {
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+        match self.connect_timeout {
+            Some(connect_timeout) => timeout(connect_timeout, socket.connect(addr))
+                .await
+                .map_err(|_| Error::new(ErrorKind::TimedOut, format!("Connect to {} timed out", addr)))?,
+            None => socket.connect(addr).await,
+        }
+    }
+
+    /// Connect to the host, through the `via` HTTP proxy if there is one.
+    pub async fn dial(&self, host: &str, port: u16) -> io::Result<BoxStream> {
+        match &self.via {
+            Some(via) => {
+                let stream = self.dial_direct(via.host(), via.port()).await?;
+                via.connect(stream, &authority(host, port)).await
+            }
+            None => self.dial_direct(host, port).await,
+        }
+    }
+
+    pub fn has_via(&self) -> bool {
+        self.via.is_some()
+    }
+
+    /// Connect to a `unix:/path.sock` host, or a TCP host resolved with the global resolver.
+    pub async fn dial_direct(&self, host: &str, port: u16) -> io::Result<BoxStream> {
+        match unix_path(host) {
+            Some(path) => connect_unix(path).await,
+            None => Ok(Box::new(self.connect_host(DnsResolver::global(), host, port).await?)),
+        }
+    }
+
+    /// Resolve the host with the resolver and race the connections to its addresses.
+    pub async fn connect_host(&self, resolver: &DnsResolver, host: &str, port: u16) -> io::Result<TcpStream> {
+        let ips = interleave_ips(resolver.lookup(host).await?, resolver.prefer());
+        self.connect_addrs(ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect()).await
+    }
+
+    /// Connect to the addresses with Happy Eyeballs (RFC 8305).
+    /// A new attempt starts when the last one fails or doesn't finish in [CONNECTION_ATTEMPT_DELAY],
+    /// the first established connection wins and the others are dropped.
+    pub async fn connect_addrs(&self, addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+        let mut pending = addrs.into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = Error::new(ErrorKind::NotFound, "No address to connect");
+        loop {
+            // Every time an attempt fails or the delay passes, start the next one.
+            if let Some(addr) = pending.next() {
+                attempts.push(async move { self.connect(addr).await.map_err(|e| (addr, e)) });
+            }
+            if attempts.is_empty() {
+                return Err(last_err);
+            }
+            let has_pending = pending.len() > 0;
+            tokio::select! {
+                Some(result) = attempts.next() => match result {
+                    Ok(stream) => return Ok(stream),
+                    Err((addr, e)) => last_err = Error::new(e.kind(), format!("Connect to {} failed: {}", addr, e)),
+                },
+                _ = sleep(CONNECTION_ATTEMPT_DELAY), if has_pending => {}
+            }
+        }
+    }
+
+    /// Bind a UDP socket for the outbound packets, it is dual-stack unless there is a bind address.
+    pub fn bind_udp(&self) -> io::Result<UdpSocket> {
+        let socket = match self.bind_address {
+            Some(ip) => bind_udp_socket(SocketAddr::new(ip, 0), false)?,
+            None => bind_udp_socket(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)), true)
+                .or_else(|_| bind_udp_socket(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), false))?,
+        };
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(interface) = &self.bind_interface {
+                socket.bind_device(Some(interface.as_bytes()))?;
+            }
+            if let Some(mark) = self.mark {
+                socket.set_mark(mark)?;
+            }
+        }
+        UdpSocket::from_std(socket.into())
+    }
+
+    /// The local IP of the route to the address, or the bind address. The remote can connect back to it.
+    pub fn local_ip_to(&self, addr: SocketAddr) -> io::Result<IpAddr> {
+        if let Some(ip) = self.bind_address {
+            return Ok(ip);
+        }
+        let unspecified = match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        // Connecting a UDP socket only picks the route, nothing is sent.
+        let socket = std::net::UdpSocket::bind(unspecified)?;
+        socket.connect(addr)?;
+        Ok(socket.local_addr()?.ip())
+    }
+
+    fn set_options(&self, socket: &TcpSocket) -> io::Result<()> {
+        let sock_ref = SockRef::from(socket);
+        if let Some(no_delay) = self.no_delay {
+            sock_ref.set_tcp_nodelay(no_delay)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            sock_ref.set_keepalive(true)?;
+            sock_ref.set_tcp_keepalive(keepalive)?;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(interface) = &self.bind_interface {
+                sock_ref.bind_device(Some(interface.as_bytes()))?;
+            }
+            if let Some(mark) = self.mark {
+                sock_ref.set_mark(mark)?;
+            }
+            // Send the data with SYN if the server supports it, `connect` returns at once.
+            if self.fast_open {
+                set_tcp_option(socket, libc::TCP_FASTOPEN_CONNECT, 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn bind_udp_socket(addr: SocketAddr, dual_stack: bool) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+    if dual_stack {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> io::Result<BoxStream> {
+    Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &str) -> io::Result<BoxStream> {
+    Err(Error::new(ErrorKind::Unsupported, "Unix sockets are not supported"))
+}
+
+/// Set an integer option of the TCP level.
+#[cfg(target_os = "linux")]
+pub fn set_tcp_option<S: std::os::unix::io::AsRawFd>(socket: &S, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    // SAFETY: The option value is a valid c_int and the socket is open.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(Error::last_os_error())
+    }
+}
+
+/// Sort the addresses for [Dialer::connect_addrs], alternate between the families and start with the preferred one.
+pub fn interleave_ips(ips: Vec<IpAddr>, preference: IpPreference) -> Vec<IpAddr> {
+    let (ipv4, ipv6): (Vec<IpAddr>, Vec<IpAddr>) = ips.into_iter().partition(IpAddr::is_ipv4);
+    let (first, second) = match preference {
+        IpPreference::Ipv4 => (ipv4, ipv6),
+        IpPreference::Ipv6 => (ipv6, ipv4),
+    };
+    let mut result = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return result,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    use tokio::net::TcpListener;
+
+    use crate::core::profile::{DialConfig, IpPreference};
+    use crate::net::dialer::{interleave_ips, Dialer};
+
+    #[test]
+    fn interleave() {
+        let ips: Vec<IpAddr> =
+            ["1.1.1.1", "1.0.0.1", "8.8.8.8", "::1", "::2"].iter().map(|s| IpAddr::from_str(s).unwrap()).collect();
+        let order =
+            |preference| -> Vec<String> { interleave_ips(ips.clone(), preference).iter().map(|ip| ip.to_string()).collect() };
+        assert_eq!(order(IpPreference::Ipv6), ["::1", "1.1.1.1", "::2", "1.0.0.1", "8.8.8.8"]);
+        assert_eq!(order(IpPreference::Ipv4), ["1.1.1.1", "::1", "1.0.0.1", "::2", "8.8.8.8"]);
+    }
+
+    #[tokio::test]
+    async fn skip_unreachable_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // TEST-NET-1 is never routed, the attempt hangs until it is dropped.
+        let blackhole = SocketAddr::from_str("192.0.2.1:80").unwrap();
+        let start = Instant::now();
+        let dialer = Dialer::default();
+        let stream = dialer.connect_addrs(vec![blackhole, addr]).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        assert!(dialer.connect_addrs(vec![closed]).await.is_err());
+        assert!(dialer.connect_addrs(vec![]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn dial_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = DialConfig {
+            bind_address: Some(IpAddr::from_str("127.0.0.1").unwrap()),
+            no_delay: Some(true),
+            keepalive: Some(30),
+            keepalive_interval: Some(5),
+            connect_timeout: Some(1),
+            ..DialConfig::default()
+        };
+        let stream = Dialer::new(&config).unwrap().connect(addr).await.unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(stream.local_addr().unwrap().ip(), config.bind_address.unwrap());
+    }
+}
@@ -0,0 +1,68 @@
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use lru::LruCache;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheEntry<V> {
+    pub value: V,
+    pub valid_until: Instant,
+}
+
+/// DNS answers cache, the least recently used entry is dropped when it is full.
+pub struct DnsCache<K: Hash + Eq, V> {
+    entries: Mutex<LruCache<K, CacheEntry<V>>>,
+}
+
+impl<K: Hash + Eq, V: Clone> DnsCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Get the entry if it is still valid at `now`.
+    pub fn get(&self, key: &K, now: Instant) -> Option<CacheEntry<V>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.valid_until > now => Some(entry.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: K, entry: CacheEntry<V>) {
+        self.entries.lock().unwrap().put(key, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::net::dns::cache::{CacheEntry, DnsCache};
+
+    #[test]
+    fn expire_and_evict() {
+        let cache = DnsCache::new(2);
+        let now = Instant::now();
+        let entry = |value: u8, secs| CacheEntry {
+            value,
+            valid_until: now + Duration::from_secs(secs),
+        };
+        cache.insert("a.com", entry(1, 10));
+        cache.insert("b.com", entry(2, 5));
+        assert_eq!(cache.get(&"a.com", now), Some(entry(1, 10)));
+        assert_eq!(cache.get(&"b.com", now).map(|e| e.value), Some(2));
+        assert_eq!(cache.get(&"b.com", now + Duration::from_secs(5)), None);
+        // "a.com" is the least recently used one now.
+        cache.insert("c.com", entry(3, 10));
+        cache.insert("d.com", entry(4, 10));
+        assert_eq!(cache.get(&"a.com", now), None);
+        assert!(cache.get(&"c.com", now).is_some());
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+/// Match a domain name against a set of patterns.
+/// # Example
+/// - `example.com` - only the domain itself
+/// - `*.example.com` - every subdomain of `example.com`, such as `a.example.com` and `a.b.example.com`
+///
+/// An exact pattern is preferred, otherwise the longest wildcard wins.
+pub struct DomainMatcher<T> {
+    exact: HashMap<String, T>,
+    /// Suffixes with the leading dot, sorted from the longest.
+    suffixes: Vec<(String, T)>,
+}
+
+impl<T> DomainMatcher<T> {
+    pub fn new() -> Self {
+        Self {
+            exact: HashMap::new(),
+            suffixes: vec![],
+        }
+    }
+
+    pub fn insert(&mut self, pattern: &str, value: T) {
+        let pattern = normalize_domain(pattern);
+        match pattern.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') => {
+                let suffix = suffix.to_string();
+                self.suffixes.retain(|(s, _)| *s != suffix);
+                let index = self.suffixes.partition_point(|(s, _)| s.len() >= suffix.len());
+                self.suffixes.insert(index, (suffix, value));
+            }
+            _ => {
+                self.exact.insert(pattern, value);
+            }
+        }
+    }
+
+    pub fn get(&self, domain: &str) -> Option<&T> {
+        let domain = normalize_domain(domain);
+        self.exact
+            .get(&domain)
+            .or_else(|| self.suffixes.iter().find(|(suffix, _)| domain.ends_with(suffix.as_str())).map(|(_, value)| value))
+    }
+}
+
+impl<T> Default for DomainMatcher<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Domain names are case insensitive, and the trailing dot of a FQDN is optional.
+pub fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::dns::domain::DomainMatcher;
+
+    #[test]
+    fn match_domain() {
+        let mut matcher = DomainMatcher::new();
+        matcher.insert("*.corp", 1);
+        matcher.insert("*.dev.corp", 2);
+        matcher.insert("git.dev.corp", 3);
+        matcher.insert("Example.com.", 4);
+        assert_eq!(matcher.get("a.corp"), Some(&1));
+        assert_eq!(matcher.get("a.b.corp"), Some(&1));
+        assert_eq!(matcher.get("ci.dev.corp"), Some(&2));
+        assert_eq!(matcher.get("GIT.dev.corp."), Some(&3));
+        assert_eq!(matcher.get("example.com"), Some(&4));
+        assert_eq!(matcher.get("corp"), None);
+        assert_eq!(matcher.get("mycorp"), None);
+        assert_eq!(matcher.get("www.example.com"), None);
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use lru::LruCache;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::net::proxy::{OutProxyStarter, OutputProxy, ProxyInfo, ProxyReader, ProxyWriter, UdpProxyReader, UdpProxyWriter};
+use crate::net::AddressType;
+
+/// Packets waiting for the UDP reader.
+const UDP_CHANNEL_SIZE: usize = 64;
+/// Associations for the fake destinations of a UDP association, the least recently used one is closed for a new one.
+const MAX_UDP_SESSIONS: usize = 32;
+/// The association of a fake destination is closed when nothing is sent to it for so long.
+const UDP_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Hand out addresses of a reserved range for domain names, so a connection to the address can be
+/// turned back into the domain. The least recently used domain loses its address when the range is used up.
+pub struct FakeIpPool {
+    network: u32,
+    /// Count of the usable addresses, without the first and the last one of the range.
+    size: u32,
+    inner: Mutex<FakeIpMap>,
+}
+
+struct FakeIpMap {
+    /// Offset in the range to domain.
+    domains: LruCache<u32, String>,
+    offsets: HashMap<String, u32>,
+    next: u32,
+}
+
+impl FakeIpPool {
+    /// Create a pool from a CIDR such as `198.18.0.0/15`.
+    pub fn new(cidr: &str) -> io::Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid fake IP range {}", cidr));
+        let (ip, prefix) = cidr.split_once('/').ok_or_else(invalid)?;
+        let ip = Ipv4Addr::from_str(ip).map_err(|_| invalid())?;
+        let prefix = u32::from_str(prefix).map_err(|_| invalid())?;
+        if !(1..=30).contains(&prefix) {
+            return Err(invalid());
+        }
+        let mask = u32::MAX << (32 - prefix);
+        Ok(Self {
+            network: u32::from(ip) & mask,
+            size: !mask - 1,
+            inner: Mutex::new(FakeIpMap {
+                domains: LruCache::unbounded(),
+                offsets: HashMap::new(),
+                next: 1,
+            }),
+        })
+    }
+
+    /// Get the fake address of the domain, allocate one if it has none.
+    pub fn fake_ip(&self, domain: &str) -> Ipv4Addr {
+        let mut map = self.inner.lock().unwrap();
+        if let Some(&offset) = map.offsets.get(domain) {
+            map.domains.get(&offset);
+            return self.ip(offset);
+        }
+        let offset = if map.next <= self.size {
+            map.next += 1;
+            map.next - 1
+        } else {
+            let (offset, old_domain) = map.domains.pop_lru().expect("Fake IP pool is not empty");
+            map.offsets.remove(&old_domain);
+            offset
+        };
+        map.domains.put(offset, domain.to_string());
+        map.offsets.insert(domain.to_string(), offset);
+        self.ip(offset)
+    }
+
+    /// Get the domain of a fake address.
+    pub fn domain(&self, ip: Ipv4Addr) -> Option<String> {
+        if !self.contains(ip) {
+            return None;
+        }
+        let offset = u32::from(ip) - self.network;
+        self.inner.lock().unwrap().domains.get(&offset).cloned()
+    }
+
+    /// Whether the address is a usable one of the range.
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let offset = u32::from(ip).wrapping_sub(self.network);
+        offset != 0 && offset <= self.size
+    }
+
+    fn ip(&self, offset: u32) -> Ipv4Addr {
+        Ipv4Addr::from(self.network + offset)
+    }
+}
+
+/// Wrap an output proxy, connect to the domain instead of the fake address.
+pub struct FakeIpOutProxy {
+    out_proxy: Arc<Mutex<Box<dyn OutputProxy + Send>>>,
+    pool: &'static FakeIpPool,
+}
+
+impl FakeIpOutProxy {
+    pub fn new(out_proxy: Box<dyn OutputProxy + Send>, pool: &'static FakeIpPool) -> Self {
+        Self {
+            out_proxy: Arc::new(Mutex::new(out_proxy)),
+            pool,
+        }
+    }
+}
+
+impl OutputProxy for FakeIpOutProxy {
+    fn gen_connector(&mut self) -> io::Result<Box<dyn OutProxyStarter>> {
+        Ok(Box::new(FakeIpOutProxyStarter {
+            starter: self.out_proxy.lock().unwrap().gen_connector()?,
+            out_proxy: self.out_proxy.clone(),
+            pool: self.pool,
+        }))
+    }
+}
+
+struct FakeIpOutProxyStarter {
+    starter: Box<dyn OutProxyStarter>,
+    /// Starts the UDP associations of the fake destinations.
+    out_proxy: Arc<Mutex<Box<dyn OutputProxy + Send>>>,
+    pool: &'static FakeIpPool,
+}
+
+#[async_trait]
+impl OutProxyStarter for FakeIpOutProxyStarter {
+    async fn new_connection(&mut self, proxy_info: ProxyInfo) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>)> {
+        let proxy_info = restore_domain(self.pool, proxy_info);
+        self.starter.new_connection(proxy_info).await
+    }
+
+    /// The replies of a domain come from its real address, but the client waits for them from the fake one.
+    /// So every fake destination has its own association, whose replies are sent from the fake address.
+    async fn new_udp(&mut self) -> io::Result<(Box<dyn UdpProxyReader>, Box<dyn UdpProxyWriter>)> {
+        let (reader, writer) = self.starter.new_udp().await?;
+        let (sender, receiver) = mpsc::channel(UDP_CHANNEL_SIZE);
+        let reader = FakeIpUdpReader {
+            receiver,
+            forward: tokio::task::spawn(forward_replies(reader, None, sender.clone())),
+            data: vec![],
+        };
+        let writer = FakeIpUdpWriter {
+            writer,
+            out_proxy: self.out_proxy.clone(),
+            sessions: LruCache::new(MAX_UDP_SESSIONS),
+            sender,
+            pool: self.pool,
+        };
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+/// The replies of the real destinations and of the fake ones.
+struct FakeIpUdpReader {
+    receiver: mpsc::Receiver<io::Result<(Vec<u8>, ProxyInfo)>>,
+    /// Forwards the replies of the real destinations.
+    forward: JoinHandle<()>,
+    data: Vec<u8>,
+}
+
+impl Drop for FakeIpUdpReader {
+    fn drop(&mut self) {
+        self.forward.abort();
+    }
+}
+
+#[async_trait]
+impl UdpProxyReader for FakeIpUdpReader {
+    async fn read_from(&mut self) -> io::Result<(&mut [u8], ProxyInfo)> {
+        let (data, source) = self.receiver.recv().await.ok_or_else(|| Error::from(ErrorKind::BrokenPipe))??;
+        self.data = data;
+        Ok((&mut self.data, source))
+    }
+}
+
+struct FakeIpUdpWriter {
+    /// Sends to the real destinations.
+    writer: Box<dyn UdpProxyWriter>,
+    out_proxy: Arc<Mutex<Box<dyn OutputProxy + Send>>>,
+    /// Associations by their fake destinations.
+    sessions: LruCache<(Ipv4Addr, u16), FakeIpUdpSession>,
+    sender: mpsc::Sender<io::Result<(Vec<u8>, ProxyInfo)>>,
+    pool: &'static FakeIpPool,
+}
+
+/// The association of a fake destination, it is closed when this is dropped.
+struct FakeIpUdpSession {
+    writer: Box<dyn UdpProxyWriter>,
+    forward: JoinHandle<()>,
+    last_write: Instant,
+}
+
+impl Drop for FakeIpUdpSession {
+    fn drop(&mut self) {
+        self.forward.abort();
+    }
+}
+
+#[async_trait]
+impl UdpProxyWriter for FakeIpUdpWriter {
+    async fn write_to(&mut self, data: &[u8], target: &ProxyInfo) -> io::Result<()> {
+        let restored = restore_domain(self.pool, target.clone());
+        if restored.address_type == target.address_type {
+            return self.writer.write_to(data, target).await;
+        }
+        let now = Instant::now();
+        while let Some((_, session)) = self.sessions.peek_lru() {
+            if now.duration_since(session.last_write) < UDP_SESSION_IDLE_TIMEOUT {
+                break;
+            }
+            self.sessions.pop_lru();
+        }
+        let key = (
+            Ipv4Addr::new(target.address[0], target.address[1], target.address[2], target.address[3]),
+            target.port,
+        );
+        if !self.sessions.contains(&key) {
+            let mut starter = self.out_proxy.lock().unwrap().gen_connector()?;
+            let (reader, writer) = starter.new_udp().await?;
+            let session = FakeIpUdpSession {
+                writer,
+                forward: tokio::task::spawn(forward_replies(reader, Some(target.clone()), self.sender.clone())),
+                last_write: now,
+            };
+            // The least recently used session is dropped when it is full.
+            self.sessions.put(key, session);
+        }
+        let session = self.sessions.get_mut(&key).expect("The session is added");
+        session.last_write = now;
+        if let Err(e) = session.writer.write_to(data, &restored).await {
+            self.sessions.pop(&key);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Forward the replies of an association, from `source` if it is set.
+/// An error of the real destinations ends the whole association, one of a fake destination only ends its own.
+async fn forward_replies(
+    mut reader: Box<dyn UdpProxyReader>,
+    source: Option<ProxyInfo>,
+    sender: mpsc::Sender<io::Result<(Vec<u8>, ProxyInfo)>>,
+) {
+    loop {
+        let reply = match reader.read_from().await {
+            Ok((data, real_source)) => Ok((data.to_vec(), source.clone().unwrap_or(real_source))),
+            Err(e) if source.is_some() => {
+                debug!("Fake IP UDP association closed. {}", e);
+                return;
+            }
+            Err(e) => Err(e),
+        };
+        let failed = reply.is_err();
+        if sender.send(reply).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Turn a fake address back into its domain, other addresses are unchanged.
+fn restore_domain(pool: &FakeIpPool, proxy_info: ProxyInfo) -> ProxyInfo {
+    if proxy_info.address_type != AddressType::IPv4 || proxy_info.address.len() != 4 {
+        return proxy_info;
+    }
+    let ip = Ipv4Addr::new(
+        proxy_info.address[0],
+        proxy_info.address[1],
+        proxy_info.address[2],
+        proxy_info.address[3],
+    );
+    if !pool.contains(ip) {
+        return proxy_info;
+    }
+    match pool.domain(ip) {
+        Some(domain) => {
+            debug!("Fake IP {} is {}", ip, domain);
+            ProxyInfo {
+                address_type: AddressType::Domain,
+                address: domain.into_bytes(),
+                port: proxy_info.port,
+            }
+        }
+        None => {
+            warn!("Fake IP {} has no domain, it may be evicted", ip);
+            proxy_info
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use tokio::net::UdpSocket;
+
+    use crate::net::dialer::Dialer;
+    use crate::net::dns::fake_ip::{FakeIpOutProxy, FakeIpPool};
+    use crate::net::proxy::{OutputProxy, ProxyInfo};
+    use crate::net::raw::RawActive;
+
+    #[test]
+    fn allocate_and_evict() {
+        let pool = FakeIpPool::new("198.18.0.0/30").unwrap();
+        let a = pool.fake_ip("a.com");
+        let b = pool.fake_ip("b.com");
+        assert_eq!((a, b), (Ipv4Addr::new(198, 18, 0, 1), Ipv4Addr::new(198, 18, 0, 2)));
+        assert_eq!(pool.fake_ip("a.com"), a);
+        assert_eq!(pool.domain(b).as_deref(), Some("b.com"));
+        // "a.com" is the least recently used one now.
+        assert_eq!(pool.fake_ip("c.com"), a);
+        assert_eq!(pool.domain(a).as_deref(), Some("c.com"));
+        assert_eq!(pool.fake_ip("b.com"), b);
+        assert_eq!(pool.domain(Ipv4Addr::new(198, 18, 0, 3)), None);
+        assert_eq!(pool.domain(Ipv4Addr::new(10, 0, 0, 1)), None);
+        assert!(!pool.contains(Ipv4Addr::new(198, 18, 0, 0)));
+        assert!(FakeIpPool::new("198.18.0.0/31").is_err());
+        assert!(FakeIpPool::new("::/64").is_err());
+    }
+
+    #[tokio::test]
+    async fn udp_replies_from_fake_ip() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (size, peer) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..size], peer).await.unwrap();
+            }
+        });
+        let pool: &'static FakeIpPool = Box::leak(Box::new(FakeIpPool::new("198.18.0.0/15").unwrap()));
+        let fake = SocketAddr::from((pool.fake_ip("127.0.0.1"), echo_addr.port()));
+        let raw = RawActive::new(None, Dialer::default()).unwrap();
+        let mut starter = FakeIpOutProxy::new(Box::new(raw), pool).gen_connector().unwrap();
+        let (mut reader, mut writer) = starter.new_udp().await.unwrap();
+
+        // The reply of the domain comes from the fake address the client sent to.
+        writer.write_to(b"fake", &ProxyInfo::from(fake)).await.unwrap();
+        let (data, source) = reader.read_from().await.unwrap();
+        assert_eq!(
+            (data.to_vec(), source.address, source.port),
+            (b"fake".to_vec(), vec![198, 18, 0, 1], fake.port())
+        );
+        // Other addresses are unchanged.
+        writer.write_to(b"real", &ProxyInfo::from(echo_addr)).await.unwrap();
+        let (data, source) = reader.read_from().await.unwrap();
+        assert_eq!(
+            (data.to_vec(), source.address, source.port),
+            (b"real".to_vec(), vec![127, 0, 0, 1], echo_addr.port())
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use trust_dns_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts, ServerOrderingStrategy,
+};
+use trust_dns_resolver::error::ResolveResult;
+use trust_dns_resolver::lookup_ip::LookupIp;
+use trust_dns_resolver::TokioAsyncResolver;
+
+pub use fake_ip::FakeIpOutProxy;
+pub use resolver::DnsResolver;
+pub use server::DnsServer;
+
+mod cache;
+pub mod domain;
+mod fake_ip;
+mod resolver;
+mod server;
+
+#[derive(Clone)]
+pub struct DnsClient {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsClient {
+    /// Creat a new DNS client.
+    /// The servers are tried in the given order, see [parse_name_server] for the formats.
+    pub fn new(dns_servers: &[String]) -> io::Result<Self> {
+        if dns_servers.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "No DNS server"));
+        }
+        let mut config = ResolverConfig::new();
+        for server in dns_servers {
+            config.add_name_server(parse_name_server(server)?);
+        }
+        let mut opts = ResolverOpts::default();
+        opts.server_ordering_strategy = ServerOrderingStrategy::UserProvidedOrder;
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        // DnsResolver has its own cache.
+        opts.cache_size = 0;
+        let resolver = TokioAsyncResolver::tokio(config, opts)?;
+        Ok(Self { resolver })
+    }
+
+    /// Query both IPv4 and IPv6 addresses of the domain name.
+    pub async fn lookup_ip(&self, domain: &str) -> ResolveResult<LookupIp> {
+        self.resolver.lookup_ip(domain).await
+    }
+}
+
+/// Parse a DNS server.
+/// # Example
+/// - `8.8.8.8`, `8.8.8.8:53`, `udp://8.8.8.8` - UDP
+/// - `tcp://8.8.8.8` - TCP
+/// - `tls://dns.google@8.8.8.8` - DNS over TLS, the name before `@` is used to verify the certificate
+/// - `https://dns.google@8.8.8.8/dns-query` - DNS over HTTPS
+/// - `quic://dns.adguard.com@94.140.14.14` - DNS over QUIC
+///
+/// IPv6 addresses must be enclosed in brackets if there is a port, such as `tcp://[2001:4860:4860::8888]:53`.
+pub fn parse_name_server(server: &str) -> io::Result<NameServerConfig> {
+    let (scheme, rest) = server.split_once("://").unwrap_or(("udp", server));
+    let (protocol, default_port) = match scheme {
+        "udp" => (Protocol::Udp, 53),
+        "tcp" => (Protocol::Tcp, 53),
+        #[cfg(feature = "dns-over-tls")]
+        "tls" => (Protocol::Tls, 853),
+        #[cfg(feature = "dns-over-https")]
+        "https" => (Protocol::Https, 443),
+        #[cfg(feature = "dns-over-quic")]
+        "quic" => (Protocol::Quic, 853),
+        _ => return Err(invalid_server(server, "unsupported protocol")),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, ""),
+    };
+    // Only DNS over HTTPS has a path, and trust-dns always uses the standard one.
+    let valid_path = path.is_empty() || (scheme == "https" && path == "/dns-query");
+    if !valid_path {
+        return Err(invalid_server(server, "unsupported path"));
+    }
+    let (tls_dns_name, addr) = match host.split_once('@') {
+        Some((name, addr)) => (Some(name.to_string()), addr),
+        None => (None, host),
+    };
+    let socket_addr = parse_socket_addr(addr, default_port).ok_or_else(|| invalid_server(server, "invalid IP address"))?;
+    let mut config = NameServerConfig::new(socket_addr, protocol);
+    config.trust_nx_responses = false;
+    if protocol.is_encrypted() {
+        // Without a name the certificate must be issued for the IP address.
+        config.tls_dns_name = Some(tls_dns_name.unwrap_or_else(|| socket_addr.ip().to_string()));
+    }
+    Ok(config)
+}
+
+/// Parse `ip`, `ip:port` or `[ipv6]:port`.
+fn parse_socket_addr(addr: &str, default_port: u16) -> Option<SocketAddr> {
+    if let Ok(ip) = IpAddr::from_str(addr.trim_start_matches('[').trim_end_matches(']')) {
+        return Some(SocketAddr::new(ip, default_port));
+    }
+    SocketAddr::from_str(addr).ok()
+}
+
+fn invalid_server(server: &str, reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        format!("Invalid DNS server '{}': {}", server, reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use trust_dns_resolver::config::Protocol;
+
+    use crate::net::dns::parse_name_server;
+
+    fn addr(s: &str) -> SocketAddr {
+        SocketAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn plain_servers() {
+        let config = parse_name_server("114.114.114.114").unwrap();
+        assert_eq!(
+            (config.socket_addr, config.protocol),
+            (addr("114.114.114.114:53"), Protocol::Udp)
+        );
+        let config = parse_name_server("udp://8.8.8.8:5353").unwrap();
+        assert_eq!((config.socket_addr, config.protocol), (addr("8.8.8.8:5353"), Protocol::Udp));
+        let config = parse_name_server("tcp://[2001:4860:4860::8888]:53").unwrap();
+        assert_eq!(
+            (config.socket_addr, config.protocol),
+            (addr("[2001:4860:4860::8888]:53"), Protocol::Tcp)
+        );
+        let config = parse_name_server("2001:4860:4860::8888").unwrap();
+        assert_eq!(config.socket_addr, addr("[2001:4860:4860::8888]:53"));
+        assert!(config.tls_dns_name.is_none());
+    }
+
+    #[test]
+    #[cfg(all(feature = "dns-over-tls", feature = "dns-over-https"))]
+    fn encrypted_servers() {
+        let config = parse_name_server("tls://dns.google@8.8.8.8").unwrap();
+        assert_eq!((config.socket_addr, config.protocol), (addr("8.8.8.8:853"), Protocol::Tls));
+        assert_eq!(config.tls_dns_name.as_deref(), Some("dns.google"));
+        let config = parse_name_server("https://1.1.1.1/dns-query").unwrap();
+        assert_eq!((config.socket_addr, config.protocol), (addr("1.1.1.1:443"), Protocol::Https));
+        assert_eq!(config.tls_dns_name.as_deref(), Some("1.1.1.1"));
+    }
+
+    #[test]
+    fn invalid_servers() {
+        assert!(parse_name_server("dns.google").is_err());
+        assert!(parse_name_server("ftp://8.8.8.8").is_err());
+        assert!(parse_name_server("tcp://8.8.8.8/dns-query").is_err());
+    }
+}
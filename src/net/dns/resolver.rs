@@ -0,0 +1,191 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use trust_dns_resolver::error::ResolveErrorKind;
+
+use crate::core::profile::{DnsConfig, IpPreference, OneOrMany};
+use crate::net::dns::cache::{CacheEntry, DnsCache};
+use crate::net::dns::domain::{normalize_domain, DomainMatcher};
+use crate::net::dns::fake_ip::FakeIpPool;
+use crate::net::dns::DnsClient;
+
+static GLOBAL_RESOLVER: OnceLock<DnsResolver> = OnceLock::new();
+
+const DEFAULT_CACHE_SIZE: usize = 1024;
+const DEFAULT_NEGATIVE_TTL: u64 = 30;
+/// TTL of the hosts and the system resolver answers.
+const LOCAL_TTL: Duration = Duration::from_secs(60);
+
+/// Resolve domain names with the hosts, the cache and the DNS servers, in that order.
+pub struct DnsResolver {
+    hosts: DomainMatcher<Vec<IpAddr>>,
+    /// Use the system resolver if it is `None`.
+    servers: Option<DnsClient>,
+    domain_servers: DomainMatcher<DnsClient>,
+    cache: DnsCache<String, Vec<IpAddr>>,
+    negative_ttl: Duration,
+    fake_ip: Option<FakeIpPool>,
+    prefer: IpPreference,
+}
+
+impl DnsResolver {
+    pub fn new(config: DnsConfig) -> io::Result<Self> {
+        let servers = match config.servers {
+            Some(servers) => Some(DnsClient::new(&servers.into_vec())?),
+            None => None,
+        };
+        let mut hosts = DomainMatcher::new();
+        for (pattern, ips) in config.hosts.unwrap_or_default() {
+            hosts.insert(&pattern, ips.into_vec());
+        }
+        let mut domain_servers = DomainMatcher::new();
+        for (pattern, servers) in config.domain_servers.unwrap_or_default() {
+            domain_servers.insert(&pattern, DnsClient::new(&servers.into_vec())?);
+        }
+        Ok(Self {
+            hosts,
+            servers,
+            domain_servers,
+            cache: DnsCache::new(config.cache_size.unwrap_or(DEFAULT_CACHE_SIZE)),
+            negative_ttl: Duration::from_secs(config.negative_ttl.unwrap_or(DEFAULT_NEGATIVE_TTL)),
+            fake_ip: config.fake_ip.as_deref().map(FakeIpPool::new).transpose()?,
+            prefer: config.prefer.unwrap_or(IpPreference::Ipv4),
+        })
+    }
+
+    /// A resolver only using these DNS servers.
+    pub fn with_servers(servers: Vec<String>) -> io::Result<Self> {
+        Self::new(DnsConfig {
+            servers: Some(OneOrMany::Many(servers)),
+            ..DnsConfig::default()
+        })
+    }
+
+    /// Set the process-wide resolver, it must be done before the first [DnsResolver::global] call.
+    pub fn init_global(config: DnsConfig) -> io::Result<()> {
+        let resolver = Self::new(config)?;
+        GLOBAL_RESOLVER
+            .set(resolver)
+            .map_err(|_| Error::new(ErrorKind::AlreadyExists, "The global DNS resolver has been initialized"))
+    }
+
+    /// The process-wide resolver, it uses the system resolver if it isn't initialized.
+    pub fn global() -> &'static DnsResolver {
+        GLOBAL_RESOLVER.get_or_init(|| Self::new(DnsConfig::default()).expect("Default DNS resolver"))
+    }
+
+    /// Query the addresses of a domain name, or parse it if it is an IP address.
+    pub async fn lookup(&self, domain: &str) -> io::Result<Vec<IpAddr>> {
+        if let Ok(ip) = IpAddr::from_str(domain) {
+            return Ok(vec![ip]);
+        }
+        let entry = self.lookup_entry(domain).await?;
+        if entry.value.is_empty() {
+            Err(unknown_host(domain))
+        } else {
+            Ok(entry.value)
+        }
+    }
+
+    /// Query the addresses of a domain name and how long they are valid.
+    /// The addresses are empty if the domain has none.
+    pub async fn lookup_entry(&self, domain: &str) -> io::Result<CacheEntry<Vec<IpAddr>>> {
+        let domain = normalize_domain(domain);
+        if let Some(entry) = self.cached(&domain) {
+            return Ok(entry);
+        }
+        let client = match self.domain_servers.get(&domain).or(self.servers.as_ref()) {
+            Some(client) => client,
+            None => {
+                let ips = lookup_system(&domain).await?;
+                return Ok(CacheEntry {
+                    value: ips,
+                    valid_until: Instant::now() + LOCAL_TTL,
+                });
+            }
+        };
+        let entry = match client.lookup_ip(&domain).await {
+            Ok(lookup) => CacheEntry {
+                value: lookup.iter().collect(),
+                valid_until: lookup.valid_until(),
+            },
+            Err(e) => match e.kind() {
+                ResolveErrorKind::NoRecordsFound { negative_ttl, .. } => {
+                    let ttl = negative_ttl.map_or(self.negative_ttl, |ttl| Duration::from_secs(ttl as u64));
+                    debug!("{} has no address, cache it for {:?}", domain, ttl);
+                    CacheEntry {
+                        value: vec![],
+                        valid_until: Instant::now() + ttl,
+                    }
+                }
+                _ => return Err(e.into()),
+            },
+        };
+        self.cache.insert(domain, entry.clone());
+        Ok(entry)
+    }
+
+    /// Get the addresses from the hosts or the cache, without a query.
+    pub fn cached(&self, domain: &str) -> Option<CacheEntry<Vec<IpAddr>>> {
+        let domain = normalize_domain(domain);
+        self.host(&domain).or_else(|| self.cache.get(&domain, Instant::now()))
+    }
+
+    /// Get the addresses from the hosts.
+    pub fn host(&self, domain: &str) -> Option<CacheEntry<Vec<IpAddr>>> {
+        self.hosts.get(&normalize_domain(domain)).map(|ips| CacheEntry {
+            value: ips.clone(),
+            valid_until: Instant::now() + LOCAL_TTL,
+        })
+    }
+
+    pub fn prefer(&self) -> IpPreference {
+        self.prefer
+    }
+
+    pub fn fake_ip_pool(&self) -> Option<&FakeIpPool> {
+        self.fake_ip.as_ref()
+    }
+}
+
+/// The system resolver has its own cache.
+async fn lookup_system(domain: &str) -> io::Result<Vec<IpAddr>> {
+    let ips = tokio::net::lookup_host((domain, 0)).await?;
+    Ok(ips.map(|addr| addr.ip()).collect())
+}
+
+fn unknown_host(domain: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("Unknown host {}", domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use crate::core::profile::{DnsConfig, OneOrMany};
+    use crate::net::dns::resolver::DnsResolver;
+
+    #[tokio::test]
+    async fn lookup_hosts() {
+        let ip = IpAddr::from_str("10.0.0.1").unwrap();
+        let hosts = HashMap::from([
+            ("*.test".to_string(), OneOrMany::One(ip)),
+            ("localhost".to_string(), OneOrMany::Many(vec![ip, ip])),
+        ]);
+        let resolver = DnsResolver::new(DnsConfig {
+            hosts: Some(hosts),
+            ..DnsConfig::default()
+        })
+        .unwrap();
+        assert_eq!(resolver.lookup("a.b.test").await.unwrap(), vec![ip]);
+        assert_eq!(resolver.lookup("LOCALHOST.").await.unwrap(), vec![ip, ip]);
+        assert_eq!(resolver.lookup("::1").await.unwrap(), vec![IpAddr::from_str("::1").unwrap()]);
+    }
+}
@@ -0,0 +1,435 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{debug, error, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use trust_dns_resolver::proto::op::{Message, MessageType, ResponseCode};
+use trust_dns_resolver::proto::rr::{RData, Record, RecordType};
+
+use crate::core::profile::DnsPassiveConfig;
+use crate::net::dialer::Dialer;
+use crate::net::dns::cache::{CacheEntry, DnsCache};
+use crate::net::dns::domain::{normalize_domain, DomainMatcher};
+use crate::net::dns::DnsResolver;
+use crate::net::listener::Listener;
+use crate::net::proxy::{InputProxy, OutProxyStarter, OutputProxy, ProxyInfo};
+use crate::net::stream::BoxStream;
+
+const DEFAULT_UPSTREAM: &str = "8.8.8.8:53";
+const CACHE_SIZE: usize = 1024;
+/// TTL of the responses without records.
+const NEGATIVE_TTL: u32 = 30;
+/// The fake addresses are only valid while they are in the pool, don't let clients keep them.
+const FAKE_IP_TTL: Duration = Duration::from_secs(1);
+/// How long a TCP client can stay silent between two queries, or in the middle of one.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a forwarded query can take with its connection, the client gets ServFail after it.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A local DNS server on UDP and TCP.
+/// The `A` and `AAAA` queries are answered from the global resolver if it has cached them,
+/// or with fake addresses if the global resolver has a fake IP range.
+/// There are no routing rules yet, the `direct` domains are resolved with the dialer of the input instead,
+/// others are sent to the upstream as DNS over TCP through the output proxy.
+pub struct DnsServer {
+    udp_sockets: Vec<Arc<UdpSocket>>,
+    tcp_listener: Listener,
+    out_proxy: Box<dyn OutputProxy + Send>,
+    handler: Arc<DnsHandler>,
+}
+
+impl DnsServer {
+    pub async fn new(passive: &DnsPassiveConfig, out_proxy: Box<dyn OutputProxy + Send>) -> io::Result<Self> {
+        let tcp_listener = Listener::bind(passive.local_host.as_slice(), passive.local_port, passive.listen.as_ref()).await?;
+        // Listen on UDP with the same addresses, the port may be chosen by the TCP listener.
+        let mut udp_sockets = vec![];
+        for addr in tcp_listener.local_addrs() {
+            udp_sockets.push(Arc::new(UdpSocket::bind(addr).await?));
+        }
+        info!("DNS server bind in {:?}", tcp_listener.local_addrs());
+
+        let upstream = passive.upstream.as_deref().unwrap_or(DEFAULT_UPSTREAM);
+        let upstream = SocketAddr::from_str(upstream)
+            .or_else(|_| IpAddr::from_str(upstream).map(|ip| SocketAddr::new(ip, 53)))
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Error DNS upstream address"))?;
+        let mut direct = DomainMatcher::new();
+        for pattern in passive.direct.iter().flatten() {
+            direct.insert(pattern, ());
+        }
+        let handler = DnsHandler {
+            upstream,
+            direct,
+            dialer: passive.dial.as_ref().map(Dialer::new).transpose()?.unwrap_or_default(),
+            cache: DnsCache::new(CACHE_SIZE),
+        };
+        Ok(Self {
+            udp_sockets,
+            tcp_listener,
+            out_proxy,
+            handler: Arc::new(handler),
+        })
+    }
+}
+
+#[async_trait]
+impl InputProxy for DnsServer {
+    async fn start(&mut self) -> io::Result<()> {
+        info!("DNS server start listen");
+        let (sender, mut receiver) = mpsc::channel(self.udp_sockets.len().max(1));
+        for socket in &self.udp_sockets {
+            let socket = socket.clone();
+            let sender = sender.clone();
+            tokio::task::spawn(async move {
+                let mut buf = [0u8; 4096];
+                loop {
+                    let received = socket.recv_from(&mut buf).await.map(|(size, client)| (buf[..size].to_vec(), client));
+                    if sender.send((socket.clone(), received)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        loop {
+            tokio::select! {
+                Some((socket, result)) = receiver.recv() => {
+                    let (query, client) = match result {
+                        Ok(n) => n,
+                        Err(e) => {
+                            error!("DNS server UDP receive error. {}", e);
+                            continue;
+                        }
+                    };
+                    let starter = match self.out_proxy.gen_connector() {
+                        Ok(n) => n,
+                        Err(_) => continue,
+                    };
+                    let handler = self.handler.clone();
+                    tokio::task::spawn(async move {
+                        if let Err(e) = handler.handle_udp(&socket, client, &query, starter).await {
+                            error!("DNS server UDP error. {}", e)
+                        };
+                    });
+                }
+                result = self.tcp_listener.accept() => {
+                    let (tcp_stream, _addr) = result?;
+                    let starter = match self.out_proxy.gen_connector() {
+                        Ok(n) => n,
+                        Err(_) => continue,
+                    };
+                    let handler = self.handler.clone();
+                    tokio::task::spawn(async move {
+                        if let Err(e) = handler.handle_tcp(tcp_stream, starter).await {
+                            error!("DNS server TCP error. {}", e)
+                        };
+                    });
+                }
+            }
+        }
+    }
+}
+
+struct DnsHandler {
+    upstream: SocketAddr,
+    direct: DomainMatcher<()>,
+    /// Dials the upstream for the `direct` domains.
+    dialer: Dialer,
+    cache: DnsCache<(String, RecordType), Message>,
+}
+
+impl DnsHandler {
+    async fn handle_udp(
+        &self,
+        socket: &UdpSocket,
+        client: SocketAddr,
+        query: &[u8],
+        mut starter: Box<dyn OutProxyStarter>,
+    ) -> io::Result<()> {
+        let request = Message::from_vec(query).map_err(invalid_data)?;
+        let mut response = self.answer(&request, &mut *starter).await;
+        let mut data = response.to_vec().map_err(invalid_data)?;
+        // The client will retry over TCP.
+        if data.len() > request.max_payload() as usize {
+            response = new_response(&request, response.response_code());
+            response.set_truncated(true);
+            data = response.to_vec().map_err(invalid_data)?;
+        }
+        socket.send_to(&data, client).await?;
+        Ok(())
+    }
+
+    async fn handle_tcp(&self, mut tcp_stream: BoxStream, mut starter: Box<dyn OutProxyStarter>) -> io::Result<()> {
+        loop {
+            let len = match timeout(TCP_IDLE_TIMEOUT, tcp_stream.read_u16()).await {
+                Ok(Ok(len)) => len as usize,
+                Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(()),
+            };
+            let mut query = vec![0u8; len];
+            timeout(TCP_IDLE_TIMEOUT, tcp_stream.read_exact(&mut query))
+                .await
+                .map_err(|_| Error::new(ErrorKind::TimedOut, "DNS client timed out"))??;
+            let request = Message::from_vec(&query).map_err(invalid_data)?;
+            let response = self.answer(&request, &mut *starter).await.to_vec().map_err(invalid_data)?;
+            let mut data = Vec::with_capacity(2 + response.len());
+            data.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            data.extend_from_slice(&response);
+            tcp_stream.write_all(&data).await?;
+        }
+    }
+
+    async fn answer(&self, request: &Message, starter: &mut dyn OutProxyStarter) -> Message {
+        match self.try_answer(request, starter).await {
+            Ok(response) => response,
+            Err(e) => {
+                error!("DNS query {:?} failed. {}", request.queries(), e);
+                new_response(request, ResponseCode::ServFail)
+            }
+        }
+    }
+
+    async fn try_answer(&self, request: &Message, starter: &mut dyn OutProxyStarter) -> io::Result<Message> {
+        let query = match request.queries() {
+            [query] if request.message_type() == MessageType::Query => query,
+            _ => return Ok(new_response(request, ResponseCode::FormErr)),
+        };
+        let domain = normalize_domain(&query.name().to_ascii());
+        let record_type = query.query_type();
+        let direct = self.direct.get(&domain).is_some();
+        if matches!(record_type, RecordType::A | RecordType::AAAA) {
+            let resolver = DnsResolver::global();
+            if let Some(entry) = resolver.host(&domain) {
+                return Ok(ip_response(request, record_type, entry));
+            }
+            if let (Some(pool), false) = (resolver.fake_ip_pool(), direct) {
+                let entry = CacheEntry {
+                    value: vec![IpAddr::V4(pool.fake_ip(&domain))],
+                    valid_until: Instant::now() + FAKE_IP_TTL,
+                };
+                return Ok(ip_response(request, record_type, entry));
+            }
+            if let Some(entry) = resolver.cached(&domain) {
+                return Ok(ip_response(request, record_type, entry));
+            }
+            if direct {
+                let entry = resolver.lookup_entry(&domain).await?;
+                return Ok(ip_response(request, record_type, entry));
+            }
+        }
+
+        let key = (domain, record_type);
+        let now = Instant::now();
+        if let Some(entry) = self.cache.get(&key, now) {
+            let mut response = entry.value;
+            let ttl = entry.valid_until.duration_since(now).as_secs().max(1) as u32;
+            response.set_id(request.id());
+            response.answers_mut().iter_mut().for_each(|record| {
+                record.set_ttl(ttl);
+            });
+            return Ok(response);
+        }
+        let response = if direct {
+            timeout(UPSTREAM_TIMEOUT, self.forward_direct(request)).await
+        } else {
+            timeout(UPSTREAM_TIMEOUT, self.forward(request, starter)).await
+        };
+        let response = response.map_err(|_| Error::new(ErrorKind::TimedOut, "DNS upstream timed out"))??;
+        if let Some(ttl) = response_ttl(&response).filter(|ttl| *ttl > 0) {
+            debug!("Cache DNS response of {:?} for {}s", key, ttl);
+            let entry = CacheEntry {
+                value: response.clone(),
+                valid_until: now + Duration::from_secs(ttl as u64),
+            };
+            self.cache.insert(key, entry);
+        }
+        Ok(response)
+    }
+
+    /// Send the query to the upstream as DNS over TCP.
+    async fn forward(&self, request: &Message, starter: &mut dyn OutProxyStarter) -> io::Result<Message> {
+        let mut data = tcp_query(request)?;
+        let (mut reader, mut writer) = starter.new_connection(ProxyInfo::from(self.upstream)).await?;
+        writer.write(&mut data).await?;
+
+        let mut buf: Vec<u8> = vec![];
+        loop {
+            if buf.len() >= 2 {
+                let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+                if buf.len() >= 2 + len {
+                    let _ = writer.shutdown().await;
+                    return Message::from_vec(&buf[2..2 + len]).map_err(invalid_data);
+                }
+            }
+            let chunk = reader.read().await?;
+            if chunk.is_empty() {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "DNS upstream closed"));
+            }
+            buf.extend_from_slice(chunk);
+        }
+    }
+
+    /// Send the query to the upstream as DNS over TCP with the dialer, without the output proxy.
+    async fn forward_direct(&self, request: &Message) -> io::Result<Message> {
+        let data = tcp_query(request)?;
+        let mut stream = self.dialer.dial(&self.upstream.ip().to_string(), self.upstream.port()).await?;
+        stream.write_all(&data).await?;
+        let mut response = vec![0u8; stream.read_u16().await? as usize];
+        stream.read_exact(&mut response).await?;
+        Message::from_vec(&response).map_err(invalid_data)
+    }
+}
+
+/// The query with its length prefix of DNS over TCP.
+fn tcp_query(request: &Message) -> io::Result<Vec<u8>> {
+    let query = request.to_vec().map_err(invalid_data)?;
+    let mut data = Vec::with_capacity(2 + query.len());
+    data.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    data.extend_from_slice(&query);
+    Ok(data)
+}
+
+fn new_response(request: &Message, response_code: ResponseCode) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true)
+        .set_response_code(response_code)
+        .add_queries(request.queries().to_vec());
+    response
+}
+
+/// Build the response with the addresses of the queried type.
+fn ip_response(request: &Message, record_type: RecordType, entry: CacheEntry<Vec<IpAddr>>) -> Message {
+    if entry.value.is_empty() {
+        return new_response(request, ResponseCode::NXDomain);
+    }
+    let mut response = new_response(request, ResponseCode::NoError);
+    let name = request.queries()[0].name().clone();
+    let ttl = entry.valid_until.saturating_duration_since(Instant::now()).as_secs().max(1) as u32;
+    let answers = entry.value.into_iter().filter_map(|ip| match (ip, record_type) {
+        (IpAddr::V4(ip), RecordType::A) => Some(RData::A(ip)),
+        (IpAddr::V6(ip), RecordType::AAAA) => Some(RData::AAAA(ip)),
+        _ => None,
+    });
+    response.add_answers(answers.map(|rdata| Record::from_rdata(name.clone(), ttl, rdata)));
+    response
+}
+
+/// How long a response can be cached, the shortest TTL of the answers or the SOA for a negative response.
+fn response_ttl(response: &Message) -> Option<u32> {
+    if response.truncated() || !matches!(response.response_code(), ResponseCode::NoError | ResponseCode::NXDomain) {
+        return None;
+    }
+    if let Some(ttl) = response.answers().iter().map(|record| record.ttl()).min() {
+        return Some(ttl);
+    }
+    let soa_ttl = response.name_servers().iter().find_map(|record| match record.data() {
+        Some(RData::SOA(soa)) => Some(record.ttl().min(soa.minimum())),
+        _ => None,
+    });
+    Some(soa_ttl.unwrap_or(NEGATIVE_TTL))
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> Error {
+    Error::new(ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::SocketAddr;
+
+    use async_trait::async_trait;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use trust_dns_resolver::proto::op::{Message, Query, ResponseCode};
+    use trust_dns_resolver::proto::rr::rdata::TXT;
+    use trust_dns_resolver::proto::rr::{Name, RData, Record, RecordType};
+
+    use crate::net::dialer::Dialer;
+    use crate::net::dns::cache::DnsCache;
+    use crate::net::dns::domain::DomainMatcher;
+    use crate::net::dns::server::{new_response, DnsHandler};
+    use crate::net::proxy::{OutProxyStarter, OutputProxy, ProxyInfo, ProxyReader, ProxyWriter};
+    use crate::net::raw::RawActive;
+
+    /// An upstream answering one query with a TXT record.
+    async fn start_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut buf = vec![0u8; len as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+            let request = Message::from_vec(&buf).unwrap();
+            let mut response = new_response(&request, ResponseCode::NoError);
+            let txt = RData::TXT(TXT::new(vec!["touch".to_string()]));
+            response.add_answer(Record::from_rdata(request.queries()[0].name().clone(), 300, txt));
+            let data = response.to_vec().unwrap();
+            stream.write_u16(data.len() as u16).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+        });
+        upstream
+    }
+
+    #[tokio::test]
+    async fn forward_and_cache() {
+        // Only one connection, the second query must be answered from the cache.
+        let handler = DnsHandler {
+            upstream: start_upstream().await,
+            direct: DomainMatcher::new(),
+            dialer: Dialer::default(),
+            cache: DnsCache::new(8),
+        };
+        let mut starter = RawActive::new(None, Dialer::default()).unwrap().gen_connector().unwrap();
+        let mut request = Message::new();
+        request.set_id(1).add_query(Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::TXT));
+        let response = handler.answer(&request, &mut *starter).await;
+        assert_eq!((response.id(), response.answers().len()), (1, 1));
+        request.set_id(2);
+        let response = handler.answer(&request, &mut *starter).await;
+        assert_eq!((response.id(), response.answers().len()), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn forward_direct() {
+        let mut direct = DomainMatcher::new();
+        direct.insert("*.com", ());
+        let handler = DnsHandler {
+            upstream: start_upstream().await,
+            direct,
+            dialer: Dialer::default(),
+            cache: DnsCache::new(8),
+        };
+        // The output proxy isn't used for the direct domains.
+        let mut starter = UnsupportedStarter;
+        let mut request = Message::new();
+        request.set_id(1).add_query(Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::TXT));
+        let response = handler.answer(&request, &mut starter).await;
+        assert_eq!(
+            (response.response_code(), response.answers().len()),
+            (ResponseCode::NoError, 1)
+        );
+    }
+
+    struct UnsupportedStarter;
+
+    #[async_trait]
+    impl OutProxyStarter for UnsupportedStarter {
+        async fn new_connection(&mut self, _proxy_info: ProxyInfo) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>)> {
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+    }
+}
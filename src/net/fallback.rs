@@ -0,0 +1,63 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::net::dialer::Dialer;
+use crate::net::stream::{unix_path, BoxStream};
+
+/// Where an input sends the connections which fail its authentication, such as a web server,
+/// so the port looks like that server to probes.
+pub struct Fallback {
+    host: String,
+    port: u16,
+}
+
+impl Fallback {
+    /// Parse `host:port`, `[IPv6]:port` or `unix:/path.sock`.
+    pub fn parse(fallback: &str) -> io::Result<Self> {
+        if unix_path(fallback).is_some() {
+            return Ok(Self {
+                host: fallback.to_string(),
+                port: 0,
+            });
+        }
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid fallback address {}", fallback));
+        let (host, port) = fallback.rsplit_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Read the bytes of `expected` from the stream, without reading any further.
+/// Return the bytes read if they don't match, so they can be sent to the fallback.
+pub async fn read_expected(stream: &mut BoxStream, expected: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let mut head = vec![0u8; expected.len()];
+    let mut size = 0;
+    while size < head.len() {
+        let read_size = stream.read(&mut head[size..]).await?;
+        if read_size == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Request is incomplete"));
+        }
+        size += read_size;
+        if head[..size] != expected[..size] {
+            head.truncate(size);
+            return Ok(Some(head));
+        }
+    }
+    Ok(None)
+}
+
+/// Relay the stream to the fallback, starting with the bytes already read.
+/// Without a fallback, the connection is closed with an error.
+pub async fn fall_back(mut stream: BoxStream, head: &[u8], fallback: Option<&Fallback>) -> io::Result<()> {
+    let fallback = fallback.ok_or_else(|| Error::new(ErrorKind::PermissionDenied, "Authentication failed"))?;
+    debug!("Authentication failed, fall back to {}", fallback.host);
+    let mut fallback_stream = Dialer::default().dial(&fallback.host, fallback.port).await?;
+    fallback_stream.write_all(head).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut fallback_stream).await?;
+    Ok(())
+}
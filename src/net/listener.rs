@@ -0,0 +1,317 @@
+use std::collections::HashSet;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::{error, info};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{lookup_host, TcpListener};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use crate::core::profile::ListenConfig;
+use crate::net::stream::{unix_path, BoxStream};
+
+const DEFAULT_BACKLOG: i32 = 1024;
+/// Max count of the pending TCP Fast Open requests.
+const FAST_OPEN_QUEUE: i32 = 256;
+/// Wait after an accept error, such as too many open files, before accepting again.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// The accepted stream and its peer address, Unix socket peers have no address.
+type Accepted = (BoxStream, Option<SocketAddr>);
+
+/// Accept the TCP and Unix socket connections of an input proxy.
+/// Every socket has its own accept loop, there are several sockets on each address with `reuse_port`.
+pub struct Listener {
+    receiver: mpsc::Receiver<Accepted>,
+    local_addrs: Vec<SocketAddr>,
+    unix_paths: Vec<PathBuf>,
+    accept_loops: Vec<JoinHandle<()>>,
+}
+
+impl Listener {
+    /// Listen on every address of the hosts, a host is an IP address, `[IPv6]`, a hostname or `unix:/path.sock`.
+    pub async fn bind(hosts: &[String], port: u16, config: Option<&ListenConfig>) -> io::Result<Self> {
+        let default_config = ListenConfig::default();
+        let config = config.unwrap_or(&default_config);
+        let fast_open = config.fast_open.unwrap_or(false);
+        if fast_open && !cfg!(target_os = "linux") {
+            return Err(Error::new(ErrorKind::Unsupported, "fast_open is only supported on Linux"));
+        }
+        let sockets_per_addr = config.reuse_port.unwrap_or(1).max(1);
+        let (sender, receiver) = mpsc::channel(sockets_per_addr * hosts.len().max(1));
+        let (unix_hosts, tcp_hosts): (Vec<String>, Vec<String>) =
+            hosts.iter().cloned().partition(|host| unix_path(host).is_some());
+        let mut listeners = vec![];
+        let mut local_addrs = vec![];
+        let tcp_addrs = if tcp_hosts.is_empty() && !unix_hosts.is_empty() {
+            vec![]
+        } else {
+            listen_addrs(&tcp_hosts, port).await?
+        };
+        for addr in tcp_addrs {
+            // With port 0, the other sockets must use the port of the first one.
+            let first = bind_tcp(addr, config, fast_open)?;
+            let local_addr = first.local_addr()?;
+            listeners.push(first);
+            for _ in 1..sockets_per_addr {
+                listeners.push(bind_tcp(local_addr, config, fast_open)?);
+            }
+            info!("Listen on {}", local_addr);
+            local_addrs.push(local_addr);
+        }
+        let mut accept_loops: Vec<JoinHandle<()>> = listeners
+            .into_iter()
+            .map(|listener| {
+                let sender = sender.clone();
+                tokio::task::spawn(async move {
+                    loop {
+                        let accepted = match listener.accept().await {
+                            Ok((stream, addr)) => (Box::new(stream) as BoxStream, Some(addr)),
+                            Err(e) => {
+                                accept_error(e).await;
+                                continue;
+                            }
+                        };
+                        if sender.send(accepted).await.is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+        let mut unix_paths = vec![];
+        for host in &unix_hosts {
+            let path = unix_path(host).unwrap_or_default();
+            accept_loops.push(spawn_unix_accept(path, config, sender.clone())?);
+            info!("Listen on {}", host);
+            unix_paths.push(PathBuf::from(path));
+        }
+        Ok(Self {
+            receiver,
+            local_addrs,
+            unix_paths,
+            accept_loops,
+        })
+    }
+
+    /// Accept a new connection from any socket.
+    /// The errors of the sockets are logged in their accept loops, they don't end the input.
+    pub async fn accept(&mut self) -> io::Result<Accepted> {
+        self.receiver.recv().await.ok_or_else(|| Error::new(ErrorKind::BrokenPipe, "Listener is closed"))
+    }
+
+    /// The TCP addresses, without the Unix sockets.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.accept_loops.iter().for_each(JoinHandle::abort);
+        for path in &self.unix_paths {
+            let _result = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Resolve the hosts to the addresses to listen on.
+pub async fn listen_addrs(hosts: &[String], port: u16) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs: Vec<SocketAddr> = vec![];
+    for host in hosts {
+        match IpAddr::from_str(host.trim_start_matches('[').trim_end_matches(']')) {
+            Ok(ip) => addrs.push(SocketAddr::new(ip, port)),
+            Err(_) => addrs.extend(lookup_host((host.as_str(), port)).await?),
+        }
+    }
+    // A host can resolve to the address of another one, which isn't next to it.
+    let mut seen = HashSet::new();
+    addrs.retain(|addr| seen.insert(*addr));
+    if addrs.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "No address to listen on"));
+    }
+    Ok(addrs)
+}
+
+/// Log the error and wait, it is usually temporary, such as running out of file descriptors.
+async fn accept_error(e: io::Error) {
+    error!("Accept error. {}", e);
+    sleep(ACCEPT_ERROR_DELAY).await;
+}
+
+fn bind_tcp(addr: SocketAddr, config: &ListenConfig, fast_open: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        // Accept IPv4 connections on `::` too, unless it is disabled.
+        socket.set_only_v6(config.ipv6_only.unwrap_or(false))?;
+    }
+    #[cfg(unix)]
+    {
+        socket.set_reuse_address(true)?;
+        if config.reuse_port.unwrap_or(1) > 1 {
+            socket.set_reuse_port(true)?;
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if fast_open {
+        crate::net::dialer::set_tcp_option(&socket, libc::TCP_FASTOPEN, FAST_OPEN_QUEUE)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(config.backlog.map_or(DEFAULT_BACKLOG, |backlog| backlog as i32))?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(unix)]
+fn spawn_unix_accept(path: &str, config: &ListenConfig, sender: mpsc::Sender<Accepted>) -> io::Result<JoinHandle<()>> {
+    let listener = bind_unix(path, config)?;
+    Ok(tokio::task::spawn(async move {
+        loop {
+            let accepted = match listener.accept().await {
+                Ok((stream, _addr)) => (Box::new(stream) as BoxStream, None),
+                Err(e) => {
+                    accept_error(e).await;
+                    continue;
+                }
+            };
+            if sender.send(accepted).await.is_err() {
+                break;
+            }
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+fn spawn_unix_accept(_path: &str, _config: &ListenConfig, _sender: mpsc::Sender<Accepted>) -> io::Result<JoinHandle<()>> {
+    Err(Error::new(ErrorKind::Unsupported, "Unix sockets are not supported"))
+}
+
+/// Bind a Unix socket, then set its file mode and owner.
+/// The socket is bound in a private directory and moved to the path once it is set,
+/// so other users can't connect with the default mode in the meantime.
+#[cfg(unix)]
+fn bind_unix(path: &str, config: &ListenConfig) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    let mode = config
+        .unix_mode
+        .as_ref()
+        .map(|mode| {
+            u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("Invalid unix_mode {}", mode)))
+        })
+        .transpose()?;
+    // The socket file of the last run makes the bind fail, other files are kept.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let target = PathBuf::from(path);
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let private_dir = parent.join(format!(".touch-rs-{}-{}", std::process::id(), unix_bind_id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+    let bound = (|| {
+        let temp_path = private_dir.join("socket");
+        let listener = tokio::net::UnixListener::bind(&temp_path)?;
+        if let Some(mode) = mode {
+            std::fs::set_permissions(&temp_path, std::fs::Permissions::from_mode(mode))?;
+        }
+        if config.unix_owner.is_some() || config.unix_group.is_some() {
+            std::os::unix::fs::chown(&temp_path, config.unix_owner, config.unix_group)?;
+        }
+        std::fs::rename(&temp_path, &target)?;
+        Ok(listener)
+    })();
+    // The directory is empty after the rename, or holds the socket of a failed bind.
+    let _result = std::fs::remove_file(private_dir.join("socket"));
+    let _result = std::fs::remove_dir(&private_dir);
+    bound
+}
+
+/// A different number for every Unix socket bound by this process.
+#[cfg(unix)]
+fn unix_bind_id() -> usize {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use crate::core::profile::ListenConfig;
+    use crate::net::listener::{listen_addrs, Listener};
+
+    #[tokio::test]
+    async fn reuse_port_listener() {
+        let config = ListenConfig {
+            reuse_port: Some(4),
+            backlog: Some(16),
+            ..ListenConfig::default()
+        };
+        let hosts = vec!["127.0.0.1".to_string(), "[::1]".to_string()];
+        let mut listener = Listener::bind(&hosts, 0, Some(&config)).await.unwrap();
+        assert_eq!(listener.local_addrs().len(), 2);
+        for addr in listener.local_addrs().to_vec() {
+            for _ in 0..8 {
+                let client = TcpStream::connect(addr).await.unwrap();
+                let (_stream, peer) = listener.accept().await.unwrap();
+                assert_eq!(peer, Some(client.local_addr().unwrap()));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicate_hosts() {
+        let hosts = vec!["127.0.0.1".to_string(), "[::1]".to_string(), "127.0.0.1".to_string()];
+        let addrs = listen_addrs(&hosts, 80).await.unwrap();
+        assert_eq!(addrs, ["127.0.0.1:80".parse().unwrap(), "[::1]:80".parse().unwrap()]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_listener() {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("touch-rs-{}.sock", std::process::id()));
+        let config = ListenConfig {
+            unix_mode: Some("600".to_string()),
+            ..ListenConfig::default()
+        };
+        let hosts = vec![format!("unix:{}", path.display())];
+        let mut listener = Listener::bind(&hosts, 0, Some(&config)).await.unwrap();
+        assert!(listener.local_addrs().is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        // The private directory of the bind is removed.
+        let private_prefix = format!(".touch-rs-{}-", std::process::id());
+        let leftovers = std::fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(&private_prefix))
+            .count();
+        assert_eq!(leftovers, 0);
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut stream, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, None);
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        drop(listener);
+        assert!(!path.exists());
+    }
+}
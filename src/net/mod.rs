@@ -1,9 +1,21 @@
-mod dns;
+pub mod dialer;
+pub mod dns;
+pub mod fallback;
 pub mod http;
+pub mod listener;
+pub mod plugin;
 pub mod proxy;
 pub mod raw;
+pub mod socks4;
 pub mod socks5;
 pub mod ss_stream;
+pub mod ss_stream_cipher;
+pub mod stream;
+pub mod transport;
+pub mod trojan;
+pub mod vless;
+#[cfg(feature = "rust-crypto")]
+pub mod vmess;
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum AddressType {
@@ -0,0 +1,199 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use crate::core::profile::BasePassiveConfig;
+use crate::net::listener::Listener;
+
+const LOCAL_HOST: &str = "127.0.0.1";
+/// Wait before restarting a plugin which exits, so a broken one doesn't spin.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// The delay doubles on every failed restart up to this. A plugin which ran so long starts over from [RESTART_DELAY].
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// A SIP003 plugin process, such as `obfs-local` or `v2ray-plugin`.
+/// The first start fails with an error, such as for a missing binary. After that,
+/// it is restarted when it exits, and killed when this is dropped.
+pub struct Plugin {
+    local_addr: Arc<Mutex<SocketAddr>>,
+    supervisor: JoinHandle<()>,
+}
+
+impl Plugin {
+    /// Start a client plugin. It listens on a free local port, and forwards to the remote server.
+    /// Another process can take the port before the plugin binds it, so every restart uses a new free port.
+    pub fn start_client(plugin: &str, options: Option<&str>, remote_host: &str, remote_port: u16) -> io::Result<Self> {
+        Self::start(plugin, options, (remote_host, remote_port), free_local_addr()?, true)
+    }
+
+    /// Start a server plugin. It listens on `remote` of SIP003, the public address,
+    /// and forwards to the local server at `local_addr`.
+    pub fn start_server(plugin: &str, options: Option<&str>, host: &str, port: u16, local_addr: SocketAddr) -> io::Result<Self> {
+        Self::start(plugin, options, (host, port), local_addr, false)
+    }
+
+    fn start(plugin: &str, options: Option<&str>, remote: (&str, u16), local_addr: SocketAddr, client: bool) -> io::Result<Self> {
+        let mut command = Command::new(plugin);
+        command
+            .env("SS_REMOTE_HOST", remote.0)
+            .env("SS_REMOTE_PORT", remote.1.to_string())
+            .env("SS_LOCAL_HOST", local_addr.ip().to_string())
+            .env("SS_LOCAL_PORT", local_addr.port().to_string())
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        if let Some(options) = options {
+            command.env("SS_PLUGIN_OPTIONS", options);
+        }
+        info!("Start plugin {} on {}", plugin, local_addr);
+        let child = command.spawn().map_err(|e| Error::new(e.kind(), format!("Plugin {} start error. {}", plugin, e)))?;
+        let local_addr = Arc::new(Mutex::new(local_addr));
+        let renew_addr = if client { Some(local_addr.clone()) } else { None };
+        Ok(Self {
+            local_addr,
+            supervisor: tokio::task::spawn(supervise(plugin.to_string(), command, child, renew_addr)),
+        })
+    }
+
+    /// The address where the plugin connects to or listens on for touch-rs.
+    /// The address of a client plugin changes when it is restarted.
+    pub fn local_addr(&self) -> SocketAddr {
+        *self.local_addr.lock().unwrap()
+    }
+}
+
+/// A local address with a port which is free now.
+fn free_local_addr() -> io::Result<SocketAddr> {
+    std::net::TcpListener::bind((LOCAL_HOST, 0))?.local_addr()
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        // The child is killed when the supervisor drops it.
+        self.supervisor.abort();
+    }
+}
+
+/// Wait for the running plugin and restart it when it exits, with a growing delay.
+/// With `renew_addr`, a restarted plugin listens on a new free port, as the old one may be taken.
+async fn supervise(plugin: String, mut command: Command, mut child: Child, renew_addr: Option<Arc<Mutex<SocketAddr>>>) {
+    let mut delay = RESTART_DELAY;
+    let mut started = Instant::now();
+    loop {
+        match child.wait().await {
+            Ok(status) => warn!("Plugin {} exited with {}, restart it", plugin, status),
+            Err(e) => error!("Plugin {} wait error. {}", plugin, e),
+        }
+        if started.elapsed() >= MAX_RESTART_DELAY {
+            delay = RESTART_DELAY;
+        }
+        child = loop {
+            sleep(delay).await;
+            delay = (delay * 2).min(MAX_RESTART_DELAY);
+            if let Some(local_addr) = &renew_addr {
+                match free_local_addr() {
+                    Ok(addr) => {
+                        info!("Restart plugin {} on {}", plugin, addr);
+                        command.env("SS_LOCAL_PORT", addr.port().to_string());
+                        *local_addr.lock().unwrap() = addr;
+                    }
+                    Err(e) => error!("Plugin {} local port error. {}", plugin, e),
+                }
+            }
+            match command.spawn() {
+                Ok(child) => break child,
+                Err(e) => error!("Plugin {} start error. {}", plugin, e),
+            }
+        };
+        started = Instant::now();
+    }
+}
+
+/// Bind the listener of a Shadowsocks input. With a plugin, the plugin listens on the configured address,
+/// and the input listens on a local port for it.
+pub async fn bind_ss_listener(passive: &BasePassiveConfig) -> io::Result<(Listener, Option<Plugin>)> {
+    let plugin = match &passive.plugin {
+        Some(plugin) => plugin,
+        None => {
+            let listener = Listener::bind(passive.local_host.as_slice(), passive.local_port, passive.listen.as_ref()).await?;
+            return Ok((listener, None));
+        }
+    };
+    let host = match passive.local_host.as_slice() {
+        [host] => host,
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "A plugin only listens on one local_host")),
+    };
+    let listener = Listener::bind(&[LOCAL_HOST.to_string()], 0, passive.listen.as_ref()).await?;
+    let plugin = Plugin::start_server(
+        plugin,
+        passive.plugin_opts.as_deref(),
+        host,
+        passive.local_port,
+        listener.local_addrs()[0],
+    )?;
+    Ok((listener, Some(plugin)))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::io::ErrorKind;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    use crate::net::plugin::Plugin;
+
+    #[tokio::test]
+    async fn restart_and_kill() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let script = dir.join(format!("touch-rs-plugin-{}.sh", id));
+        let output = dir.join(format!("touch-rs-plugin-{}.out", id));
+        // Record the environment, then exit at the first start and keep running at the second one.
+        let content = format!(
+            "#!/bin/sh\necho \"$$ $SS_REMOTE_HOST:$SS_REMOTE_PORT $SS_LOCAL_HOST:$SS_LOCAL_PORT $SS_PLUGIN_OPTIONS\" >> {0}\n\
+             [ $(wc -l < {0}) -ge 2 ] && exec sleep 60\n",
+            output.display()
+        );
+        std::fs::write(&script, content).unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let plugin = Plugin::start_client(script.to_str().unwrap(), Some("obfs=http"), "example.com", 8388).unwrap();
+        let first_local = plugin.local_addr();
+        let mut lines = vec![];
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            lines = std::fs::read_to_string(&output).unwrap_or_default().lines().map(str::to_string).collect();
+            if lines.len() >= 2 {
+                break;
+            }
+        }
+        assert_eq!(lines.len(), 2);
+        // The restarted plugin listens on a new port, the old one may have been taken.
+        for (line, local) in lines.iter().zip([first_local, plugin.local_addr()]) {
+            assert!(line.ends_with(&format!(" example.com:8388 127.0.0.1:{} obfs=http", local.port())));
+        }
+        assert_ne!(first_local, plugin.local_addr());
+
+        let pid = lines[1].split(' ').next().unwrap().to_string();
+        drop(plugin);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        // The killed plugin is gone or a zombie.
+        let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+        assert!(status.is_empty() || status.contains("zombie"), "{}", status);
+        let _ = std::fs::remove_file(&script);
+        let _ = std::fs::remove_file(&output);
+    }
+
+    #[tokio::test]
+    async fn missing_plugin() {
+        let err = Plugin::start_client("/nonexistent/touch-rs-plugin", None, "example.com", 8388).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
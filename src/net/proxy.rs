@@ -1,7 +1,11 @@
 use std::io;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
 
 use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+use crate::net::stream::{split, BoxStream};
 use crate::net::AddressType;
 
 #[async_trait]
@@ -19,6 +23,26 @@ pub trait OutputProxy: Send {
 pub trait OutProxyStarter: Send {
     /// Creat a new OUT_PROXY connection.
     async fn new_connection(&mut self, proxy_info: ProxyInfo) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>)>;
+
+    /// Creat a UDP association, every packet has its own destination.
+    async fn new_udp(&mut self) -> io::Result<(Box<dyn UdpProxyReader>, Box<dyn UdpProxyWriter>)> {
+        Err(Error::new(ErrorKind::Unsupported, "The output proxy doesn't support UDP"))
+    }
+
+    /// Listen for a connection from the destination, such as the data connection of active FTP.
+    async fn bind(&mut self, _proxy_info: ProxyInfo) -> io::Result<Box<dyn ProxyBinder>> {
+        Err(Error::new(ErrorKind::Unsupported, "The output proxy doesn't support BIND"))
+    }
+}
+
+/// A listener waiting for one inbound connection.
+#[async_trait]
+pub trait ProxyBinder: Send {
+    /// The address which the destination connects to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Wait for the connection, return it with the address it comes from.
+    async fn accept(&mut self) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>, SocketAddr)>;
 }
 
 #[async_trait]
@@ -35,9 +59,60 @@ pub trait ProxyWriter: Send {
     async fn shutdown(&mut self) -> io::Result<()>;
 }
 
+#[async_trait]
+pub trait UdpProxyReader: Send {
+    /// Read a packet and the address it comes from.
+    async fn read_from(&mut self) -> io::Result<(&mut [u8], ProxyInfo)>;
+}
+
+#[async_trait]
+pub trait UdpProxyWriter: Send {
+    async fn write_to(&mut self, data: &[u8], target: &ProxyInfo) -> io::Result<()>;
+}
+
+/// Relay between an accepted stream and an output connection, until one direction is done.
+pub async fn relay(stream: BoxStream, out_reader: &mut dyn ProxyReader, out_writer: &mut dyn ProxyWriter) {
+    let (mut read_half, mut write_half) = split(stream);
+    let upload = async {
+        let mut buf = vec![0u8; 16 * 1024];
+        while let Ok(size) = read_half.read(&mut buf).await {
+            if size == 0 || out_writer.write(&mut buf[..size]).await.is_err() {
+                break;
+            }
+        }
+        let _result = out_writer.shutdown().await;
+    };
+    let download = async {
+        while let Ok(data) = out_reader.read().await {
+            if data.is_empty() || write_half.write_all(data).await.is_err() {
+                break;
+            }
+        }
+        let _result = write_half.shutdown().await;
+    };
+    tokio::select! {
+        _ = upload => {}
+        _ = download => {}
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ProxyInfo {
     pub address_type: AddressType,
     pub address: Vec<u8>,
     pub port: u16,
 }
+
+impl From<SocketAddr> for ProxyInfo {
+    fn from(addr: SocketAddr) -> Self {
+        let (address_type, address) = match addr.ip() {
+            IpAddr::V4(ip) => (AddressType::IPv4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (AddressType::IPv6, ip.octets().to_vec()),
+        };
+        Self {
+            address_type,
+            address,
+            port: addr.port(),
+        }
+    }
+}
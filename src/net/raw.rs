@@ -1,63 +1,176 @@
-use std::borrow::Borrow;
 use std::io;
-use std::io::Error;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, ErrorKind};
+use log::warn;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::TcpStream;
+use tokio::net::{TcpListener, UdpSocket};
 
-use crate::net::dns::DnsClient;
-use crate::net::proxy::{OutProxyStarter, OutputProxy, ProxyInfo, ProxyReader, ProxyWriter};
+use crate::net::dialer::Dialer;
+use crate::net::dns::DnsResolver;
+use crate::net::proxy::{
+    OutProxyStarter, OutputProxy, ProxyBinder, ProxyInfo, ProxyReader, ProxyWriter, UdpProxyReader, UdpProxyWriter,
+};
 use crate::net::AddressType;
 use crate::util::address::Address;
 
 pub struct RawActive {
-    dns: Arc<Option<DnsClient>>,
+    /// Use the global resolver if it is `None`.
+    dns: Option<Arc<DnsResolver>>,
+    dialer: Dialer,
 }
 
 /// Send raw data to dest server
 impl RawActive {
     /// Init raw active.
-    pub fn new(dns_config: Option<String>) -> io::Result<Self> {
-        let dns = dns_config.and_then(|dns_str| DnsClient::new(dns_str).ok());
-        Ok(Self { dns: Arc::new(dns) })
+    pub fn new(dns_config: Option<Vec<String>>, dialer: Dialer) -> io::Result<Self> {
+        if dialer.has_via() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "The raw output doesn't use `via`, use the http output for the HTTP proxy",
+            ));
+        }
+        let dns = dns_config.map(DnsResolver::with_servers).transpose()?;
+        Ok(Self {
+            dns: dns.map(Arc::new),
+            dialer,
+        })
     }
 }
 
 impl OutputProxy for RawActive {
     fn gen_connector(&mut self) -> io::Result<Box<dyn OutProxyStarter>> {
-        Ok(Box::new(RawOutProxyStarter { dns: self.dns.clone() }))
+        Ok(Box::new(RawOutProxyStarter {
+            dns: self.dns.clone(),
+            dialer: self.dialer.clone(),
+        }))
     }
 }
 
 pub struct RawOutProxyStarter {
-    dns: Arc<Option<DnsClient>>,
+    dns: Option<Arc<DnsResolver>>,
+    dialer: Dialer,
 }
 
 #[async_trait]
 impl OutProxyStarter for RawOutProxyStarter {
     async fn new_connection(&mut self, proxy_info: ProxyInfo) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>)> {
-        let tcp_stream = if proxy_info.address_type == AddressType::Domain {
-            let domain_str = String::from_utf8_lossy(&proxy_info.address);
-            if let Some(client) = self.dns.borrow() {
-                // Query domain IP address
-                let ip_addr =
-                    client.query(&proxy_info.address).await.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "unknown host"))?;
-                TcpStream::connect((ip_addr, proxy_info.port)).await?
-            } else {
-                let address = format!("{}:{}", domain_str, proxy_info.port);
-                TcpStream::connect(&address).await?
-            }
+        let tcp_stream = if let (AddressType::Domain, Some(dns)) = (proxy_info.address_type, &self.dns) {
+            let domain = String::from_utf8_lossy(&proxy_info.address);
+            self.dialer.connect_host(dns, &domain, proxy_info.port).await?
         } else {
-            Address::new_connect(&proxy_info.address, proxy_info.port, &proxy_info.address_type).await?
+            Address::new_connect(&self.dialer, &proxy_info.address, proxy_info.port, &proxy_info.address_type).await?
         };
         let (read_half, write_half) = tcp_stream.into_split();
         let writer = RawProxyWriter::new(write_half);
         let reader = RawProxyReader::new(read_half);
         Ok((Box::new(reader), Box::new(writer)))
     }
+
+    async fn new_udp(&mut self) -> io::Result<(Box<dyn UdpProxyReader>, Box<dyn UdpProxyWriter>)> {
+        let socket = Arc::new(self.dialer.bind_udp()?);
+        let reader = RawUdpReader {
+            socket: socket.clone(),
+            buf: vec![0u8; 64 * 1024],
+        };
+        let writer = RawUdpWriter {
+            socket,
+            dns: self.dns.clone(),
+        };
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+
+    /// Listen on the local address of the route to the destination, so it can connect back.
+    async fn bind(&mut self, proxy_info: ProxyInfo) -> io::Result<Box<dyn ProxyBinder>> {
+        let ips = resolve(self.dns.as_deref(), &proxy_info).await?;
+        let ip = *ips.first().ok_or_else(|| Error::new(ErrorKind::NotFound, "No address to bind for"))?;
+        let local_ip = self.dialer.local_ip_to(SocketAddr::new(ip, proxy_info.port))?;
+        Ok(Box::new(RawBinder {
+            listener: TcpListener::bind(SocketAddr::new(local_ip, 0)).await?,
+            peers: ips.into_iter().map(|ip| ip.to_canonical()).collect(),
+        }))
+    }
+}
+
+struct RawBinder {
+    listener: TcpListener,
+    /// Only the destination of the request may connect, any host if it is unspecified.
+    peers: Vec<IpAddr>,
+}
+
+#[async_trait]
+impl ProxyBinder for RawBinder {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    async fn accept(&mut self) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>, SocketAddr)> {
+        let (tcp_stream, addr) = loop {
+            let (tcp_stream, addr) = self.listener.accept().await?;
+            let ip = addr.ip().to_canonical();
+            if self.peers.iter().any(|peer| peer.is_unspecified() || *peer == ip) {
+                break (tcp_stream, addr);
+            }
+            warn!("Drop the BIND connection from {}, which isn't the destination", addr);
+        };
+        let (read_half, write_half) = tcp_stream.into_split();
+        Ok((
+            Box::new(RawProxyReader::new(read_half)),
+            Box::new(RawProxyWriter::new(write_half)),
+            addr,
+        ))
+    }
+}
+
+/// The IPs of the destination, a domain is resolved with the resolver or the global one.
+async fn resolve(dns: Option<&DnsResolver>, target: &ProxyInfo) -> io::Result<Vec<IpAddr>> {
+    Ok(match target.address_type {
+        AddressType::Domain => {
+            let dns = dns.unwrap_or_else(|| DnsResolver::global());
+            dns.lookup(&String::from_utf8_lossy(&target.address)).await?
+        }
+        AddressType::IPv4 => <[u8; 4]>::try_from(target.address.as_slice()).map(IpAddr::from).into_iter().collect(),
+        AddressType::IPv6 => <[u8; 16]>::try_from(target.address.as_slice()).map(IpAddr::from).into_iter().collect(),
+    })
+}
+
+pub struct RawUdpReader {
+    socket: Arc<UdpSocket>,
+    buf: Vec<u8>,
+}
+
+#[async_trait]
+impl UdpProxyReader for RawUdpReader {
+    async fn read_from(&mut self) -> io::Result<(&mut [u8], ProxyInfo)> {
+        let (size, addr) = self.socket.recv_from(&mut self.buf).await?;
+        // IPv4 sources of a dual-stack socket are mapped to IPv6.
+        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        Ok((&mut self.buf[..size], ProxyInfo::from(addr)))
+    }
+}
+
+pub struct RawUdpWriter {
+    socket: Arc<UdpSocket>,
+    /// Use the global resolver if it is `None`.
+    dns: Option<Arc<DnsResolver>>,
+}
+
+#[async_trait]
+impl UdpProxyWriter for RawUdpWriter {
+    async fn write_to(&mut self, data: &[u8], target: &ProxyInfo) -> io::Result<()> {
+        let dual_stack = self.socket.local_addr()?.is_ipv6();
+        let ips = resolve(self.dns.as_deref(), target).await?;
+        let ip = match ips.into_iter().find(|ip| dual_stack || ip.is_ipv4()) {
+            Some(IpAddr::V4(ip)) if dual_stack => IpAddr::V6(ip.to_ipv6_mapped()),
+            Some(ip) => ip,
+            None => return Err(Error::new(ErrorKind::AddrNotAvailable, "No address to send the UDP packet")),
+        };
+        self.socket.send_to(data, SocketAddr::new(ip, target.port)).await?;
+        Ok(())
+    }
 }
 
 pub struct RawProxyReader {
@@ -106,3 +219,33 @@ impl ProxyWriter for RawProxyWriter {
         self.write_half.shutdown().await
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpSocket, TcpStream};
+
+    use crate::net::dialer::Dialer;
+    use crate::net::proxy::{OutputProxy, ProxyInfo};
+    use crate::net::raw::RawActive;
+
+    #[tokio::test]
+    async fn bind_accepts_the_destination() {
+        let mut starter = RawActive::new(None, Dialer::default()).unwrap().gen_connector().unwrap();
+        let destination = SocketAddr::from(([127, 0, 0, 2], 21));
+        let mut binder = starter.bind(ProxyInfo::from(destination)).await.unwrap();
+        let bound = binder.local_addr().unwrap();
+
+        // Another host connects first and is dropped.
+        let mut other = TcpStream::connect(bound).await.unwrap();
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::from(([127, 0, 0, 2], 0))).unwrap();
+        let stream = socket.connect(bound).await.unwrap();
+        let (_reader, _writer, peer) = binder.accept().await.unwrap();
+        assert_eq!(peer, stream.local_addr().unwrap());
+        let mut buf = [0u8; 1];
+        assert_eq!(other.read(&mut buf).await.unwrap(), 0);
+    }
+}
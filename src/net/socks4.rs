@@ -0,0 +1,82 @@
+use std::io;
+
+use async_trait::async_trait;
+use log::{error, info};
+use tokio::io::AsyncReadExt;
+
+use crate::core::profile::BasePassiveConfig;
+use crate::net::listener::Listener;
+use crate::net::proxy::{relay, InputProxy, OutProxyStarter, OutputProxy};
+use crate::net::stream::BoxStream;
+use crate::net::transport::ServerTransport;
+use crate::socks::consts::SocksVersion;
+use crate::socks::socks4::Socks4Server;
+
+/// The SOCKS4 and SOCKS4a input. The socks5 input accepts them too.
+pub struct Socks4Passive {
+    tcp_listener: Listener,
+    transport: ServerTransport,
+    out_proxy: Box<dyn OutputProxy + Send>,
+}
+
+impl Socks4Passive {
+    pub async fn new(passive: &BasePassiveConfig, out_proxy: Box<dyn OutputProxy + Send>) -> io::Result<Self> {
+        let tcp_listener = Listener::bind(passive.local_host.as_slice(), passive.local_port, passive.listen.as_ref()).await?;
+        info!("Socks4 bind in {:?}", tcp_listener.local_addrs());
+        Ok(Self {
+            tcp_listener,
+            transport: ServerTransport::new(passive.transport.as_ref())?,
+            out_proxy,
+        })
+    }
+}
+
+#[async_trait]
+impl InputProxy for Socks4Passive {
+    async fn start(&mut self) -> io::Result<()> {
+        info!("Socks4 start listen");
+        loop {
+            let (tcp_stream, _addr) = self.tcp_listener.accept().await?;
+            let starter = match self.out_proxy.gen_connector() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            let transport = self.transport.clone();
+            tokio::task::spawn(async move {
+                let result = match transport.accept(tcp_stream).await {
+                    Ok(stream) => new_proxy(stream, starter).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    error!("Socks4 proxy error. {}", e)
+                };
+            });
+        }
+    }
+}
+
+async fn new_proxy(mut stream: BoxStream, starter: Box<dyn OutProxyStarter>) -> io::Result<()> {
+    match SocksVersion::with_byte(stream.read_u8().await?)? {
+        SocksVersion::V4 => socks4_proxy(stream, starter).await,
+        version => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            format!("The socks4 input doesn't accept {:?}", version),
+        )),
+    }
+}
+
+/// Serve a SOCKS4 request after its version byte. The reply is sent after the outbound connection is open.
+pub async fn socks4_proxy(mut stream: BoxStream, mut starter: Box<dyn OutProxyStarter>) -> io::Result<()> {
+    let mut socks4 = Socks4Server::new(&mut stream);
+    let info = socks4.read_request().await?;
+    let (mut out_reader, mut out_writer) = match starter.new_connection(info).await {
+        Ok(n) => n,
+        Err(e) => {
+            socks4.write_reply(false).await?;
+            return Err(e);
+        }
+    };
+    socks4.write_reply(true).await?;
+    relay(stream, &mut *out_reader, &mut *out_writer).await;
+    Ok(())
+}
@@ -1,34 +1,44 @@
 use std::io;
 use std::io::{Error, ErrorKind};
-use std::net::{IpAddr, SocketAddr};
-use std::str::FromStr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use log::{error, info};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::time::timeout;
 
 use crate::core::profile::{BaseActiveConfig, BasePassiveConfig};
-use crate::net::proxy::{InputProxy, OutProxyStarter, OutputProxy, ProxyInfo, ProxyReader, ProxyWriter};
+use crate::net::dialer::Dialer;
+use crate::net::listener::Listener;
+use crate::net::proxy::{relay, InputProxy, OutProxyStarter, OutputProxy, ProxyInfo, ProxyReader, ProxyWriter};
+use crate::net::socks4::socks4_proxy;
+use crate::net::stream::{split, BoxStream, StreamReadHalf, StreamWriteHalf};
+use crate::net::transport::{ClientTransport, ServerTransport};
+use crate::socks::consts::{
+    Command, SocksVersion, REPLY_COMMAND_NOT_SUPPORTED, REPLY_CONNECTION_REFUSED, REPLY_GENERAL_FAILURE, REPLY_HOST_UNREACHABLE,
+    REPLY_NETWORK_UNREACHABLE, REPLY_NOT_ALLOWED, REPLY_SUCCEEDED, REPLY_TTL_EXPIRED,
+};
 use crate::socks::socks5_connector::{Sock5ClientConnector, Socks5Server};
 
+/// How long BIND waits for the inbound connection.
+const BIND_TIMEOUT: Duration = Duration::from_secs(120);
+const UNSPECIFIED_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
 pub struct Socks5Passive {
-    tcp_listener: TcpListener,
-    password: Option<String>,
+    tcp_listener: Listener,
+    transport: ServerTransport,
     out_proxy: Box<dyn OutputProxy + Send>,
 }
 
 impl Socks5Passive {
     /// Init Socks5 Passive. And try to bind host and port
     pub async fn new(passive: &BasePassiveConfig, out_proxy: Box<dyn OutputProxy + Send>) -> io::Result<Self> {
-        let addr_str = format!("{}:{}", &passive.local_host, passive.local_port);
-        let addr = SocketAddr::from_str(addr_str.as_str()).map_err(|_| Error::new(ErrorKind::InvalidInput, "Error address"));
-        let tcp_listener = TcpListener::bind(addr?).await?;
-        info!("Socks5 bind in {}", addr_str);
+        let tcp_listener = Listener::bind(passive.local_host.as_slice(), passive.local_port, passive.listen.as_ref()).await?;
+        info!("Socks5 bind in {:?}", tcp_listener.local_addrs());
         Ok(Self {
             tcp_listener,
-            password: passive.password.clone(),
+            transport: ServerTransport::new(passive.transport.as_ref())?,
             out_proxy,
         })
     }
@@ -45,8 +55,13 @@ impl InputProxy for Socks5Passive {
                 Ok(n) => n,
                 Err(_) => continue,
             };
+            let transport = self.transport.clone();
             tokio::task::spawn(async move {
-                if let Err(e) = new_proxy(tcp_stream, starter).await {
+                let result = match transport.accept(tcp_stream).await {
+                    Ok(stream) => new_proxy(stream, starter).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
                     error!("Socks5 proxy error. {}", e)
                 };
             });
@@ -54,85 +69,130 @@ impl InputProxy for Socks5Passive {
     }
 }
 
-async fn new_proxy(mut input_stream: TcpStream, mut starter: Box<dyn OutProxyStarter>) -> io::Result<()> {
-    let mut connector = Socks5Server::new(&mut input_stream);
-    let info = connector.accept_check().await?;
-
-    let (mut out_reader, mut out_writer) = starter.new_connection(info).await?;
+/// SOCKS4 and SOCKS4a are detected by the version byte.
+async fn new_proxy(mut input_stream: BoxStream, starter: Box<dyn OutProxyStarter>) -> io::Result<()> {
+    match SocksVersion::with_byte(input_stream.read_u8().await?)? {
+        SocksVersion::V4 => socks4_proxy(input_stream, starter).await,
+        SocksVersion::V5 => socks5_proxy(input_stream, starter).await,
+    }
+}
 
-    let (read_half, write_half) = input_stream.into_split();
-    let _reader = write(write_half, &mut out_reader);
-    let _writer = read(read_half, &mut out_writer);
-    // Wait for two future done.
-    // TODO Don't know TCP will be dropped.
-    // let _sd_rs = input_stream.shutdown().await;
+async fn socks5_proxy(mut input_stream: BoxStream, mut starter: Box<dyn OutProxyStarter>) -> io::Result<()> {
+    let mut connector = Socks5Server::new(&mut input_stream);
+    let (command, info) = connector.accept_check().await?;
+    match command {
+        Command::Connect => {
+            let (mut out_reader, mut out_writer) = match starter.new_connection(info).await {
+                Ok(n) => n,
+                Err(e) => {
+                    connector.write_reply(reply_code(&e), UNSPECIFIED_ADDR).await?;
+                    return Err(e);
+                }
+            };
+            connector.write_reply(REPLY_SUCCEEDED, UNSPECIFIED_ADDR).await?;
+            relay(input_stream, &mut *out_reader, &mut *out_writer).await;
+        }
+        Command::Bind => {
+            let (mut out_reader, mut out_writer) = socks5_bind(&mut connector, starter, info).await?;
+            relay(input_stream, &mut *out_reader, &mut *out_writer).await;
+        }
+        Command::UdpAssociate => {
+            connector.write_reply(REPLY_COMMAND_NOT_SUPPORTED, UNSPECIFIED_ADDR).await?;
+            return Err(Error::new(ErrorKind::Unsupported, "Socks5 UDP ASSOCIATE is unsupported"));
+        }
+    }
     Ok(())
 }
 
-async fn read(mut input_read: OwnedReadHalf, out_writer: &mut Box<dyn ProxyWriter>) -> usize {
-    let mut buf = [0u8; 1024];
-    let mut total = 0;
-    while let Ok(size) = input_read.read(&mut buf).await {
-        if size == 0 {
-            break;
+/// The first reply carries the listening address, the second one the address of the inbound connection.
+async fn socks5_bind(
+    connector: &mut Socks5Server<'_>,
+    mut starter: Box<dyn OutProxyStarter>,
+    info: ProxyInfo,
+) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>)> {
+    let mut binder = match starter.bind(info).await {
+        Ok(binder) => binder,
+        Err(e) => {
+            connector.write_reply(reply_code(&e), UNSPECIFIED_ADDR).await?;
+            return Err(e);
+        }
+    };
+    connector.write_reply(REPLY_SUCCEEDED, binder.local_addr()?).await?;
+    let accepted = match timeout(BIND_TIMEOUT, binder.accept()).await {
+        Ok(accepted) => accepted,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "Socks5 BIND timed out")),
+    };
+    match accepted {
+        Ok((reader, writer, addr)) => {
+            connector.write_reply(REPLY_SUCCEEDED, addr).await?;
+            Ok((reader, writer))
         }
-        total += size;
-        if out_writer.write(&mut buf[..size]).await.is_err() {
-            break;
+        Err(e) => {
+            connector.write_reply(reply_code(&e), UNSPECIFIED_ADDR).await?;
+            Err(e)
         }
     }
-    total
 }
 
-async fn write(mut input_write: OwnedWriteHalf, out_reader: &mut Box<dyn ProxyReader>) -> usize {
-    let mut total = 0;
-    while let Ok(data) = out_reader.read().await {
-        if data.is_empty() {
-            break;
-        }
-        total += data.len();
-        if input_write.write_all(data.as_ref()).await.is_err() {
-            break;
-        };
+/// The REP for an error of the output. A failed DNS lookup is `NotFound`, so the host is unreachable.
+fn reply_code(e: &Error) -> u8 {
+    match e.kind() {
+        ErrorKind::PermissionDenied => REPLY_NOT_ALLOWED,
+        ErrorKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+        ErrorKind::HostUnreachable | ErrorKind::NotFound | ErrorKind::AddrNotAvailable => REPLY_HOST_UNREACHABLE,
+        ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        ErrorKind::TimedOut => REPLY_TTL_EXPIRED,
+        ErrorKind::Unsupported => REPLY_COMMAND_NOT_SUPPORTED,
+        _ => REPLY_GENERAL_FAILURE,
     }
-    let _result = input_write.shutdown().await;
-    total
 }
 
 //----------------------Socks5Active--------------------
 
 pub struct Socks5Active {
-    socket_addr: SocketAddr,
+    host: String,
+    port: u16,
+    dialer: Dialer,
+    transport: ClientTransport,
 }
 
 impl Socks5Active {
-    pub fn new(active: &BaseActiveConfig) -> io::Result<Self> {
-        let ip_addr = IpAddr::from_str(&active.remote_host).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
-        let socket_addr = SocketAddr::new(ip_addr, active.remote_port);
-        Ok(Self { socket_addr })
+    pub fn new(active: &BaseActiveConfig, dialer: Dialer) -> io::Result<Self> {
+        Ok(Self {
+            host: active.remote_host.clone(),
+            port: active.remote_port,
+            dialer,
+            transport: ClientTransport::new(active.transport.as_ref(), &active.remote_host, active.remote_port)?,
+        })
     }
 }
 
 impl OutputProxy for Socks5Active {
     fn gen_connector(&mut self) -> io::Result<Box<dyn OutProxyStarter>> {
         let starter = Socks5OutProxyStarter {
-            socket_addr: self.socket_addr,
+            host: self.host.clone(),
+            port: self.port,
+            dialer: self.dialer.clone(),
+            transport: self.transport.clone(),
         };
         Ok(Box::new(starter))
     }
 }
 
 struct Socks5OutProxyStarter {
-    socket_addr: SocketAddr,
+    host: String,
+    port: u16,
+    dialer: Dialer,
+    transport: ClientTransport,
 }
 
 #[async_trait]
 impl OutProxyStarter for Socks5OutProxyStarter {
     async fn new_connection(&mut self, proxy_info: ProxyInfo) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>)> {
-        let mut tcp_stream = TcpStream::connect(self.socket_addr).await?;
+        let mut tcp_stream = self.transport.connect(self.dialer.dial(&self.host, self.port).await?).await?;
         let mut connector = Sock5ClientConnector::new(&mut tcp_stream);
         connector.try_connect(&proxy_info).await?;
-        let (half_reader, half_writer) = tcp_stream.into_split();
+        let (half_reader, half_writer) = split(tcp_stream);
         let reader = Socks5Redaer::new(half_reader);
         let writer = Socks5Writer::new(half_writer);
         Ok((Box::new(reader), Box::new(writer)))
@@ -142,12 +202,12 @@ impl OutProxyStarter for Socks5OutProxyStarter {
 //--------------------------SOCKS5_READER_AND_WRITER-----------------------
 
 struct Socks5Redaer {
-    read_half: OwnedReadHalf,
+    read_half: StreamReadHalf,
     buffer: Vec<u8>,
 }
 
 impl Socks5Redaer {
-    pub fn new(read_half: OwnedReadHalf) -> Self {
+    pub fn new(read_half: StreamReadHalf) -> Self {
         Self {
             read_half,
             buffer: vec![0u8; 32 * 1024],
@@ -171,11 +231,11 @@ impl ProxyReader for Socks5Redaer {
 }
 
 struct Socks5Writer {
-    write_half: OwnedWriteHalf,
+    write_half: StreamWriteHalf,
 }
 
 impl Socks5Writer {
-    pub fn new(write_half: OwnedWriteHalf) -> Self {
+    pub fn new(write_half: StreamWriteHalf) -> Self {
         Self { write_half }
     }
 }
@@ -190,3 +250,91 @@ impl ProxyWriter for Socks5Writer {
         self.write_half.shutdown().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::net::dialer::Dialer;
+    use crate::net::proxy::OutputProxy;
+    use crate::net::raw::RawActive;
+    use crate::net::socks5::new_proxy;
+    use crate::net::stream::BoxStream;
+
+    #[tokio::test]
+    async fn bind_with_raw() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let starter = RawActive::new(None, Dialer::default()).unwrap().gen_connector().unwrap();
+        let server: BoxStream = Box::new(server);
+        let proxy = tokio::spawn(new_proxy(server, starter));
+
+        // BIND for a connection from 127.0.0.1:21.
+        client.write_all(&[5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1, 0, 21]).await.unwrap();
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..6], [5, 0, 5, 0, 0, 1]);
+        let bound = SocketAddr::from((
+            [reply[6], reply[7], reply[8], reply[9]],
+            u16::from_be_bytes([reply[10], reply[11]]),
+        ));
+        assert_eq!(bound.ip().to_string(), "127.0.0.1");
+
+        let mut inbound = TcpStream::connect(bound).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..8], [5, 0, 0, 1, 127, 0, 0, 1]);
+        assert_eq!(u16::from_be_bytes([reply[8], reply[9]]), inbound.local_addr().unwrap().port());
+
+        inbound.write_all(b"220 ready").await.unwrap();
+        let mut data = [0u8; 9];
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"220 ready");
+        client.write_all(b"PORT").await.unwrap();
+        let mut data = [0u8; 4];
+        inbound.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"PORT");
+
+        drop(inbound);
+        drop(client);
+        proxy.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn unsupported_commands() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let starter = RawActive::new(None, Dialer::default()).unwrap().gen_connector().unwrap();
+        let server: BoxStream = Box::new(server);
+        let proxy = tokio::spawn(new_proxy(server, starter));
+
+        // UDP ASSOCIATE isn't served by the socks5 input.
+        client.write_all(&[5, 1, 0, 5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 0, 5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(proxy.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_replies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open_port = listener.local_addr().unwrap().port();
+        let closed_port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+
+        for (port, rep) in [(closed_port, 5), (open_port, 0)] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let starter = RawActive::new(None, Dialer::default()).unwrap().gen_connector().unwrap();
+            let server: BoxStream = Box::new(server);
+            let proxy = tokio::spawn(new_proxy(server, starter));
+            let [high, low] = port.to_be_bytes();
+            client.write_all(&[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, high, low]).await.unwrap();
+            let mut reply = [0u8; 12];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [5, 0, 5, rep, 0, 1, 0, 0, 0, 0, 0, 0]);
+            drop(client);
+            assert_eq!(proxy.await.unwrap().is_ok(), rep == 0);
+        }
+    }
+}
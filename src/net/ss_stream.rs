@@ -1,46 +1,48 @@
-use std::borrow::Borrow;
 use std::io;
-use std::io::{Error, ErrorKind};
-use std::net::SocketAddr;
-use std::str::FromStr;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::sync::Arc;
 
 use async_trait::async_trait;
 use log::{debug, error, info};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::{TcpListener, TcpStream};
 
 use crate::core::profile::BasePassiveConfig;
-use crate::encrypt::aead::AeadType;
+use crate::encrypt::aead::{AeadType, AEAD_TAG_SIZE};
 use crate::encrypt::error::EncryptError;
-use crate::encrypt::ss::ss_aead::SsAead;
+use crate::encrypt::ss::ss_aead::{SsAead, MAX_PAYLOAD_SIZE};
+use crate::net::dialer::Dialer;
+use crate::net::listener::Listener;
+use crate::net::plugin::{bind_ss_listener, Plugin};
 use crate::net::proxy::{InputProxy, OutProxyStarter, OutputProxy, ProxyInfo, ProxyReader, ProxyWriter};
-use crate::socks::socks5::Socks5;
+use crate::net::stream::{split, BoxStream, StreamReadHalf, StreamWriteHalf};
+use crate::net::transport::{ClientTransport, ServerTransport};
+use crate::socks::socks5::{ParseError, Socks5};
+use crate::util::address::Address;
 
 pub struct SsStreamReader {
-    read_half: OwnedReadHalf,
+    read_half: StreamReadHalf,
     password: Vec<u8>,
     aead_type: AeadType,
     ss_aead: Option<SsAead>,
-    ss_len_buf: [u8; 18],
+    ss_len_buf: [u8; 2 + AEAD_TAG_SIZE],
     ss_data_buf: Vec<u8>,
 }
 
 impl SsStreamReader {
-    pub fn new(read_half: OwnedReadHalf, password: &str, aead_type: AeadType) -> Self {
+    pub fn new(read_half: StreamReadHalf, password: &str, aead_type: AeadType) -> Self {
         SsStreamReader {
             read_half,
             password: password.as_bytes().to_vec(),
             aead_type,
             ss_aead: None,
-            ss_len_buf: [0u8; 18],
-            ss_data_buf: vec![0u8; 1024 * 32],
+            ss_len_buf: [0u8; 2 + AEAD_TAG_SIZE],
+            ss_data_buf: vec![0u8; MAX_PAYLOAD_SIZE + AEAD_TAG_SIZE],
         }
     }
 }
 
 /// Shadowsocks TCP Reader.
-/// First, it will read a 16/32 bytes of salt.
+/// First, it will read a 16/24/32 bytes of salt.
 #[async_trait]
 impl ProxyReader for SsStreamReader {
     async fn read(&mut self) -> io::Result<&mut [u8]> {
@@ -54,11 +56,15 @@ impl ProxyReader for SsStreamReader {
         self.read_half.read_exact(&mut self.ss_len_buf).await?;
         let len_vec = decrypt(&mut self.ss_len_buf, aead)?;
         let en_data_len = u16::from_be_bytes([len_vec[0], len_vec[1]]) as usize;
-        // Automatic capacity expansion
-        if en_data_len > self.ss_data_buf.len() {
-            self.ss_data_buf = vec![0u8; en_data_len]
+        // A peer must never send a payload longer than the spec allows.
+        if en_data_len > MAX_PAYLOAD_SIZE {
+            let err = format!(
+                "Shadowsocks payload size {} exceeds the max size {}",
+                en_data_len, MAX_PAYLOAD_SIZE
+            );
+            return Err(Error::new(ErrorKind::InvalidData, err));
         }
-        let buf = self.ss_data_buf[..(en_data_len + 16) as usize].as_mut();
+        let buf = self.ss_data_buf[..en_data_len + AEAD_TAG_SIZE].as_mut();
         self.read_half.read_exact(buf).await?;
         decrypt(buf, aead)
     }
@@ -69,67 +75,90 @@ impl ProxyReader for SsStreamReader {
 }
 
 /// Read slat from TCP , and initialize a Shadowsocks AEAD.
-async fn read_slat_to_aead(aead_type: &AeadType, readhalf: &mut OwnedReadHalf, password: &[u8]) -> io::Result<SsAead> {
-    let mut salt = match aead_type {
-        AeadType::AES128GCM => vec![0u8; 16],
-        AeadType::AES256GCM | AeadType::Chacha20Poly1305 => vec![0u8; 32],
-    };
+async fn read_slat_to_aead(aead_type: &AeadType, readhalf: &mut StreamReadHalf, password: &[u8]) -> io::Result<SsAead> {
+    let mut salt = vec![0u8; aead_type.key_len()];
     readhalf.read_exact(&mut salt).await?;
     SsAead::new(salt, password, aead_type).map_err(change_error)
 }
 
 pub struct SsStreamWriter {
-    writehalf: OwnedWriteHalf,
+    writehalf: StreamWriteHalf,
     ss_aead: SsAead,
+    salt_sent: bool,
     proxy_info: Option<ProxyInfo>,
+    /// Encrypted length headers and payload tags of the chunks being written.
+    frame_buf: Vec<u8>,
 }
 
+/// Encrypted length with its tag, followed by the tag of the payload.
+const FRAME_SIZE: usize = 2 + AEAD_TAG_SIZE + AEAD_TAG_SIZE;
+
 impl SsStreamWriter {
     /// Create a pure Shadowsocks writer.
     /// It will only faithfully send the en_data you want to transmit,
     /// and will not automatically send the ss_header.
-    pub fn creat_without_info(writehalf: OwnedWriteHalf, ss_aead: SsAead) -> Self {
-        SsStreamWriter {
-            writehalf,
-            ss_aead,
-            proxy_info: None,
-        }
+    pub fn creat_without_info(writehalf: StreamWriteHalf, ss_aead: SsAead) -> Self {
+        Self::new(writehalf, ss_aead, None)
     }
 
     /// Creat a new [SsStreamWriter] with [ProxyInfo], and this writer will send
     /// a bytes of ss_header when you first write.
-    pub fn new_with_addr(writehalf: OwnedWriteHalf, ss_aead: SsAead, proxy_info: ProxyInfo) -> Self {
+    pub fn new_with_addr(writehalf: StreamWriteHalf, ss_aead: SsAead, proxy_info: ProxyInfo) -> Self {
+        Self::new(writehalf, ss_aead, Some(proxy_info))
+    }
+
+    fn new(writehalf: StreamWriteHalf, ss_aead: SsAead, proxy_info: Option<ProxyInfo>) -> Self {
         SsStreamWriter {
             writehalf,
             ss_aead,
-            proxy_info: Some(proxy_info),
+            salt_sent: false,
+            proxy_info,
+            frame_buf: vec![0u8; 4 * FRAME_SIZE],
         }
     }
 
-    pub async fn shutdown(&mut self) -> io::Result<()> {
-        self.writehalf.shutdown().await
-    }
-
-    async fn en_write(&mut self, raw_data: &mut [u8]) -> io::Result<()> {
+    /// Encrypt the payloads in place and send them with a single vectored write.
+    /// Payloads longer than [MAX_PAYLOAD_SIZE] are split into several chunks,
+    /// only the length headers and tags are stored in the frame buffer.
+    async fn en_write(&mut self, prefix: &[u8], payloads: &mut [&mut [u8]]) -> io::Result<()> {
+        let chunk_count: usize = payloads.iter().map(|payload| payload.len().div_ceil(MAX_PAYLOAD_SIZE)).sum();
+        if self.frame_buf.len() < chunk_count * FRAME_SIZE {
+            self.frame_buf = vec![0u8; chunk_count * FRAME_SIZE];
+        }
         let aead = &mut self.ss_aead;
-        let len = raw_data.len() as u16;
-        let len_en = encrypt(&mut len.to_be_bytes(), aead)?;
-        self.writehalf.write_all(len_en.as_ref()).await?;
-        let en_data = encrypt(raw_data, aead)?;
-        self.writehalf.write_all(en_data.as_ref()).await
+        let mut frames = self.frame_buf.chunks_mut(FRAME_SIZE);
+        let mut slices = Vec::with_capacity(1 + chunk_count * 3);
+        slices.push(IoSlice::new(prefix));
+        for payload in payloads.iter_mut() {
+            for chunk in payload.chunks_mut(MAX_PAYLOAD_SIZE) {
+                let frame = frames.next().unwrap();
+                let (len_part, payload_tag) = frame.split_at_mut(2 + AEAD_TAG_SIZE);
+                let (len, len_tag) = len_part.split_at_mut(2);
+                len.copy_from_slice(&(chunk.len() as u16).to_be_bytes());
+                aead.seal_in_place(len, len_tag).map_err(change_error)?;
+                aead.seal_in_place(chunk, payload_tag).map_err(change_error)?;
+                slices.push(IoSlice::new(len_part));
+                slices.push(IoSlice::new(chunk));
+                slices.push(IoSlice::new(payload_tag));
+            }
+        }
+        write_all_vectored(&mut self.writehalf, &mut slices).await
     }
 }
 
 #[async_trait]
 impl ProxyWriter for SsStreamWriter {
     async fn write(&mut self, raw_data: &mut [u8]) -> io::Result<()> {
-        if let Some(info) = &self.proxy_info {
-            self.writehalf.write_all(self.ss_aead.salt.borrow()).await?;
-            let mut addr_arr = Socks5::socks5_addr_arr(&info.address, info.port, &info.address_type);
-            self.en_write(&mut addr_arr).await?;
-            self.proxy_info = None;
+        // The salt and the address are sent together with the first data.
+        let salt = if self.salt_sent { vec![] } else { self.ss_aead.salt.clone() };
+        self.salt_sent = true;
+        match self.proxy_info.take() {
+            Some(info) => {
+                let mut addr_arr = Socks5::socks5_addr_arr(&info.address, info.port, &info.address_type)?;
+                self.en_write(&salt, &mut [&mut addr_arr, raw_data]).await
+            }
+            None => self.en_write(&salt, &mut [raw_data]).await,
         }
-        self.en_write(raw_data).await
     }
 
     async fn shutdown(&mut self) -> io::Result<()> {
@@ -137,7 +166,20 @@ impl ProxyWriter for SsStreamWriter {
     }
 }
 
-fn change_error(error: EncryptError) -> io::Error {
+/// Write all slices, retrying on partial writes.
+async fn write_all_vectored(writehalf: &mut StreamWriteHalf, mut slices: &mut [IoSlice<'_>]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        let size = writehalf.write_vectored(slices).await?;
+        if size == 0 {
+            return Err(Error::from(ErrorKind::WriteZero));
+        }
+        IoSlice::advance_slices(&mut slices, size);
+    }
+    Ok(())
+}
+
+pub(crate) fn change_error(error: EncryptError) -> io::Error {
     error!("Stream encrypt error: {}", error);
     io::Error::from(ErrorKind::InvalidInput)
 }
@@ -149,13 +191,6 @@ fn decrypt<'a>(de_data: &'a mut [u8], ss_aead: &'a mut SsAead) -> io::Result<&'a
     }
 }
 
-fn encrypt<'a>(raw_data: &mut [u8], ss_aead: &'a mut SsAead) -> io::Result<&'a mut [u8]> {
-    match ss_aead.ss_encrypt(raw_data) {
-        Ok(en_data) => Ok(en_data),
-        Err(e) => Err(change_error(e)),
-    }
-}
-
 //------------------------------SS_OUT_PROXY-----------------------------------------
 #[derive(Clone)]
 pub struct SsOutProxy {
@@ -163,17 +198,37 @@ pub struct SsOutProxy {
     ss_port: u16,
     password: String,
     aead_type: AeadType,
+    dialer: Dialer,
+    transport: ClientTransport,
+    /// The SIP003 plugin, connections go to its local address. It stops with the output.
+    plugin: Option<Arc<Plugin>>,
 }
 
 impl SsOutProxy {
-    pub fn new(ss_addr: String, ss_port: u16, password: String, aead_type: &AeadType) -> Self {
+    pub fn new(
+        ss_addr: String,
+        ss_port: u16,
+        password: String,
+        aead_type: &AeadType,
+        dialer: Dialer,
+        transport: ClientTransport,
+    ) -> Self {
         Self {
             ss_addr,
             ss_port,
             password,
             aead_type: (*aead_type),
+            dialer,
+            transport,
+            plugin: None,
         }
     }
+
+    /// Connect to the remote through the SIP003 plugin if there is one, it listens on a local port.
+    pub fn with_plugin(mut self, plugin: Option<Plugin>) -> Self {
+        self.plugin = plugin.map(Arc::new);
+        self
+    }
 }
 
 impl OutputProxy for SsOutProxy {
@@ -183,6 +238,9 @@ impl OutputProxy for SsOutProxy {
             ss_port: self.ss_port,
             password: self.password.clone(),
             aead_type: self.aead_type,
+            dialer: self.dialer.clone(),
+            transport: self.transport.clone(),
+            plugin: self.plugin.clone(),
         }))
     }
 }
@@ -192,18 +250,30 @@ pub struct SsOutProxyStarter {
     ss_port: u16,
     password: String,
     aead_type: AeadType,
+    dialer: Dialer,
+    transport: ClientTransport,
+    plugin: Option<Arc<Plugin>>,
 }
 
 #[async_trait]
 impl OutProxyStarter for SsOutProxyStarter {
     async fn new_connection(&mut self, proxy_info: ProxyInfo) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>)> {
-        debug!("new connect");
-        let addr = format!("{}:{}", self.ss_addr, self.ss_port);
-        let output_stream = TcpStream::connect(addr).await?;
+        debug!(
+            "new connect to {}",
+            Address::ip_str(&proxy_info.address, proxy_info.port, &proxy_info.address_type)
+        );
+        let output_stream = match &self.plugin {
+            Some(plugin) => {
+                let addr = plugin.local_addr();
+                self.dialer.dial(&addr.ip().to_string(), addr.port()).await?
+            }
+            None => self.dialer.dial(&self.ss_addr, self.ss_port).await?,
+        };
+        let output_stream = self.transport.connect(output_stream).await?;
         // Creat a random salt
         let write_salt = gen_random_salt(&self.aead_type);
         let write_ss_aead = SsAead::new(write_salt, self.password.as_bytes(), &self.aead_type).map_err(change_error)?;
-        let (read_half, write_half) = output_stream.into_split();
+        let (read_half, write_half) = split(output_stream);
 
         let reader = SsStreamReader::new(read_half, self.password.as_str(), self.aead_type);
         let writer = SsStreamWriter::new_with_addr(write_half, write_ss_aead, proxy_info);
@@ -215,7 +285,10 @@ impl OutProxyStarter for SsOutProxyStarter {
 //>-->-->-->-->-->-->-->-->-->-->-->--SS_INPUT_PROXY-->-->-->-->-->-->-->-->-->-->-->-->
 
 pub struct SsInputProxy {
-    tcp_listener: TcpListener,
+    tcp_listener: Listener,
+    /// Kept to stop the plugin with the input.
+    _plugin: Option<Plugin>,
+    transport: ServerTransport,
     password: String,
     out_proxy: Box<dyn OutputProxy>,
     aead_type: AeadType,
@@ -223,14 +296,14 @@ pub struct SsInputProxy {
 
 impl SsInputProxy {
     pub async fn new(aead_type: AeadType, passive: &BasePassiveConfig, out_proxy: Box<dyn OutputProxy>) -> io::Result<Self> {
-        let addr_str = format!("{}:{}", &passive.local_host, passive.local_port);
-        let addr = SocketAddr::from_str(addr_str.as_str()).map_err(|_| Error::new(ErrorKind::InvalidInput, "Error address"));
-        let tcp_listener = TcpListener::bind(addr?).await?;
-        info!("Shadowsocks ({:?}) bind in {}", aead_type, addr_str);
+        let (tcp_listener, plugin) = bind_ss_listener(passive).await?;
+        info!("Shadowsocks ({:?}) bind in {:?}", aead_type, tcp_listener.local_addrs());
         let password =
             passive.password.clone().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Shadowsocks must have a password"))?;
         Ok(Self {
             tcp_listener,
+            _plugin: plugin,
+            transport: ServerTransport::new(passive.transport.as_ref())?,
             password,
             out_proxy,
             aead_type,
@@ -250,8 +323,13 @@ impl InputProxy for SsInputProxy {
             };
             let aead_type = self.aead_type;
             let password = self.password.clone();
+            let transport = self.transport.clone();
             tokio::task::spawn(async move {
-                if let Err(e) = new_ss_proxy(tcpstream, starter, aead_type, password).await {
+                let result = match transport.accept(tcpstream).await {
+                    Ok(stream) => new_ss_proxy(stream, starter, aead_type, password).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
                     error!("Shadowsocks input proxy error. {}", e)
                 };
             });
@@ -260,19 +338,26 @@ impl InputProxy for SsInputProxy {
 }
 
 async fn new_ss_proxy(
-    tcpstream: TcpStream,
+    tcpstream: BoxStream,
     mut starter: Box<dyn OutProxyStarter>,
     aead_type: AeadType,
     password: String,
 ) -> io::Result<()> {
-    let (read_half, write_half) = tcpstream.into_split();
+    let (read_half, write_half) = split(tcpstream);
     let mut ss_reader = SsStreamReader::new(read_half, password.as_str(), aead_type);
     let write_slat = gen_random_salt(&aead_type);
     let write_aead = SsAead::new(write_slat, password.as_bytes(), &aead_type).map_err(change_error)?;
     let ss_writer = SsStreamWriter::creat_without_info(write_half, write_aead);
 
-    let first_read_data = ss_reader.read().await?;
-    let (info, read_addr_size) = Socks5::read_to_socket_addrs(first_read_data);
+    // The address may be split into several chunks.
+    let mut first_read_data = ss_reader.read().await?.to_vec();
+    let (info, read_addr_size) = loop {
+        match Socks5::parse_addr(&first_read_data) {
+            Ok(n) => break n,
+            Err(ParseError::Incomplete(_)) => first_read_data.extend_from_slice(ss_reader.read().await?),
+            Err(e) => return Err(e.into()),
+        }
+    };
     let (mut out_reader, mut out_writer) = starter.new_connection(info).await?;
 
     let reader = ss_input_write(ss_writer, &mut *out_reader);
@@ -293,7 +378,11 @@ async fn new_ss_proxy(
     Ok(())
 }
 
-async fn ss_input_read(mut ss_reader: SsStreamReader, out_writer: &mut dyn ProxyWriter, first_write: Option<Box<[u8]>>) -> usize {
+pub(crate) async fn ss_input_read<R: ProxyReader>(
+    mut ss_reader: R,
+    out_writer: &mut dyn ProxyWriter,
+    first_write: Option<Box<[u8]>>,
+) -> usize {
     let mut total = 0;
     if let Some(mut data) = first_write {
         if out_writer.write(data.as_mut()).await.is_err() {
@@ -318,7 +407,7 @@ async fn ss_input_read(mut ss_reader: SsStreamReader, out_writer: &mut dyn Proxy
     total
 }
 
-async fn ss_input_write(mut input_write: SsStreamWriter, out_reader: &mut dyn ProxyReader) -> usize {
+pub(crate) async fn ss_input_write<W: ProxyWriter>(mut input_write: W, out_reader: &mut dyn ProxyReader) -> usize {
     let mut total = 0;
     while let Ok(data) = out_reader.read().await {
         let size = data.len();
@@ -340,8 +429,70 @@ async fn ss_input_write(mut input_write: SsStreamWriter, out_reader: &mut dyn Pr
 
 /// Generate a Shadowsocks salt
 fn gen_random_salt(aead_type: &AeadType) -> Vec<u8> {
-    match aead_type {
-        AeadType::AES128GCM => rand::random::<[u8; 16]>().into(),
-        AeadType::AES256GCM | AeadType::Chacha20Poly1305 => rand::random::<[u8; 32]>().into(),
+    (0..aead_type.key_len()).map(|_| rand::random::<u8>()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::encrypt::aead::{AeadType, AEAD_TAG_SIZE};
+    use crate::encrypt::ss::ss_aead::{SsAead, MAX_PAYLOAD_SIZE};
+    use crate::net::proxy::{ProxyInfo, ProxyReader, ProxyWriter};
+    use crate::net::ss_stream::{gen_random_salt, SsStreamReader, SsStreamWriter};
+    use crate::net::stream::split;
+    use crate::net::AddressType;
+    use crate::socks::socks5::Socks5;
+
+    #[tokio::test]
+    async fn split_large_write() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let aead_type = AeadType::AES256GCM;
+        let ss_aead = SsAead::new(gen_random_salt(&aead_type), b"test", &aead_type).unwrap();
+        let info = ProxyInfo {
+            address_type: AddressType::Domain,
+            address: b"example.com".to_vec(),
+            port: 443,
+        };
+        let mut writer = SsStreamWriter::new_with_addr(split(Box::new(client)).1, ss_aead, info);
+        let mut reader = SsStreamReader::new(split(Box::new(server)).0, "test", aead_type);
+
+        let plain: Vec<u8> = (0..MAX_PAYLOAD_SIZE * 2 + 100).map(|i| i as u8).collect();
+        writer.write(&mut plain.clone()).await.unwrap();
+
+        let (info, addr_size) = Socks5::parse_addr(reader.read().await.unwrap()).unwrap();
+        assert_eq!(info.address, b"example.com");
+        assert_eq!(addr_size, 4 + "example.com".len());
+        let mut received = vec![];
+        while received.len() < plain.len() {
+            let data = reader.read().await.unwrap();
+            assert!(data.len() <= MAX_PAYLOAD_SIZE);
+            received.extend_from_slice(data);
+        }
+        assert_eq!(received, plain);
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_chunk() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let aead_type = AeadType::Chacha20Poly1305;
+        let mut ss_aead = SsAead::new(gen_random_salt(&aead_type), b"test", &aead_type).unwrap();
+        let mut header = [0u8; 2 + AEAD_TAG_SIZE];
+        let (len, tag) = header.split_at_mut(2);
+        len.copy_from_slice(&((MAX_PAYLOAD_SIZE + 1) as u16).to_be_bytes());
+        ss_aead.seal_in_place(len, tag).unwrap();
+        client.write_all(&ss_aead.salt).await.unwrap();
+        client.write_all(&header).await.unwrap();
+
+        let mut reader = SsStreamReader::new(split(Box::new(server)).0, "test", aead_type);
+        let err = reader.read().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
     }
 }
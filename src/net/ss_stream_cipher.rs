@@ -0,0 +1,352 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{debug, error, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::core::profile::BasePassiveConfig;
+use crate::encrypt::ss::ss_stream::SsStreamCipher;
+use crate::encrypt::stream::StreamCipherType;
+use crate::net::dialer::Dialer;
+use crate::net::listener::Listener;
+use crate::net::plugin::{bind_ss_listener, Plugin};
+use crate::net::proxy::{InputProxy, OutProxyStarter, OutputProxy, ProxyInfo, ProxyReader, ProxyWriter};
+use crate::net::ss_stream::{change_error, ss_input_read, ss_input_write};
+use crate::net::stream::{split, BoxStream, StreamReadHalf, StreamWriteHalf};
+use crate::net::transport::{ClientTransport, ServerTransport};
+use crate::socks::socks5::{ParseError, Socks5};
+use crate::util::address::Address;
+
+pub struct SsCipherReader {
+    read_half: StreamReadHalf,
+    password: Vec<u8>,
+    cipher_type: StreamCipherType,
+    cipher: Option<SsStreamCipher>,
+    buf: Vec<u8>,
+}
+
+impl SsCipherReader {
+    pub fn new(read_half: StreamReadHalf, password: &str, cipher_type: StreamCipherType) -> Self {
+        SsCipherReader {
+            read_half,
+            password: password.as_bytes().to_vec(),
+            cipher_type,
+            cipher: None,
+            buf: vec![0u8; 32 * 1024],
+        }
+    }
+
+    /// Read the IV from TCP if this is the first read.
+    async fn init_cipher(&mut self) -> io::Result<&mut SsStreamCipher> {
+        if self.cipher.is_none() {
+            let mut iv = vec![0u8; self.cipher_type.iv_len()];
+            self.read_half.read_exact(&mut iv).await?;
+            let cipher = SsStreamCipher::new_decryptor(iv, &self.password, &self.cipher_type).map_err(change_error)?;
+            self.cipher = Some(cipher);
+        }
+        Ok(self.cipher.as_mut().unwrap())
+    }
+
+    /// Read exactly `len` bytes and decrypt them.
+    async fn read_exact_plain(&mut self, len: usize) -> io::Result<&mut [u8]> {
+        self.init_cipher().await?;
+        let buf = &mut self.buf[..len];
+        self.read_half.read_exact(buf).await?;
+        self.cipher.as_mut().unwrap().apply(buf);
+        Ok(buf)
+    }
+
+    /// Read the target address header at the beginning of the stream.
+    /// A stream cipher has no chunks, so the header may be split over several TCP packets.
+    pub async fn read_proxy_info(&mut self) -> io::Result<ProxyInfo> {
+        let mut header = vec![];
+        loop {
+            match Socks5::parse_addr(&header) {
+                Ok((info, _)) => return Ok(info),
+                Err(ParseError::Incomplete(size)) => {
+                    let plain = self.read_exact_plain(size - header.len()).await?;
+                    header.extend_from_slice(plain);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Shadowsocks stream cipher TCP Reader.
+/// First, it will read a 12/16 bytes of IV.
+#[async_trait]
+impl ProxyReader for SsCipherReader {
+    async fn read(&mut self) -> io::Result<&mut [u8]> {
+        self.init_cipher().await?;
+        let size = self.read_half.read(&mut self.buf).await?;
+        let data = &mut self.buf[..size];
+        self.cipher.as_mut().unwrap().apply(data);
+        Ok(data)
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct SsCipherWriter {
+    writehalf: StreamWriteHalf,
+    cipher: SsStreamCipher,
+    iv_sent: bool,
+    proxy_info: Option<ProxyInfo>,
+}
+
+impl SsCipherWriter {
+    /// Create a pure Shadowsocks writer, it will not send the ss_header.
+    pub fn creat_without_info(writehalf: StreamWriteHalf, cipher: SsStreamCipher) -> Self {
+        SsCipherWriter {
+            writehalf,
+            cipher,
+            iv_sent: false,
+            proxy_info: None,
+        }
+    }
+
+    /// Creat a new [SsCipherWriter] with [ProxyInfo], and this writer will send
+    /// a bytes of ss_header when you first write.
+    pub fn new_with_addr(writehalf: StreamWriteHalf, cipher: SsStreamCipher, proxy_info: ProxyInfo) -> Self {
+        SsCipherWriter {
+            writehalf,
+            cipher,
+            iv_sent: false,
+            proxy_info: Some(proxy_info),
+        }
+    }
+}
+
+#[async_trait]
+impl ProxyWriter for SsCipherWriter {
+    async fn write(&mut self, raw_data: &mut [u8]) -> io::Result<()> {
+        if !self.iv_sent {
+            self.writehalf.write_all(&self.cipher.iv).await?;
+            self.iv_sent = true;
+        }
+        if let Some(info) = self.proxy_info.take() {
+            let mut addr_arr = Socks5::socks5_addr_arr(&info.address, info.port, &info.address_type)?;
+            self.cipher.apply(&mut addr_arr);
+            self.writehalf.write_all(&addr_arr).await?;
+        }
+        self.cipher.apply(raw_data);
+        self.writehalf.write_all(raw_data).await
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        self.writehalf.shutdown().await
+    }
+}
+
+//------------------------------SS_CIPHER_OUT_PROXY-----------------------------------------
+#[derive(Clone)]
+pub struct SsCipherOutProxy {
+    ss_addr: String,
+    ss_port: u16,
+    password: String,
+    cipher_type: StreamCipherType,
+    dialer: Dialer,
+    transport: ClientTransport,
+    /// The SIP003 plugin, connections go to its local address. It stops with the output.
+    plugin: Option<Arc<Plugin>>,
+}
+
+impl SsCipherOutProxy {
+    pub fn new(
+        ss_addr: String,
+        ss_port: u16,
+        password: String,
+        cipher_type: &StreamCipherType,
+        dialer: Dialer,
+        transport: ClientTransport,
+    ) -> Self {
+        Self {
+            ss_addr,
+            ss_port,
+            password,
+            cipher_type: (*cipher_type),
+            dialer,
+            transport,
+            plugin: None,
+        }
+    }
+
+    /// Connect to the remote through the SIP003 plugin if there is one, it listens on a local port.
+    pub fn with_plugin(mut self, plugin: Option<Plugin>) -> Self {
+        self.plugin = plugin.map(Arc::new);
+        self
+    }
+}
+
+impl OutputProxy for SsCipherOutProxy {
+    fn gen_connector(&mut self) -> io::Result<Box<dyn OutProxyStarter>> {
+        Ok(Box::new(SsCipherOutProxyStarter { proxy: self.clone() }))
+    }
+}
+
+pub struct SsCipherOutProxyStarter {
+    proxy: SsCipherOutProxy,
+}
+
+#[async_trait]
+impl OutProxyStarter for SsCipherOutProxyStarter {
+    async fn new_connection(&mut self, proxy_info: ProxyInfo) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>)> {
+        debug!(
+            "new connect to {}",
+            Address::ip_str(&proxy_info.address, proxy_info.port, &proxy_info.address_type)
+        );
+        let proxy = &self.proxy;
+        let output_stream = match &proxy.plugin {
+            Some(plugin) => {
+                let addr = plugin.local_addr();
+                proxy.dialer.dial(&addr.ip().to_string(), addr.port()).await?
+            }
+            None => proxy.dialer.dial(&proxy.ss_addr, proxy.ss_port).await?,
+        };
+        let output_stream = proxy.transport.connect(output_stream).await?;
+        let write_iv = gen_random_iv(&proxy.cipher_type);
+        let write_cipher =
+            SsStreamCipher::new_encryptor(write_iv, proxy.password.as_bytes(), &proxy.cipher_type).map_err(change_error)?;
+        let (read_half, write_half) = split(output_stream);
+
+        let reader = SsCipherReader::new(read_half, proxy.password.as_str(), proxy.cipher_type);
+        let writer = SsCipherWriter::new_with_addr(write_half, write_cipher, proxy_info);
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+//<--<--<--<--<--<--<--<--<--<--<--<--SS_CIPHER_OUT_PROXY--<--<--<--<--<--<--<--<--<--<--<--<
+
+//>-->-->-->-->-->-->-->-->-->-->-->--SS_CIPHER_INPUT_PROXY-->-->-->-->-->-->-->-->-->-->-->-->
+
+pub struct SsCipherInputProxy {
+    tcp_listener: Listener,
+    /// Kept to stop the plugin with the input.
+    _plugin: Option<Plugin>,
+    transport: ServerTransport,
+    password: String,
+    out_proxy: Box<dyn OutputProxy>,
+    cipher_type: StreamCipherType,
+}
+
+impl SsCipherInputProxy {
+    pub async fn new(
+        cipher_type: StreamCipherType,
+        passive: &BasePassiveConfig,
+        out_proxy: Box<dyn OutputProxy>,
+    ) -> io::Result<Self> {
+        let (tcp_listener, plugin) = bind_ss_listener(passive).await?;
+        info!("Shadowsocks ({:?}) bind in {:?}", cipher_type, tcp_listener.local_addrs());
+        let password = match passive.password.clone() {
+            Some(password) => password,
+            // The "none" method doesn't need a password.
+            None if cipher_type.key_len() == 0 => String::new(),
+            None => return Err(Error::new(ErrorKind::InvalidInput, "Shadowsocks must have a password")),
+        };
+        Ok(Self {
+            tcp_listener,
+            _plugin: plugin,
+            transport: ServerTransport::new(passive.transport.as_ref())?,
+            password,
+            out_proxy,
+            cipher_type,
+        })
+    }
+}
+
+#[async_trait]
+impl InputProxy for SsCipherInputProxy {
+    async fn start(&mut self) -> io::Result<()> {
+        info!("Shadowsocks start listen");
+        loop {
+            let (tcpstream, _addr) = self.tcp_listener.accept().await?;
+            let starter = match self.out_proxy.gen_connector() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            let cipher_type = self.cipher_type;
+            let password = self.password.clone();
+            let transport = self.transport.clone();
+            tokio::task::spawn(async move {
+                let result = match transport.accept(tcpstream).await {
+                    Ok(stream) => new_ss_cipher_proxy(stream, starter, cipher_type, password).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    error!("Shadowsocks input proxy error. {}", e)
+                };
+            });
+        }
+    }
+}
+
+async fn new_ss_cipher_proxy(
+    tcpstream: BoxStream,
+    mut starter: Box<dyn OutProxyStarter>,
+    cipher_type: StreamCipherType,
+    password: String,
+) -> io::Result<()> {
+    let (read_half, write_half) = split(tcpstream);
+    let mut ss_reader = SsCipherReader::new(read_half, password.as_str(), cipher_type);
+    let write_iv = gen_random_iv(&cipher_type);
+    let write_cipher = SsStreamCipher::new_encryptor(write_iv, password.as_bytes(), &cipher_type).map_err(change_error)?;
+    let ss_writer = SsCipherWriter::creat_without_info(write_half, write_cipher);
+
+    let info = ss_reader.read_proxy_info().await?;
+    let (mut out_reader, mut out_writer) = starter.new_connection(info).await?;
+
+    let reader = ss_input_write(ss_writer, &mut *out_reader);
+    let writer = ss_input_read(ss_reader, &mut *out_writer, None);
+    // Wait for two futures done.
+    tokio::select! {
+        _ = reader => {}
+        _ = writer => {}
+    }
+    Ok(())
+}
+
+//<--<--<--<--<--<--<--<--<--<--<--<--SS_CIPHER_INPUT_PROXY--<--<--<--<--<--<--<--<--<--<--<--<
+
+/// Generate a Shadowsocks IV
+fn gen_random_iv(cipher_type: &StreamCipherType) -> Vec<u8> {
+    (0..cipher_type.iv_len()).map(|_| rand::random::<u8>()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use tokio::io::AsyncWriteExt;
+
+    use crate::encrypt::stream::StreamCipherType;
+    use crate::net::proxy::ProxyReader;
+    use crate::net::ss_stream_cipher::SsCipherReader;
+    use crate::net::stream::split;
+
+    #[tokio::test]
+    async fn read_split_proxy_info() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = SsCipherReader::new(split(Box::new(server)).0, "test", StreamCipherType::None);
+        let writer = tokio::spawn(async move {
+            // The header comes one byte at a time, followed by the data.
+            for byte in b"\x03\x05a.com\x01\xbb" {
+                client.write_all(&[*byte]).await.unwrap();
+            }
+            client.write_all(b"data").await.unwrap();
+            client
+        });
+        let info = reader.read_proxy_info().await.unwrap();
+        assert_eq!((info.address, info.port), (b"a.com".to_vec(), 443));
+        let _client = writer.await.unwrap();
+        assert_eq!(reader.read().await.unwrap(), b"data");
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = SsCipherReader::new(split(Box::new(server)).0, "test", StreamCipherType::None);
+        client.write_all(&[2, 0, 0]).await.unwrap();
+        assert_eq!(reader.read_proxy_info().await.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
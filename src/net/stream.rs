@@ -0,0 +1,22 @@
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// A connection of the proxies, such as a TCP stream or a Unix socket stream.
+pub trait ProxyStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ProxyStream for T {}
+
+pub type BoxStream = Box<dyn ProxyStream>;
+pub type StreamReadHalf = ReadHalf<BoxStream>;
+pub type StreamWriteHalf = WriteHalf<BoxStream>;
+
+/// Split a stream into a reader and a writer.
+pub fn split(stream: BoxStream) -> (StreamReadHalf, StreamWriteHalf) {
+    tokio::io::split(stream)
+}
+
+/// Get the path of a `unix:/path.sock` host.
+pub fn unix_path(host: &str) -> Option<&str> {
+    host.strip_prefix(UNIX_PREFIX)
+}
@@ -0,0 +1,108 @@
+use std::io;
+#[cfg(not(feature = "tls"))]
+use std::io::{Error, ErrorKind};
+
+use crate::core::profile::{ActiveTransportConfig, PassiveTransportConfig};
+use crate::net::stream::BoxStream;
+
+mod obfs;
+#[cfg(feature = "tls")]
+mod tls;
+mod ws;
+
+/// Layers between an outbound connection and the protocol of the output proxy.
+/// The connection goes through TLS first, then WebSocket, then simple-obfs.
+#[derive(Clone, Default)]
+pub struct ClientTransport {
+    #[cfg(feature = "tls")]
+    tls: Option<tls::TlsClient>,
+    ws: Option<ws::WsClient>,
+    obfs: Option<obfs::ObfsClient>,
+}
+
+impl ClientTransport {
+    /// Create the transport of the connections to `remote_host` and `remote_port`.
+    pub fn new(config: Option<&ActiveTransportConfig>, remote_host: &str, remote_port: u16) -> io::Result<Self> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(Self::default()),
+        };
+        #[cfg(not(feature = "tls"))]
+        if config.tls.is_some() {
+            return Err(tls_unsupported());
+        }
+        Ok(Self {
+            #[cfg(feature = "tls")]
+            tls: config.tls.as_ref().map(|tls| tls::TlsClient::new(tls, remote_host)).transpose()?,
+            ws: config.ws.as_ref().map(|ws| ws::WsClient::new(ws, remote_host)).transpose()?,
+            obfs: config.obfs.as_ref().map(|obfs| obfs::ObfsClient::new(obfs, remote_port)),
+        })
+    }
+
+    /// Wrap a new connection to the remote.
+    pub async fn connect(&self, stream: BoxStream) -> io::Result<BoxStream> {
+        #[cfg(feature = "tls")]
+        let stream = match &self.tls {
+            Some(tls) => tls.connect(stream).await?,
+            None => stream,
+        };
+        let stream = match &self.ws {
+            Some(ws) => ws.connect(stream),
+            None => stream,
+        };
+        Ok(match &self.obfs {
+            Some(obfs) => obfs.connect(stream),
+            None => stream,
+        })
+    }
+}
+
+/// Layers between an accepted connection and the protocol of the input proxy.
+#[derive(Clone, Default)]
+pub struct ServerTransport {
+    #[cfg(feature = "tls")]
+    tls: Option<tls::TlsServer>,
+    ws: Option<ws::WsServer>,
+    obfs: Option<obfs::ObfsServer>,
+}
+
+impl ServerTransport {
+    pub fn new(config: Option<&PassiveTransportConfig>) -> io::Result<Self> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(Self::default()),
+        };
+        #[cfg(not(feature = "tls"))]
+        if config.tls.is_some() {
+            return Err(tls_unsupported());
+        }
+        Ok(Self {
+            #[cfg(feature = "tls")]
+            tls: config.tls.as_ref().map(tls::TlsServer::new).transpose()?,
+            ws: config.ws.as_ref().map(ws::WsServer::new),
+            obfs: config.obfs.as_ref().map(obfs::ObfsServer::new),
+        })
+    }
+
+    /// Unwrap an accepted connection, it should be called in the task of the connection.
+    pub async fn accept(&self, stream: BoxStream) -> io::Result<BoxStream> {
+        #[cfg(feature = "tls")]
+        let stream = match &self.tls {
+            Some(tls) => tls.accept(stream).await?,
+            None => stream,
+        };
+        let stream = match &self.ws {
+            Some(ws) => ws.accept(stream).await?,
+            None => stream,
+        };
+        Ok(match &self.obfs {
+            Some(obfs) => obfs.accept(stream),
+            None => stream,
+        })
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_unsupported() -> Error {
+    Error::new(ErrorKind::Unsupported, "TLS transport needs the `tls` feature")
+}
@@ -0,0 +1,492 @@
+use std::io;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::core::profile::{ObfsConfig, ObfsMode};
+use crate::net::stream::BoxStream;
+
+/// The host of simple-obfs when `obfs-host` is not set.
+const DEFAULT_HOST: &str = "cloudfront.net";
+const MAX_HTTP_HEADER: usize = 8 * 1024;
+const MAX_RECORD_DATA: usize = 16 * 1024;
+const TLS_HEADER_LEN: usize = 5;
+
+/// Cipher suites of the fake ClientHello.
+const CIPHER_SUITES: [u8; 56] = [
+    0xc0, 0x2c, 0xc0, 0x30, 0x00, 0x9f, 0xcc, 0xa9, 0xcc, 0xa8, 0xcc, 0xaa, 0xc0, 0x2b, 0xc0, 0x2f, 0x00, 0x9e, 0xc0, 0x24, 0xc0,
+    0x28, 0x00, 0x6b, 0xc0, 0x23, 0xc0, 0x27, 0x00, 0x67, 0xc0, 0x0a, 0xc0, 0x14, 0x00, 0x39, 0xc0, 0x09, 0xc0, 0x13, 0x00, 0x33,
+    0x00, 0x9d, 0x00, 0x9c, 0x00, 0x3d, 0x00, 0x3c, 0x00, 0x35, 0x00, 0x2f, 0x00, 0xff,
+];
+
+/// Extensions of the fake ClientHello after the session ticket and the SNI:
+/// ec_point_formats, supported_groups, signature_algorithms, encrypt_then_mac and extended_master_secret.
+const OTHER_EXTENSIONS: [u8; 66] = [
+    0x00, 0x0b, 0x00, 0x04, 0x03, 0x01, 0x00, 0x02, 0x00, 0x0a, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x19, 0x00,
+    0x18, 0x00, 0x0d, 0x00, 0x20, 0x00, 0x1e, 0x06, 0x01, 0x06, 0x02, 0x06, 0x03, 0x05, 0x01, 0x05, 0x02, 0x05, 0x03, 0x04, 0x01,
+    0x04, 0x02, 0x04, 0x03, 0x03, 0x01, 0x03, 0x02, 0x03, 0x03, 0x02, 0x01, 0x02, 0x02, 0x02, 0x03, 0x00, 0x16, 0x00, 0x00, 0x00,
+    0x17, 0x00, 0x00,
+];
+
+/// The client of simple-obfs, compatible with `obfs-server`.
+#[derive(Clone)]
+pub struct ObfsClient {
+    mode: ObfsMode,
+    host: String,
+    uri: String,
+}
+
+impl ObfsClient {
+    pub fn new(config: &ObfsConfig, remote_port: u16) -> Self {
+        let mut host = config.host.clone().unwrap_or_else(|| DEFAULT_HOST.to_string());
+        // simple-obfs puts the port in the HTTP Host header unless it is 80.
+        if config.mode == ObfsMode::Http && remote_port != 80 {
+            host = format!("{}:{}", host, remote_port);
+        }
+        Self {
+            mode: config.mode,
+            host,
+            uri: config.uri.clone().unwrap_or_else(|| "/".to_string()),
+        }
+    }
+
+    /// The fake handshake is sent with the first write.
+    pub fn connect(&self, stream: BoxStream) -> BoxStream {
+        let read_stage = match self.mode {
+            ObfsMode::Http => ReadStage::HttpHeader,
+            ObfsMode::Tls => ReadStage::TlsServerHello,
+        };
+        Box::new(ObfsStream::new(stream, self.mode, Some(self.clone()), read_stage))
+    }
+}
+
+/// The server of simple-obfs, compatible with `obfs-local`.
+#[derive(Clone)]
+pub struct ObfsServer {
+    mode: ObfsMode,
+}
+
+impl ObfsServer {
+    pub fn new(config: &ObfsConfig) -> Self {
+        Self { mode: config.mode }
+    }
+
+    /// The fake handshake of the client is checked with the first read.
+    pub fn accept(&self, stream: BoxStream) -> BoxStream {
+        let read_stage = match self.mode {
+            ObfsMode::Http => ReadStage::HttpHeader,
+            ObfsMode::Tls => ReadStage::TlsClientHello,
+        };
+        Box::new(ObfsStream::new(stream, self.mode, None, read_stage))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ReadStage {
+    HttpHeader,
+    /// The client skips the fake ServerHello and ChangeCipherSpec.
+    TlsServerHello,
+    TlsChangeCipherSpec,
+    /// The server takes the first data from the session ticket of the ClientHello.
+    TlsClientHello,
+    TlsRecords,
+    Raw,
+}
+
+struct ObfsStream {
+    inner: BoxStream,
+    mode: ObfsMode,
+    /// The client settings, `None` on the server side.
+    client: Option<ObfsClient>,
+    /// The fake handshake is sent with the first write.
+    handshake_sent: bool,
+    /// Session ID of the ClientHello, the ServerHello sends it back.
+    session_id: Option<[u8; 32]>,
+    read_stage: ReadStage,
+    /// Bytes read from the inner stream, which are not decoded yet.
+    read_raw: Vec<u8>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    /// Size of the data in `write_buf`, the write is done when it is all written.
+    write_len: usize,
+}
+
+impl ObfsStream {
+    fn new(inner: BoxStream, mode: ObfsMode, client: Option<ObfsClient>, read_stage: ReadStage) -> Self {
+        Self {
+            inner,
+            mode,
+            client,
+            handshake_sent: false,
+            session_id: None,
+            read_stage,
+            read_raw: vec![],
+            read_buf: vec![],
+            read_pos: 0,
+            write_buf: vec![],
+            write_pos: 0,
+            write_len: 0,
+        }
+    }
+
+    /// Encode the data into `write_buf`, return the size of the data taken.
+    fn encode(&mut self, data: &[u8]) -> usize {
+        let first = !self.handshake_sent;
+        self.handshake_sent = true;
+        self.write_buf.clear();
+        self.write_pos = 0;
+        match (self.mode, &self.client) {
+            (ObfsMode::Http, Some(client)) if first => {
+                self.write_buf = http_request(client, data.len()).into_bytes();
+                self.write_buf.extend_from_slice(data);
+                data.len()
+            }
+            (ObfsMode::Http, None) if first => {
+                self.write_buf = http_response().into_bytes();
+                self.write_buf.extend_from_slice(data);
+                data.len()
+            }
+            (ObfsMode::Http, _) => {
+                self.write_buf.extend_from_slice(data);
+                data.len()
+            }
+            (ObfsMode::Tls, Some(client)) if first => {
+                let size = data.len().min(MAX_RECORD_DATA);
+                self.write_buf = client_hello(&client.host, &data[..size]);
+                size
+            }
+            (ObfsMode::Tls, None) if first => {
+                let size = data.len().min(MAX_RECORD_DATA);
+                let session_id = self.session_id.unwrap_or_else(rand::random);
+                self.write_buf = server_hello(&session_id, &data[..size]);
+                size
+            }
+            (ObfsMode::Tls, _) => {
+                for chunk in data.chunks(MAX_RECORD_DATA) {
+                    self.write_buf.extend_from_slice(&[0x17, 0x03, 0x03]);
+                    self.write_buf.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                    self.write_buf.extend_from_slice(chunk);
+                }
+                data.len()
+            }
+        }
+    }
+
+    /// Take the data out of `read_raw`, `None` if more bytes are needed.
+    fn decode(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_stage {
+                ReadStage::HttpHeader => {
+                    let end = match self.read_raw.windows(4).position(|window| window == b"\r\n\r\n") {
+                        Some(pos) => pos + 4,
+                        None if self.read_raw.len() > MAX_HTTP_HEADER => {
+                            return Err(invalid_data("Obfs HTTP header is too long"))
+                        }
+                        None => return Ok(None),
+                    };
+                    if self.client.is_none() && !is_upgrade_request(&self.read_raw[..end]) {
+                        return Err(invalid_data("Invalid obfs HTTP request"));
+                    }
+                    self.read_raw.drain(..end);
+                    self.read_stage = ReadStage::Raw;
+                }
+                ReadStage::Raw if self.read_raw.is_empty() => return Ok(None),
+                ReadStage::Raw => return Ok(Some(std::mem::take(&mut self.read_raw))),
+                ReadStage::TlsServerHello | ReadStage::TlsChangeCipherSpec => {
+                    let (content_type, _) = match take_record(&mut self.read_raw) {
+                        Some(record) => record,
+                        None => return Ok(None),
+                    };
+                    self.read_stage = match (self.read_stage, content_type) {
+                        (ReadStage::TlsServerHello, 0x16) => ReadStage::TlsChangeCipherSpec,
+                        (ReadStage::TlsChangeCipherSpec, 0x14) => ReadStage::TlsRecords,
+                        _ => return Err(invalid_data("Invalid obfs TLS ServerHello")),
+                    };
+                }
+                ReadStage::TlsClientHello => {
+                    let (content_type, record) = match take_record(&mut self.read_raw) {
+                        Some(record) => record,
+                        None => return Ok(None),
+                    };
+                    let (session_id, data) = match content_type {
+                        0x16 => parse_client_hello(&record).ok_or_else(|| invalid_data("Invalid obfs TLS ClientHello"))?,
+                        _ => return Err(invalid_data("Invalid obfs TLS ClientHello")),
+                    };
+                    self.session_id = Some(session_id);
+                    self.read_stage = ReadStage::TlsRecords;
+                    if !data.is_empty() {
+                        return Ok(Some(data));
+                    }
+                }
+                ReadStage::TlsRecords => match take_record(&mut self.read_raw) {
+                    Some((0x16 | 0x17, data)) if !data.is_empty() => return Ok(Some(data)),
+                    Some((0x16 | 0x17, _)) => continue,
+                    Some(_) => return Err(invalid_data("Invalid obfs TLS record")),
+                    None => return Ok(None),
+                },
+            }
+        }
+    }
+}
+
+impl AsyncRead for ObfsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let size = buf.remaining().min(this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + size]);
+                this.read_pos += size;
+                return Poll::Ready(Ok(()));
+            }
+            // The payload after the HTTP header is passed through.
+            if this.read_stage == ReadStage::Raw && this.read_raw.is_empty() {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+            if let Some(data) = this.decode()? {
+                this.read_buf = data;
+                this.read_pos = 0;
+                continue;
+            }
+            let mut raw = [0u8; 4096];
+            let mut raw_buf = ReadBuf::new(&mut raw);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut raw_buf))?;
+            if raw_buf.filled().is_empty() {
+                return if this.read_raw.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Ready(Err(ErrorKind::UnexpectedEof.into()))
+                };
+            }
+            this.read_raw.extend_from_slice(raw_buf.filled());
+        }
+    }
+}
+
+impl AsyncWrite for ObfsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        // A pending write is retried with the same data, so it goes on with the encoded one.
+        if this.write_pos == this.write_buf.len() {
+            if this.mode == ObfsMode::Http && this.handshake_sent {
+                return Pin::new(&mut this.inner).poll_write(cx, buf);
+            }
+            this.write_len = this.encode(buf);
+        }
+        while this.write_pos < this.write_buf.len() {
+            let size = ready!(Pin::new(&mut this.inner).poll_write(cx, &this.write_buf[this.write_pos..]))?;
+            if size == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            this.write_pos += size;
+        }
+        Poll::Ready(Ok(this.write_len))
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        let data: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        self.poll_write(cx, &data)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+fn http_request(client: &ObfsClient, content_length: usize) -> String {
+    let key: [u8; 16] = rand::random();
+    format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: curl/7.{}.{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nContent-Length: {}\r\n\r\n",
+        client.uri,
+        client.host,
+        rand::random::<u8>() % 51,
+        rand::random::<u8>() % 2,
+        STANDARD.encode(key),
+        content_length
+    )
+}
+
+fn http_response() -> String {
+    let key: [u8; 16] = rand::random();
+    format!(
+        "HTTP/1.1 101 Switching Protocols\r\nServer: nginx/1.{}.{}\r\nDate: {}\r\nUpgrade: websocket\r\n\
+         Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        rand::random::<u8>() % 11,
+        rand::random::<u8>() % 12,
+        httpdate::fmt_http_date(SystemTime::now()),
+        STANDARD.encode(key)
+    )
+}
+
+fn is_upgrade_request(header: &[u8]) -> bool {
+    String::from_utf8_lossy(header).lines().any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("upgrade:") && line.contains("websocket")
+    })
+}
+
+/// The first data is sent in the session ticket extension.
+fn client_hello(host: &str, data: &[u8]) -> Vec<u8> {
+    let ext_len = 4 + data.len() + 9 + host.len() + OTHER_EXTENSIONS.len();
+    let total = 138 + ext_len;
+    let mut hello = Vec::with_capacity(total);
+    hello.extend_from_slice(&[0x16, 0x03, 0x01]);
+    hello.extend_from_slice(&((total - TLS_HEADER_LEN) as u16).to_be_bytes());
+    hello.extend_from_slice(&[0x01, 0x00]);
+    hello.extend_from_slice(&((total - TLS_HEADER_LEN - 4) as u16).to_be_bytes());
+    hello.extend_from_slice(&[0x03, 0x03]);
+    hello.extend_from_slice(&unix_time().to_be_bytes());
+    hello.extend_from_slice(&rand::random::<[u8; 28]>());
+    hello.push(32);
+    hello.extend_from_slice(&rand::random::<[u8; 32]>());
+    hello.extend_from_slice(&(CIPHER_SUITES.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&CIPHER_SUITES);
+    hello.extend_from_slice(&[0x01, 0x00]);
+    hello.extend_from_slice(&(ext_len as u16).to_be_bytes());
+    hello.extend_from_slice(&[0x00, 0x23]);
+    hello.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    hello.extend_from_slice(data);
+    hello.extend_from_slice(&[0x00, 0x00]);
+    hello.extend_from_slice(&((host.len() + 5) as u16).to_be_bytes());
+    hello.extend_from_slice(&((host.len() + 3) as u16).to_be_bytes());
+    hello.push(0x00);
+    hello.extend_from_slice(&(host.len() as u16).to_be_bytes());
+    hello.extend_from_slice(host.as_bytes());
+    hello.extend_from_slice(&OTHER_EXTENSIONS);
+    hello
+}
+
+/// The ServerHello, ChangeCipherSpec, and the first data as the encrypted Finished message.
+fn server_hello(session_id: &[u8; 32], data: &[u8]) -> Vec<u8> {
+    let mut hello = Vec::with_capacity(96 + 6 + TLS_HEADER_LEN + data.len());
+    hello.extend_from_slice(&[0x16, 0x03, 0x01, 0x00, 0x5b, 0x02, 0x00, 0x00, 0x57, 0x03, 0x03]);
+    hello.extend_from_slice(&unix_time().to_be_bytes());
+    hello.extend_from_slice(&rand::random::<[u8; 28]>());
+    hello.push(32);
+    hello.extend_from_slice(session_id);
+    hello.extend_from_slice(&[
+        0xcc, 0xa8, 0x00, 0x00, 0x0f, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x17, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x02, 0x01, 0x00,
+    ]);
+    hello.extend_from_slice(&[0x14, 0x03, 0x03, 0x00, 0x01, 0x01]);
+    hello.extend_from_slice(&[0x16, 0x03, 0x03]);
+    hello.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    hello.extend_from_slice(data);
+    hello
+}
+
+/// Take a whole TLS record, return its content type and data.
+fn take_record(raw: &mut Vec<u8>) -> Option<(u8, Vec<u8>)> {
+    if raw.len() < TLS_HEADER_LEN {
+        return None;
+    }
+    let len = u16::from_be_bytes([raw[3], raw[4]]) as usize;
+    if raw.len() < TLS_HEADER_LEN + len {
+        return None;
+    }
+    let record = (raw[0], raw[TLS_HEADER_LEN..TLS_HEADER_LEN + len].to_vec());
+    raw.drain(..TLS_HEADER_LEN + len);
+    Some(record)
+}
+
+/// Return the session ID and the session ticket of a ClientHello message.
+fn parse_client_hello(message: &[u8]) -> Option<([u8; 32], Vec<u8>)> {
+    // Handshake type, length, version and random.
+    if *message.first()? != 0x01 || *message.get(38)? != 32 {
+        return None;
+    }
+    let session_id: [u8; 32] = message.get(39..71)?.try_into().ok()?;
+    let read_u16 = |pos: usize| Some(u16::from_be_bytes([*message.get(pos)?, *message.get(pos + 1)?]) as usize);
+    let mut pos = 71;
+    pos += 2 + read_u16(pos)?;
+    pos += 1 + *message.get(pos)? as usize;
+    let end = pos + 2 + read_u16(pos)?;
+    pos += 2;
+    while pos + 4 <= end {
+        let len = read_u16(pos + 2)?;
+        if read_u16(pos)? == 0x0023 {
+            return Some((session_id, message.get(pos + 4..pos + 4 + len)?.to_vec()));
+        }
+        pos += 4 + len;
+    }
+    None
+}
+
+fn unix_time() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs() as u32).unwrap_or_default()
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::core::profile::{ObfsConfig, ObfsMode};
+    use crate::net::transport::obfs::{client_hello, parse_client_hello, ObfsClient, ObfsServer};
+
+    #[test]
+    fn client_hello_layout() {
+        let hello = client_hello("example.com", b"data");
+        assert_eq!(hello.len(), 138 + 4 + 4 + 9 + 11 + 66);
+        assert_eq!(&hello[..3], &[0x16, 0x03, 0x01]);
+        assert_eq!(u16::from_be_bytes([hello[3], hello[4]]) as usize, hello.len() - 5);
+        assert_eq!(&hello[138..146], b"\x00\x23\x00\x04data");
+        assert_eq!(&hello[146..155], b"\x00\x00\x00\x10\x00\x0e\x00\x00\x0b");
+        assert_eq!(&hello[155..166], b"example.com");
+        let (session_id, data) = parse_client_hello(&hello[5..]).unwrap();
+        assert_eq!(session_id, hello[44..76]);
+        assert_eq!(data, b"data");
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        for mode in [ObfsMode::Http, ObfsMode::Tls] {
+            let config = ObfsConfig {
+                mode,
+                host: Some("example.com".to_string()),
+                uri: None,
+            };
+            let client = ObfsClient::new(&config, 8388);
+            let server = ObfsServer::new(&config);
+            let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+            let server_task = tokio::spawn(async move {
+                let mut stream = server.accept(Box::new(server_io));
+                let mut buf = vec![0u8; 20000];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf[..4]).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+                stream.shutdown().await.unwrap();
+            });
+            let mut stream = client.connect(Box::new(client_io));
+            // Larger than a record, and split into two writes in the TLS mode.
+            let data: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+            stream.write_all(&data).await.unwrap();
+            let mut buf = vec![];
+            stream.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(&buf[..4], &data[..4]);
+            assert_eq!(&buf[4..], &data[..], "{:?}", mode);
+            server_task.await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Error, ErrorKind};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use log::debug;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig, ServerName};
+use sha2::{Digest, Sha256};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::core::profile::{TlsCertificateConfig, TlsClientConfig, TlsServerConfig};
+use crate::net::dns::domain::DomainMatcher;
+use crate::net::stream::{unix_path, BoxStream};
+
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName,
+}
+
+impl TlsClient {
+    pub fn new(config: &TlsClientConfig, remote_host: &str) -> io::Result<Self> {
+        let name = match &config.server_name {
+            Some(name) => name.as_str(),
+            None if unix_path(remote_host).is_none() => remote_host.trim_start_matches('[').trim_end_matches(']'),
+            None => return Err(invalid_input("TLS over a Unix socket needs a server_name".to_string())),
+        };
+        let server_name = ServerName::try_from(name).map_err(|_| invalid_input(format!("Invalid TLS server name {}", name)))?;
+        let builder = ClientConfig::builder().with_safe_defaults();
+        let mut client_config = match &config.pin_sha256 {
+            Some(pins) => {
+                let verifier = PinnedCertVerifier {
+                    pins: pins.as_slice().iter().map(|pin| normalize_hex(pin)).collect(),
+                };
+                builder.with_custom_certificate_verifier(Arc::new(verifier)).with_no_client_auth()
+            }
+            None => builder.with_root_certificates(root_store(config.ca.as_deref())?).with_no_client_auth(),
+        };
+        client_config.alpn_protocols = alpn_protocols(&config.alpn);
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(client_config)),
+            server_name,
+        })
+    }
+
+    pub async fn connect(&self, stream: BoxStream) -> io::Result<BoxStream> {
+        Ok(Box::new(self.connector.connect(self.server_name.clone(), stream).await?))
+    }
+}
+
+#[derive(Clone)]
+pub struct TlsServer {
+    acceptor: TlsAcceptor,
+}
+
+impl TlsServer {
+    pub fn new(config: &TlsServerConfig) -> io::Result<Self> {
+        let mut resolver = SniCertResolver {
+            names: DomainMatcher::new(),
+            default: None,
+        };
+        for cert_config in config.certificates.as_slice() {
+            let certified_key = Arc::new(load_certified_key(cert_config)?);
+            for name in cert_config.server_names.iter().flatten() {
+                resolver.names.insert(name, certified_key.clone());
+            }
+            resolver.default.get_or_insert(certified_key);
+        }
+        let mut server_config =
+            ServerConfig::builder().with_safe_defaults().with_no_client_auth().with_cert_resolver(Arc::new(resolver));
+        server_config.alpn_protocols = alpn_protocols(&config.alpn);
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+        })
+    }
+
+    pub async fn accept(&self, stream: BoxStream) -> io::Result<BoxStream> {
+        Ok(Box::new(self.acceptor.accept(stream).await?))
+    }
+}
+
+/// Choose the certificate by the SNI of the client.
+struct SniCertResolver {
+    names: DomainMatcher<Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        match client_hello.server_name().and_then(|name| self.names.get(name)) {
+            Some(certified_key) => Some(certified_key.clone()),
+            None => self.default.clone(),
+        }
+    }
+}
+
+/// Accept the server certificates whose SHA-256 is one of the pins.
+struct PinnedCertVerifier {
+    pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let hash = sha256_hex(&end_entity.0);
+        if self.pins.contains(&hash) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            debug!("TLS certificate SHA-256 {} is not pinned", hash);
+            Err(rustls::Error::General("The server certificate is not pinned".to_string()))
+        }
+    }
+}
+
+fn root_store(ca: Option<&str>) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(path) => {
+            for cert in load_certs(path)? {
+                roots.add(&cert).map_err(|e| invalid_input(format!("Invalid CA certificate in {}: {}", path, e)))?;
+            }
+        }
+        None => roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+        })),
+    }
+    Ok(roots)
+}
+
+fn load_certified_key(config: &TlsCertificateConfig) -> io::Result<CertifiedKey> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_private_key(&config.key)?;
+    let signing_key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| invalid_input(format!("Unsupported private key in {}", config.key)))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(invalid_input(format!("No certificate in {}", path)));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &str) -> io::Result<PrivateKey> {
+    for item in rustls_pemfile::read_all(&mut BufReader::new(File::open(path)?))? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => continue,
+        }
+    }
+    Err(invalid_input(format!("No private key in {}", path)))
+}
+
+fn alpn_protocols(alpn: &Option<Vec<String>>) -> Vec<Vec<u8>> {
+    alpn.iter().flatten().map(|protocol| protocol.as_bytes().to_vec()).collect()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Pins may be upper case and separated by colons, such as `AB:CD:...`.
+fn normalize_hex(hex: &str) -> String {
+    hex.chars().filter(|c| *c != ':').collect::<String>().to_ascii_lowercase()
+}
+
+fn invalid_input(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::core::profile::{OneOrMany, TlsCertificateConfig, TlsClientConfig, TlsServerConfig};
+    use crate::net::transport::tls::{load_certs, sha256_hex, TlsClient, TlsServer};
+
+    /// Write a self-signed certificate and its key, return the paths.
+    fn self_signed(name: &str) -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let path = |ext: &str| -> PathBuf { dir.join(format!("touch-rs-{}-{}.{}", std::process::id(), name, ext)) };
+        std::fs::write(path("crt"), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(path("key"), cert.serialize_private_key_pem()).unwrap();
+        let to_string = |path: PathBuf| path.to_str().unwrap().to_string();
+        (to_string(path("crt")), to_string(path("key")))
+    }
+
+    /// Connect a client to the server and echo a message.
+    async fn handshake(server: &TlsServer, client: &TlsClient) -> std::io::Result<()> {
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let server = server.clone();
+        let server_task = tokio::spawn(async move {
+            let mut stream = server.accept(Box::new(server_io)).await?;
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            stream.flush().await
+        });
+        let mut stream = client.connect(Box::new(client_io)).await?;
+        stream.write_all(b"ping").await?;
+        stream.flush().await?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        server_task.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn tls_handshake() {
+        let (a_cert, a_key) = self_signed("a.test");
+        let (b_cert, b_key) = self_signed("b.test");
+        let server = TlsServer::new(&TlsServerConfig {
+            certificates: OneOrMany::Many(vec![
+                TlsCertificateConfig {
+                    cert: a_cert.clone(),
+                    key: a_key,
+                    server_names: Some(vec!["a.test".to_string()]),
+                },
+                TlsCertificateConfig {
+                    cert: b_cert.clone(),
+                    key: b_key,
+                    server_names: Some(vec!["*.b.test".to_string()]),
+                },
+            ]),
+            alpn: Some(vec!["h2".to_string()]),
+        })
+        .unwrap();
+
+        // The certificate is chosen by the SNI.
+        let with_ca = |ca: &str, server_name: &str| TlsClientConfig {
+            server_name: Some(server_name.to_string()),
+            ca: Some(ca.to_string()),
+            ..TlsClientConfig::default()
+        };
+        let client = TlsClient::new(&with_ca(&a_cert, "a.test"), "127.0.0.1").unwrap();
+        handshake(&server, &client).await.unwrap();
+        let client = TlsClient::new(&with_ca(&a_cert, "x.b.test"), "127.0.0.1").unwrap();
+        assert!(handshake(&server, &client).await.is_err());
+
+        // The server name is the remote host by default.
+        let default_name = TlsClientConfig {
+            ca: Some(a_cert.clone()),
+            ..TlsClientConfig::default()
+        };
+        let client = TlsClient::new(&default_name, "a.test").unwrap();
+        handshake(&server, &client).await.unwrap();
+
+        // A pinned certificate is accepted without the CA.
+        let pinned = |pin: String| TlsClientConfig {
+            pin_sha256: Some(OneOrMany::One(pin)),
+            ..TlsClientConfig::default()
+        };
+        let a_der = &load_certs(&a_cert).unwrap()[0].0;
+        let client = TlsClient::new(&pinned(sha256_hex(a_der).to_uppercase()), "a.test").unwrap();
+        handshake(&server, &client).await.unwrap();
+        let client = TlsClient::new(&pinned("00".repeat(32)), "a.test").unwrap();
+        assert!(handshake(&server, &client).await.is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use std::future::Future;
+use std::io;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::net::Ipv6Addr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request as ServerRequest, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::core::profile::WsConfig;
+use crate::net::stream::{unix_path, BoxStream};
+
+/// The early data is sent in this header as URL-safe base64, like Xray does.
+const EARLY_DATA_HEADER: &str = "sec-websocket-protocol";
+
+#[derive(Clone)]
+pub struct WsClient {
+    url: String,
+    headers: HeaderMap,
+    max_early_data: usize,
+}
+
+impl WsClient {
+    pub fn new(config: &WsConfig, remote_host: &str) -> io::Result<Self> {
+        let host = match &config.host {
+            Some(host) => host.clone(),
+            None if unix_path(remote_host).is_some() => "localhost".to_string(),
+            None if Ipv6Addr::from_str(remote_host).is_ok() => format!("[{}]", remote_host),
+            None => remote_host.to_string(),
+        };
+        let url = format!("ws://{}{}", host, config.path.as_deref().unwrap_or("/"));
+        // Check the URL once, so the connections can't fail on it.
+        url.as_str().into_client_request().map_err(ws_error)?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in config.headers.iter().flatten() {
+            let invalid = || {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Invalid WebSocket header {}: {}", name, value),
+                )
+            };
+            let name = HeaderName::from_str(name).map_err(|_| invalid())?;
+            headers.insert(name, HeaderValue::from_str(value).map_err(|_| invalid())?);
+        }
+        Ok(Self {
+            url,
+            headers,
+            max_early_data: config.max_early_data.unwrap_or(0),
+        })
+    }
+
+    /// The handshake is done with the first write, so the first data can be sent in the request.
+    pub fn connect(&self, stream: BoxStream) -> BoxStream {
+        let mut request = self.url.as_str().into_client_request().expect("The URL is checked");
+        request.headers_mut().extend(self.headers.clone());
+        Box::new(WsStream::new(
+            WsState::Connecting(Some((stream, request, self.max_early_data))),
+            vec![],
+        ))
+    }
+}
+
+#[derive(Clone)]
+pub struct WsServer {
+    path: String,
+}
+
+impl WsServer {
+    pub fn new(config: &WsConfig) -> Self {
+        Self {
+            path: config.path.clone().unwrap_or_else(|| "/".to_string()),
+        }
+    }
+
+    pub async fn accept(&self, stream: BoxStream) -> io::Result<BoxStream> {
+        let mut early_data = vec![];
+        // The callback type is defined by tungstenite.
+        #[allow(clippy::result_large_err)]
+        let callback = |request: &ServerRequest, mut response: Response| -> Result<Response, ErrorResponse> {
+            if request.uri().path() != self.path {
+                let mut error = ErrorResponse::new(None);
+                *error.status_mut() = StatusCode::NOT_FOUND;
+                return Err(error);
+            }
+            // A client must get back the protocol it requested.
+            if let Some(protocol) = request.headers().get(EARLY_DATA_HEADER) {
+                if let Ok(data) = URL_SAFE_NO_PAD.decode(protocol.as_bytes()) {
+                    early_data = data;
+                    response.headers_mut().insert(EARLY_DATA_HEADER, protocol.clone());
+                }
+            }
+            Ok(response)
+        };
+        let ws = tokio_tungstenite::accept_hdr_async(stream, callback).await.map_err(ws_error)?;
+        Ok(Box::new(WsStream::new(WsState::Open(ws), early_data)))
+    }
+}
+
+type Handshake = Pin<Box<dyn Future<Output = io::Result<WebSocketStream<BoxStream>>> + Send>>;
+
+enum WsState {
+    /// The client waits for the early data of the first write.
+    Connecting(Option<(BoxStream, Request, usize)>),
+    Handshaking(Handshake),
+    Open(WebSocketStream<BoxStream>),
+}
+
+/// Carry a stream in WebSocket binary messages.
+struct WsStream {
+    state: WsState,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// Size of the early data sent with the handshake.
+    early_written: usize,
+    /// Size of the data in a message which is not flushed yet.
+    unflushed: Option<usize>,
+}
+
+impl WsStream {
+    fn new(state: WsState, early_data: Vec<u8>) -> Self {
+        Self {
+            state,
+            read_buf: early_data,
+            read_pos: 0,
+            early_written: 0,
+            unflushed: None,
+        }
+    }
+
+    /// Start the client handshake with the early data, return the size of it.
+    fn start_handshake(&mut self, data: &[u8]) -> usize {
+        let (stream, mut request, max_early_data) = match &mut self.state {
+            WsState::Connecting(pending) => pending.take().expect("The handshake is not started"),
+            _ => return 0,
+        };
+        let size = data.len().min(max_early_data);
+        if size > 0 {
+            let protocol = HeaderValue::from_str(&URL_SAFE_NO_PAD.encode(&data[..size])).expect("Base64 is a valid header");
+            request.headers_mut().insert(EARLY_DATA_HEADER, protocol);
+        }
+        self.state = WsState::Handshaking(Box::pin(async move {
+            let (ws, _response) = tokio_tungstenite::client_async(request, stream).await.map_err(ws_error)?;
+            Ok(ws)
+        }));
+        size
+    }
+
+    fn poll_open(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match &mut self.state {
+                WsState::Connecting(_) => {
+                    self.start_handshake(&[]);
+                }
+                WsState::Handshaking(handshake) => {
+                    let ws = ready!(handshake.as_mut().poll(cx))?;
+                    self.state = WsState::Open(ws);
+                }
+                WsState::Open(_) => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+
+    fn ws(&mut self) -> Pin<&mut WebSocketStream<BoxStream>> {
+        match &mut self.state {
+            WsState::Open(ws) => Pin::new(ws),
+            _ => unreachable!("The WebSocket is not open"),
+        }
+    }
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let size = buf.remaining().min(this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + size]);
+                this.read_pos += size;
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.poll_open(cx))?;
+            match ready!(this.ws().poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.read_buf = data,
+                Some(Ok(Message::Text(text))) => this.read_buf = text.into_bytes(),
+                Some(Ok(Message::Close(_))) | Some(Err(WsError::ConnectionClosed)) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(ws_error(e))),
+            }
+            this.read_pos = 0;
+        }
+    }
+}
+
+impl AsyncWrite for WsStream {
+    /// The data is sent in one message, and the write is done when the message is flushed.
+    /// A write retried after `Pending` may come with another buffer, the sent count is kept within it.
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if let WsState::Connecting(_) = this.state {
+            this.early_written = this.start_handshake(buf);
+        }
+        ready!(this.poll_open(cx))?;
+        if this.early_written > 0 {
+            return Poll::Ready(Ok(std::mem::take(&mut this.early_written).min(buf.len())));
+        }
+        if this.unflushed.is_none() {
+            ready!(this.ws().poll_ready(cx)).map_err(ws_error)?;
+            this.ws().start_send(Message::Binary(buf.to_vec())).map_err(ws_error)?;
+            this.unflushed = Some(buf.len());
+        }
+        ready!(this.ws().poll_flush(cx)).map_err(ws_error)?;
+        Poll::Ready(Ok(this.unflushed.take().unwrap_or_default().min(buf.len())))
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        let data: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+        self.poll_write(cx, &data)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.state {
+            WsState::Open(_) => this.ws().poll_flush(cx).map_err(ws_error),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.state {
+            WsState::Open(_) => this.ws().poll_close(cx).map_err(ws_error),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+fn ws_error(error: WsError) -> Error {
+    match error {
+        WsError::Io(e) => e,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => Error::from(ErrorKind::BrokenPipe),
+        e => Error::new(ErrorKind::InvalidData, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::pin::Pin;
+    use std::task::Poll;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    use crate::core::profile::WsConfig;
+    use crate::net::transport::ws::{WsClient, WsServer};
+
+    #[tokio::test]
+    async fn early_data_and_path() {
+        let config = WsConfig {
+            path: Some("/ws".to_string()),
+            headers: Some(HashMap::from([("User-Agent".to_string(), "touch".to_string())])),
+            max_early_data: Some(4),
+            ..WsConfig::default()
+        };
+        let server = WsServer::new(&config);
+        let client = WsClient::new(&config, "::1").unwrap();
+
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let server_task = tokio::spawn(async move {
+            let mut stream = server.accept(Box::new(server_io)).await.unwrap();
+            let mut buf = [0u8; 10];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+        let mut stream = client.connect(Box::new(client_io));
+        // The first 4 bytes go in the request header.
+        stream.write_all(b"0123456789").await.unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"0123456789");
+        server_task.await.unwrap();
+
+        let other_path = WsClient::new(&WsConfig::default(), "example.com").unwrap();
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let server = WsServer::new(&config);
+        tokio::spawn(async move { server.accept(Box::new(server_io)).await });
+        let mut stream = other_path.connect(Box::new(client_io));
+        assert!(stream.write_all(b"data").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn retry_write_with_shorter_buffer() {
+        let config = WsConfig::default();
+        let server = WsServer::new(&config);
+        let client = WsClient::new(&config, "::1").unwrap();
+
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let (start_read, wait_read) = tokio::sync::oneshot::channel();
+        let server_task = tokio::spawn(async move {
+            let mut stream = server.accept(Box::new(server_io)).await.unwrap();
+            wait_read.await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            stream.read_exact(&mut buf).await.unwrap();
+        });
+        let mut stream = client.connect(Box::new(client_io));
+        // The message doesn't fit in the pipe, so the write waits for the flush.
+        let large = vec![7u8; 4096];
+        for _ in 0..20 {
+            let poll = std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut stream).poll_write(cx, &large))).await;
+            assert!(poll.is_pending());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        start_read.send(()).unwrap();
+        let written = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_write(cx, b"ab")).await.unwrap();
+        assert!(written <= 2);
+        server_task.await.unwrap();
+    }
+}
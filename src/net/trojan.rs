@@ -0,0 +1,458 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use sha2::{Digest, Sha224};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::core::profile::{BaseActiveConfig, BasePassiveConfig};
+use crate::net::dialer::Dialer;
+use crate::net::fallback::{fall_back, read_expected, Fallback};
+use crate::net::listener::Listener;
+use crate::net::proxy::{
+    relay, InputProxy, OutProxyStarter, OutputProxy, ProxyInfo, ProxyReader, ProxyWriter, UdpProxyReader, UdpProxyWriter,
+};
+use crate::net::stream::{split, BoxStream, StreamReadHalf, StreamWriteHalf};
+use crate::net::transport::{ClientTransport, ServerTransport};
+use crate::net::AddressType;
+use crate::socks::socks5::Socks5;
+use crate::util::address::Address;
+
+const CRLF: &[u8] = b"\r\n";
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const MAX_UDP_PAYLOAD: usize = u16::MAX as usize;
+
+/// Hex SHA-224 of the password, followed by CRLF. A Trojan request starts with it.
+fn auth_line(password: &str) -> Vec<u8> {
+    let mut line: Vec<u8> =
+        Sha224::digest(password.as_bytes()).iter().flat_map(|byte| format!("{:02x}", byte).into_bytes()).collect();
+    line.extend_from_slice(CRLF);
+    line
+}
+
+fn request_header(auth: &[u8], cmd: u8, proxy_info: &ProxyInfo) -> io::Result<Vec<u8>> {
+    let addr_arr = Socks5::socks5_addr_arr(&proxy_info.address, proxy_info.port, &proxy_info.address_type)?;
+    Ok([auth, &[cmd], &addr_arr, CRLF].concat())
+}
+
+/// A UDP packet in the stream: socks5 address, length, CRLF and the payload.
+fn encode_udp_packet(packet: &mut Vec<u8>, data: &[u8], proxy_info: &ProxyInfo) -> io::Result<()> {
+    packet.extend_from_slice(&Socks5::socks5_addr_arr(
+        &proxy_info.address,
+        proxy_info.port,
+        &proxy_info.address_type,
+    )?);
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(CRLF);
+    packet.extend_from_slice(data);
+    Ok(())
+}
+
+/// Read a UDP packet into the buffer, return its address and the size of the payload.
+async fn read_udp_packet<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> io::Result<(ProxyInfo, usize)> {
+    let proxy_info = Socks5::read_addr(reader).await?;
+    let size = reader.read_u16().await? as usize;
+    read_crlf(reader).await?;
+    reader.read_exact(&mut buf[..size]).await?;
+    Ok((proxy_info, size))
+}
+
+async fn read_crlf<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<()> {
+    let mut crlf = [0u8; 2];
+    reader.read_exact(&mut crlf).await?;
+    if crlf != CRLF {
+        return Err(Error::new(ErrorKind::InvalidData, "Trojan request misses CRLF"));
+    }
+    Ok(())
+}
+
+//------------------------------TROJAN_OUT_PROXY-----------------------------------------
+
+pub struct TrojanActive {
+    host: String,
+    port: u16,
+    auth: Arc<[u8]>,
+    dialer: Dialer,
+    transport: ClientTransport,
+}
+
+impl TrojanActive {
+    pub fn new(active: &BaseActiveConfig, dialer: Dialer) -> io::Result<Self> {
+        let password =
+            active.password.as_deref().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Trojan must have a password"))?;
+        Ok(Self {
+            host: active.remote_host.clone(),
+            port: active.remote_port,
+            auth: auth_line(password).into(),
+            dialer,
+            transport: ClientTransport::new(active.transport.as_ref(), &active.remote_host, active.remote_port)?,
+        })
+    }
+}
+
+impl OutputProxy for TrojanActive {
+    fn gen_connector(&mut self) -> io::Result<Box<dyn OutProxyStarter>> {
+        Ok(Box::new(TrojanOutProxyStarter {
+            host: self.host.clone(),
+            port: self.port,
+            auth: self.auth.clone(),
+            dialer: self.dialer.clone(),
+            transport: self.transport.clone(),
+        }))
+    }
+}
+
+struct TrojanOutProxyStarter {
+    host: String,
+    port: u16,
+    auth: Arc<[u8]>,
+    dialer: Dialer,
+    transport: ClientTransport,
+}
+
+impl TrojanOutProxyStarter {
+    async fn connect(&self) -> io::Result<(StreamReadHalf, StreamWriteHalf)> {
+        Ok(split(
+            self.transport.connect(self.dialer.dial(&self.host, self.port).await?).await?,
+        ))
+    }
+}
+
+#[async_trait]
+impl OutProxyStarter for TrojanOutProxyStarter {
+    async fn new_connection(&mut self, proxy_info: ProxyInfo) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>)> {
+        debug!(
+            "new connect to {}",
+            Address::ip_str(&proxy_info.address, proxy_info.port, &proxy_info.address_type)
+        );
+        let (read_half, mut write_half) = self.connect().await?;
+        // Sent at once, the remote may speak first, such as SMTP and SSH.
+        write_half.write_all(&request_header(&self.auth, CMD_CONNECT, &proxy_info)?).await?;
+        let reader = TrojanReader {
+            read_half,
+            buffer: vec![0u8; 32 * 1024],
+        };
+        let writer = TrojanWriter { write_half };
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+
+    async fn new_udp(&mut self) -> io::Result<(Box<dyn UdpProxyReader>, Box<dyn UdpProxyWriter>)> {
+        let (read_half, write_half) = self.connect().await?;
+        // The address of the request is not used, every packet has its own.
+        let unspecified = ProxyInfo {
+            address_type: AddressType::IPv4,
+            address: vec![0u8; 4],
+            port: 0,
+        };
+        let reader = TrojanUdpReader {
+            read_half,
+            buffer: vec![0u8; MAX_UDP_PAYLOAD],
+        };
+        let writer = TrojanUdpWriter {
+            write_half,
+            header: Some(request_header(&self.auth, CMD_UDP_ASSOCIATE, &unspecified)?),
+            packet: vec![],
+        };
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+struct TrojanReader {
+    read_half: StreamReadHalf,
+    buffer: Vec<u8>,
+}
+
+#[async_trait]
+impl ProxyReader for TrojanReader {
+    async fn read(&mut self) -> io::Result<&mut [u8]> {
+        let size = self.read_half.read(&mut self.buffer).await?;
+        Ok(&mut self.buffer[..size])
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct TrojanWriter {
+    write_half: StreamWriteHalf,
+}
+
+#[async_trait]
+impl ProxyWriter for TrojanWriter {
+    async fn write(&mut self, raw_data: &mut [u8]) -> io::Result<()> {
+        self.write_half.write_all(raw_data).await
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        self.write_half.shutdown().await
+    }
+}
+
+struct TrojanUdpReader {
+    read_half: StreamReadHalf,
+    buffer: Vec<u8>,
+}
+
+#[async_trait]
+impl UdpProxyReader for TrojanUdpReader {
+    async fn read_from(&mut self) -> io::Result<(&mut [u8], ProxyInfo)> {
+        let (proxy_info, size) = read_udp_packet(&mut self.read_half, &mut self.buffer).await?;
+        Ok((&mut self.buffer[..size], proxy_info))
+    }
+}
+
+struct TrojanUdpWriter {
+    write_half: StreamWriteHalf,
+    header: Option<Vec<u8>>,
+    packet: Vec<u8>,
+}
+
+#[async_trait]
+impl UdpProxyWriter for TrojanUdpWriter {
+    async fn write_to(&mut self, data: &[u8], target: &ProxyInfo) -> io::Result<()> {
+        if data.len() > MAX_UDP_PAYLOAD {
+            return Err(Error::new(ErrorKind::InvalidInput, "UDP packet is too large"));
+        }
+        self.packet.clear();
+        if let Some(header) = &self.header {
+            self.packet.extend_from_slice(header);
+        }
+        // The header is kept for the next packet if this one can't be encoded.
+        encode_udp_packet(&mut self.packet, data, target)?;
+        self.header = None;
+        self.write_half.write_all(&self.packet).await
+    }
+}
+//<--<--<--<--<--<--<--<--<--<--<--<--TROJAN_OUT_PROXY--<--<--<--<--<--<--<--<--<--<--<--<
+
+//>-->-->-->-->-->-->-->-->-->-->-->--TROJAN_INPUT_PROXY-->-->-->-->-->-->-->-->-->-->-->-->
+
+pub struct TrojanPassive {
+    tcp_listener: Listener,
+    transport: ServerTransport,
+    auth: Arc<[u8]>,
+    /// Where the connections go when they are not Trojan.
+    fallback: Option<Arc<Fallback>>,
+    out_proxy: Box<dyn OutputProxy + Send>,
+}
+
+impl TrojanPassive {
+    pub async fn new(passive: &BasePassiveConfig, out_proxy: Box<dyn OutputProxy + Send>) -> io::Result<Self> {
+        let password =
+            passive.password.as_deref().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Trojan must have a password"))?;
+        let fallback = passive.fallback.as_deref().map(Fallback::parse).transpose()?;
+        let tcp_listener = Listener::bind(passive.local_host.as_slice(), passive.local_port, passive.listen.as_ref()).await?;
+        info!("Trojan bind in {:?}", tcp_listener.local_addrs());
+        Ok(Self {
+            tcp_listener,
+            transport: ServerTransport::new(passive.transport.as_ref())?,
+            auth: auth_line(password).into(),
+            fallback: fallback.map(Arc::new),
+            out_proxy,
+        })
+    }
+}
+
+#[async_trait]
+impl InputProxy for TrojanPassive {
+    async fn start(&mut self) -> io::Result<()> {
+        info!("Trojan start listen");
+        loop {
+            let (tcp_stream, _addr) = self.tcp_listener.accept().await?;
+            let starter = match self.out_proxy.gen_connector() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            let transport = self.transport.clone();
+            let auth = self.auth.clone();
+            let fallback = self.fallback.clone();
+            tokio::task::spawn(async move {
+                let result = match transport.accept(tcp_stream).await {
+                    Ok(stream) => new_trojan_proxy(stream, starter, &auth, fallback.as_deref()).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    error!("Trojan input proxy error. {}", e)
+                };
+            });
+        }
+    }
+}
+
+async fn new_trojan_proxy(
+    mut stream: BoxStream,
+    mut starter: Box<dyn OutProxyStarter>,
+    auth: &[u8],
+    fallback: Option<&Fallback>,
+) -> io::Result<()> {
+    if let Some(head) = read_expected(&mut stream, auth).await? {
+        return fall_back(stream, &head, fallback).await;
+    }
+    let cmd = stream.read_u8().await?;
+    let proxy_info = Socks5::read_addr(&mut stream).await?;
+    read_crlf(&mut stream).await?;
+    match cmd {
+        CMD_CONNECT => {
+            let (mut out_reader, mut out_writer) = starter.new_connection(proxy_info).await?;
+            relay(stream, &mut *out_reader, &mut *out_writer).await;
+            Ok(())
+        }
+        CMD_UDP_ASSOCIATE => {
+            let (udp_reader, udp_writer) = starter.new_udp().await?;
+            let (read_half, write_half) = split(stream);
+            tokio::select! {
+                result = udp_upload(read_half, udp_writer) => result,
+                result = udp_download(write_half, udp_reader) => result,
+            }
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unsupported Trojan command {}", cmd),
+        )),
+    }
+}
+
+async fn udp_upload(mut read_half: StreamReadHalf, mut udp_writer: Box<dyn UdpProxyWriter>) -> io::Result<()> {
+    let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+    loop {
+        let (target, size) = match read_udp_packet(&mut read_half, &mut buf).await {
+            Ok(packet) => packet,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        // The association carries other destinations, one which fails doesn't end it.
+        if let Err(e) = udp_writer.write_to(&buf[..size], &target).await {
+            warn!(
+                "Trojan UDP to {} error. {}",
+                Address::ip_str(&target.address, target.port, &target.address_type),
+                e
+            );
+        }
+    }
+}
+
+async fn udp_download(mut write_half: StreamWriteHalf, mut udp_reader: Box<dyn UdpProxyReader>) -> io::Result<()> {
+    let mut packet = vec![];
+    loop {
+        let (data, source) = udp_reader.read_from().await?;
+        packet.clear();
+        encode_udp_packet(&mut packet, data, &source)?;
+        write_half.write_all(&packet).await?;
+    }
+}
+//<--<--<--<--<--<--<--<--<--<--<--<--TROJAN_INPUT_PROXY--<--<--<--<--<--<--<--<--<--<--<--<
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use crate::net::dialer::Dialer;
+    use crate::net::fallback::Fallback;
+    use crate::net::proxy::{OutputProxy, ProxyInfo};
+    use crate::net::raw::RawActive;
+    use crate::net::transport::ClientTransport;
+    use crate::net::trojan::{auth_line, new_trojan_proxy, request_header, TrojanOutProxyStarter, CMD_CONNECT};
+    use crate::net::AddressType;
+
+    #[test]
+    fn request_format() {
+        let auth = auth_line("password");
+        // SHA-224 of "password".
+        assert_eq!(&auth[..], b"d63dc919e201d7bc4c825630d2cf25fdc93d4b2f0d46706d29038d01\r\n");
+        let info = ProxyInfo {
+            address_type: AddressType::Domain,
+            address: b"a.com".to_vec(),
+            port: 443,
+        };
+        let header = request_header(&auth, CMD_CONNECT, &info).unwrap();
+        assert_eq!(&header[58..], b"\x01\x03\x05a.com\x01\xbb\r\n");
+    }
+
+    /// Serve one connection of the Trojan input with the raw output, or the fallback.
+    async fn serve_one(fallback: Option<Fallback>) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let starter = RawActive::new(None, Dialer::default()).unwrap().gen_connector().unwrap();
+            new_trojan_proxy(Box::new(stream), starter, &auth_line("test"), fallback.as_ref()).await
+        });
+        addr
+    }
+
+    fn starter(addr: std::net::SocketAddr) -> TrojanOutProxyStarter {
+        TrojanOutProxyStarter {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            auth: auth_line("test").into(),
+            dialer: Dialer::default(),
+            transport: ClientTransport::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_udp_and_fallback() {
+        use crate::net::proxy::OutProxyStarter;
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = echo.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let size = stream.read(&mut buf).await.unwrap();
+                    stream.write_all(&buf[..size]).await.unwrap();
+                });
+            }
+        });
+
+        // TCP through the input and the output.
+        let mut trojan = starter(serve_one(None).await);
+        let (mut reader, mut writer) = trojan.new_connection(ProxyInfo::from(echo_addr)).await.unwrap();
+        writer.write(&mut b"ping".to_vec()).await.unwrap();
+        assert_eq!(reader.read().await.unwrap(), b"ping");
+
+        // A server which speaks first gets the request before any data of the client.
+        let banner = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let banner_addr = banner.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = banner.accept().await.unwrap();
+            stream.write_all(b"220 ready").await.unwrap();
+        });
+        let mut trojan = starter(serve_one(None).await);
+        let (mut reader, _writer) = trojan.new_connection(ProxyInfo::from(banner_addr)).await.unwrap();
+        assert_eq!(reader.read().await.unwrap(), b"220 ready");
+
+        // UDP over the Trojan stream.
+        let udp_echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_addr = udp_echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (size, peer) = udp_echo.recv_from(&mut buf).await.unwrap();
+            udp_echo.send_to(&buf[..size], peer).await.unwrap();
+        });
+        let mut trojan = starter(serve_one(None).await);
+        let (mut reader, mut writer) = trojan.new_udp().await.unwrap();
+        // A packet the input can't send, broadcast isn't allowed, doesn't end the association.
+        let broadcast = std::net::SocketAddr::from(([255, 255, 255, 255], 9));
+        writer.write_to(b"lost", &ProxyInfo::from(broadcast)).await.unwrap();
+        writer.write_to(b"hello", &ProxyInfo::from(udp_addr)).await.unwrap();
+        let (data, source) = reader.read_from().await.unwrap();
+        assert_eq!(data, b"hello");
+        assert_eq!(source.port, udp_addr.port());
+
+        // A wrong password goes to the fallback.
+        let fallback = Some(Fallback::parse(&format!("127.0.0.1:{}", echo_addr.port())).unwrap());
+        let mut stream = tokio::net::TcpStream::connect(serve_one(fallback).await).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut buf = [0u8; 18];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"GET / HTTP/1.1\r\n\r\n");
+    }
+}
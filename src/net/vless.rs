@@ -0,0 +1,561 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::{debug, error, info};
+use lru::LruCache;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::core::profile::{BaseActiveConfig, BasePassiveConfig};
+use crate::net::dialer::Dialer;
+use crate::net::fallback::{fall_back, read_expected, Fallback};
+use crate::net::listener::Listener;
+use crate::net::proxy::{
+    relay, InputProxy, OutProxyStarter, OutputProxy, ProxyInfo, ProxyReader, ProxyWriter, UdpProxyReader, UdpProxyWriter,
+};
+use crate::net::stream::{split, BoxStream, StreamReadHalf, StreamWriteHalf};
+use crate::net::transport::{ClientTransport, ServerTransport};
+use crate::net::AddressType;
+use crate::util::address::Address;
+
+const VERSION: u8 = 0;
+const CMD_TCP: u8 = 0x01;
+const CMD_UDP: u8 = 0x02;
+const MAX_UDP_PAYLOAD: usize = u16::MAX as usize;
+/// Packets waiting for the UDP reader of an output.
+const UDP_CHANNEL_SIZE: usize = 64;
+/// Connections of a UDP association, the least recently used one is closed for a new destination.
+const MAX_UDP_SESSIONS: usize = 32;
+/// A UDP connection is closed when nothing is sent to its destination for so long.
+const UDP_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Parse a UUID such as `b831381d-6324-4d53-ad4f-8cda48b30811`.
+pub fn parse_uuid(uuid: &str) -> io::Result<[u8; 16]> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid UUID {}", uuid));
+    let hex: Vec<u8> = uuid.bytes().filter(|c| *c != b'-').collect();
+    if hex.len() != 32 {
+        return Err(invalid());
+    }
+    let mut bytes = [0u8; 16];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(bytes)
+}
+
+/// VLESS and VMess put the port before the address, and number the address types differently from socks5.
+/// A domain longer than 255 bytes is rejected.
+pub fn encode_addr(buf: &mut Vec<u8>, proxy_info: &ProxyInfo) -> io::Result<()> {
+    let address_len = proxy_info.address.len();
+    if proxy_info.address_type == AddressType::Domain && address_len > u8::MAX as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("The domain is {} bytes, longer than 255", address_len),
+        ));
+    }
+    buf.extend_from_slice(&proxy_info.port.to_be_bytes());
+    match proxy_info.address_type {
+        AddressType::IPv4 => buf.push(0x01),
+        AddressType::Domain => buf.extend_from_slice(&[0x02, address_len as u8]),
+        AddressType::IPv6 => buf.push(0x03),
+    }
+    buf.extend_from_slice(&proxy_info.address);
+    Ok(())
+}
+
+async fn read_addr<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<ProxyInfo> {
+    let port = reader.read_u16().await?;
+    let (address_type, address_len) = match reader.read_u8().await? {
+        0x01 => (AddressType::IPv4, 4),
+        0x02 => (AddressType::Domain, reader.read_u8().await? as usize),
+        0x03 => (AddressType::IPv6, 16),
+        n => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown VLESS address type {}", n),
+            ))
+        }
+    };
+    let mut address = vec![0u8; address_len];
+    reader.read_exact(&mut address).await?;
+    Ok(ProxyInfo {
+        address_type,
+        address,
+        port,
+    })
+}
+
+/// Version, UUID, no addons, the command and the address.
+fn request_header(uuid: &[u8; 16], cmd: u8, proxy_info: &ProxyInfo) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(1 + 16 + 2 + 4 + proxy_info.address.len());
+    header.push(VERSION);
+    header.extend_from_slice(uuid);
+    header.extend_from_slice(&[0, cmd]);
+    encode_addr(&mut header, proxy_info)?;
+    Ok(header)
+}
+
+/// The response starts with the version and the addons, which are skipped.
+async fn read_response_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<()> {
+    let version = reader.read_u8().await?;
+    if version != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unknown VLESS version {}", version),
+        ));
+    }
+    skip_addons(reader).await
+}
+
+async fn skip_addons<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<()> {
+    let addons_len = reader.read_u8().await? as usize;
+    let mut addons = vec![0u8; addons_len];
+    reader.read_exact(&mut addons).await?;
+    Ok(())
+}
+
+/// UDP packets in the stream are prefixed with their length.
+fn encode_udp_packet(packet: &mut Vec<u8>, data: &[u8]) -> io::Result<()> {
+    if data.len() > MAX_UDP_PAYLOAD {
+        return Err(Error::new(ErrorKind::InvalidInput, "UDP packet is too large"));
+    }
+    packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    packet.extend_from_slice(data);
+    Ok(())
+}
+
+async fn read_udp_packet<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let size = reader.read_u16().await? as usize;
+    reader.read_exact(&mut buf[..size]).await?;
+    Ok(size)
+}
+
+//------------------------------VLESS_OUT_PROXY-----------------------------------------
+
+pub struct VlessActive {
+    starter: VlessOutProxyStarter,
+}
+
+impl VlessActive {
+    pub fn new(active: &BaseActiveConfig, dialer: Dialer) -> io::Result<Self> {
+        let uuid = active
+            .password
+            .as_deref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "VLESS must have a UUID as the password"))?;
+        Ok(Self {
+            starter: VlessOutProxyStarter {
+                host: active.remote_host.clone(),
+                port: active.remote_port,
+                uuid: parse_uuid(uuid)?,
+                dialer,
+                transport: ClientTransport::new(active.transport.as_ref(), &active.remote_host, active.remote_port)?,
+            },
+        })
+    }
+}
+
+impl OutputProxy for VlessActive {
+    fn gen_connector(&mut self) -> io::Result<Box<dyn OutProxyStarter>> {
+        Ok(Box::new(self.starter.clone()))
+    }
+}
+
+#[derive(Clone)]
+struct VlessOutProxyStarter {
+    host: String,
+    port: u16,
+    uuid: [u8; 16],
+    dialer: Dialer,
+    transport: ClientTransport,
+}
+
+impl VlessOutProxyStarter {
+    async fn connect(&self) -> io::Result<(StreamReadHalf, StreamWriteHalf)> {
+        Ok(split(
+            self.transport.connect(self.dialer.dial(&self.host, self.port).await?).await?,
+        ))
+    }
+}
+
+#[async_trait]
+impl OutProxyStarter for VlessOutProxyStarter {
+    async fn new_connection(&mut self, proxy_info: ProxyInfo) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>)> {
+        debug!(
+            "new connect to {}",
+            Address::ip_str(&proxy_info.address, proxy_info.port, &proxy_info.address_type)
+        );
+        let (read_half, mut write_half) = self.connect().await?;
+        // Sent at once, the remote may speak first, such as SMTP and SSH.
+        let header = request_header(&self.uuid, CMD_TCP, &proxy_info)?;
+        write_half.write_all(&header).await?;
+        let reader = VlessReader {
+            read_half,
+            buffer: vec![0u8; 32 * 1024],
+            response_read: false,
+        };
+        let writer = VlessWriter { write_half };
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+
+    /// A VLESS UDP connection only has one destination, so there is a connection for each destination.
+    async fn new_udp(&mut self) -> io::Result<(Box<dyn UdpProxyReader>, Box<dyn UdpProxyWriter>)> {
+        let (sender, receiver) = mpsc::channel(UDP_CHANNEL_SIZE);
+        let reader = VlessUdpReader { receiver, data: vec![] };
+        let writer = VlessUdpWriter {
+            starter: self.clone(),
+            sessions: LruCache::new(MAX_UDP_SESSIONS),
+            sender,
+            packet: vec![],
+        };
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+struct VlessReader {
+    read_half: StreamReadHalf,
+    buffer: Vec<u8>,
+    response_read: bool,
+}
+
+#[async_trait]
+impl ProxyReader for VlessReader {
+    async fn read(&mut self) -> io::Result<&mut [u8]> {
+        if !self.response_read {
+            read_response_header(&mut self.read_half).await?;
+            self.response_read = true;
+        }
+        let size = self.read_half.read(&mut self.buffer).await?;
+        Ok(&mut self.buffer[..size])
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct VlessWriter {
+    write_half: StreamWriteHalf,
+}
+
+#[async_trait]
+impl ProxyWriter for VlessWriter {
+    async fn write(&mut self, raw_data: &mut [u8]) -> io::Result<()> {
+        self.write_half.write_all(raw_data).await
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        self.write_half.shutdown().await
+    }
+}
+
+/// Packets of all the connections of a UDP association.
+struct VlessUdpReader {
+    receiver: mpsc::Receiver<(Vec<u8>, ProxyInfo)>,
+    data: Vec<u8>,
+}
+
+#[async_trait]
+impl UdpProxyReader for VlessUdpReader {
+    async fn read_from(&mut self) -> io::Result<(&mut [u8], ProxyInfo)> {
+        let (data, source) = self.receiver.recv().await.ok_or_else(|| Error::from(ErrorKind::BrokenPipe))?;
+        self.data = data;
+        Ok((&mut self.data, source))
+    }
+}
+
+struct VlessUdpWriter {
+    starter: VlessOutProxyStarter,
+    /// Connections by their destinations.
+    sessions: LruCache<String, UdpSession>,
+    sender: mpsc::Sender<(Vec<u8>, ProxyInfo)>,
+    packet: Vec<u8>,
+}
+
+/// The connection of a destination, it is closed when this is dropped.
+struct UdpSession {
+    write_half: StreamWriteHalf,
+    reader: JoinHandle<()>,
+    last_write: Instant,
+}
+
+impl Drop for UdpSession {
+    fn drop(&mut self) {
+        // The read half is dropped with the task, the connection is closed with both halves.
+        self.reader.abort();
+    }
+}
+
+#[async_trait]
+impl UdpProxyWriter for VlessUdpWriter {
+    async fn write_to(&mut self, data: &[u8], target: &ProxyInfo) -> io::Result<()> {
+        let now = Instant::now();
+        while let Some((_, session)) = self.sessions.peek_lru() {
+            if now.duration_since(session.last_write) < UDP_SESSION_IDLE_TIMEOUT {
+                break;
+            }
+            self.sessions.pop_lru();
+        }
+        let key = Address::ip_str(&target.address, target.port, &target.address_type);
+        self.packet.clear();
+        if !self.sessions.contains(&key) {
+            self.packet = request_header(&self.starter.uuid, CMD_UDP, target)?;
+            let (read_half, write_half) = self.starter.connect().await?;
+            let session = UdpSession {
+                write_half,
+                reader: tokio::task::spawn(udp_session(read_half, target.clone(), self.sender.clone())),
+                last_write: now,
+            };
+            // The least recently used session is dropped when it is full.
+            self.sessions.put(key.clone(), session);
+        }
+        encode_udp_packet(&mut self.packet, data)?;
+        let session = self.sessions.get_mut(&key).expect("The session is added");
+        session.last_write = now;
+        if let Err(e) = session.write_half.write_all(&self.packet).await {
+            self.sessions.pop(&key);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Read the packets of a UDP connection until it is closed or the reader of the association is dropped.
+async fn udp_session(mut read_half: StreamReadHalf, source: ProxyInfo, sender: mpsc::Sender<(Vec<u8>, ProxyInfo)>) {
+    tokio::select! {
+        result = read_udp_session(&mut read_half, &source, &sender) => {
+            if let Err(e) = result {
+                debug!("VLESS UDP connection closed. {}", e);
+            }
+        }
+        _ = sender.closed() => {}
+    }
+}
+
+async fn read_udp_session(
+    read_half: &mut StreamReadHalf,
+    source: &ProxyInfo,
+    sender: &mpsc::Sender<(Vec<u8>, ProxyInfo)>,
+) -> io::Result<()> {
+    read_response_header(read_half).await?;
+    let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+    loop {
+        let size = read_udp_packet(read_half, &mut buf).await?;
+        if sender.send((buf[..size].to_vec(), source.clone())).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+//<--<--<--<--<--<--<--<--<--<--<--<--VLESS_OUT_PROXY--<--<--<--<--<--<--<--<--<--<--<--<
+
+//>-->-->-->-->-->-->-->-->-->-->-->--VLESS_INPUT_PROXY-->-->-->-->-->-->-->-->-->-->-->-->
+
+pub struct VlessPassive {
+    tcp_listener: Listener,
+    transport: ServerTransport,
+    /// The version and the UUID, a request starts with them.
+    auth: Arc<[u8]>,
+    fallback: Option<Arc<Fallback>>,
+    out_proxy: Box<dyn OutputProxy + Send>,
+}
+
+impl VlessPassive {
+    pub async fn new(passive: &BasePassiveConfig, out_proxy: Box<dyn OutputProxy + Send>) -> io::Result<Self> {
+        let uuid = passive
+            .password
+            .as_deref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "VLESS must have a UUID as the password"))?;
+        let auth = [&[VERSION][..], &parse_uuid(uuid)?].concat();
+        let fallback = passive.fallback.as_deref().map(Fallback::parse).transpose()?;
+        let tcp_listener = Listener::bind(passive.local_host.as_slice(), passive.local_port, passive.listen.as_ref()).await?;
+        info!("VLESS bind in {:?}", tcp_listener.local_addrs());
+        Ok(Self {
+            tcp_listener,
+            transport: ServerTransport::new(passive.transport.as_ref())?,
+            auth: auth.into(),
+            fallback: fallback.map(Arc::new),
+            out_proxy,
+        })
+    }
+}
+
+#[async_trait]
+impl InputProxy for VlessPassive {
+    async fn start(&mut self) -> io::Result<()> {
+        info!("VLESS start listen");
+        loop {
+            let (tcp_stream, _addr) = self.tcp_listener.accept().await?;
+            let starter = match self.out_proxy.gen_connector() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            let transport = self.transport.clone();
+            let auth = self.auth.clone();
+            let fallback = self.fallback.clone();
+            tokio::task::spawn(async move {
+                let result = match transport.accept(tcp_stream).await {
+                    Ok(stream) => new_vless_proxy(stream, starter, &auth, fallback.as_deref()).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    error!("VLESS input proxy error. {}", e)
+                };
+            });
+        }
+    }
+}
+
+async fn new_vless_proxy(
+    mut stream: BoxStream,
+    mut starter: Box<dyn OutProxyStarter>,
+    auth: &[u8],
+    fallback: Option<&Fallback>,
+) -> io::Result<()> {
+    if let Some(head) = read_expected(&mut stream, auth).await? {
+        return fall_back(stream, &head, fallback).await;
+    }
+    skip_addons(&mut stream).await?;
+    let cmd = stream.read_u8().await?;
+    let proxy_info = read_addr(&mut stream).await?;
+    match cmd {
+        CMD_TCP => {
+            let (mut out_reader, mut out_writer) = starter.new_connection(proxy_info).await?;
+            stream.write_all(&[VERSION, 0]).await?;
+            relay(stream, &mut *out_reader, &mut *out_writer).await;
+            Ok(())
+        }
+        CMD_UDP => {
+            let (udp_reader, udp_writer) = starter.new_udp().await?;
+            stream.write_all(&[VERSION, 0]).await?;
+            let (read_half, write_half) = split(stream);
+            tokio::select! {
+                result = udp_upload(read_half, udp_writer, &proxy_info) => result,
+                result = udp_download(write_half, udp_reader) => result,
+            }
+        }
+        _ => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("Unsupported VLESS command {}", cmd),
+        )),
+    }
+}
+
+async fn udp_upload(
+    mut read_half: StreamReadHalf,
+    mut udp_writer: Box<dyn UdpProxyWriter>,
+    target: &ProxyInfo,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; MAX_UDP_PAYLOAD];
+    loop {
+        let size = match read_udp_packet(&mut read_half, &mut buf).await {
+            Ok(size) => size,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        udp_writer.write_to(&buf[..size], target).await?;
+    }
+}
+
+async fn udp_download(mut write_half: StreamWriteHalf, mut udp_reader: Box<dyn UdpProxyReader>) -> io::Result<()> {
+    let mut packet = vec![];
+    loop {
+        let (data, _source) = udp_reader.read_from().await?;
+        packet.clear();
+        encode_udp_packet(&mut packet, data)?;
+        write_half.write_all(&packet).await?;
+    }
+}
+//<--<--<--<--<--<--<--<--<--<--<--<--VLESS_INPUT_PROXY--<--<--<--<--<--<--<--<--<--<--<--<
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use crate::net::dialer::Dialer;
+    use crate::net::proxy::{OutProxyStarter, OutputProxy, ProxyInfo};
+    use crate::net::raw::RawActive;
+    use crate::net::transport::ClientTransport;
+    use crate::net::vless::{new_vless_proxy, parse_uuid, request_header, VlessOutProxyStarter, CMD_TCP, VERSION};
+    use crate::net::AddressType;
+
+    const UUID: &str = "b831381d-6324-4d53-ad4f-8cda48b30811";
+
+    #[test]
+    fn request_format() {
+        let uuid = parse_uuid(UUID).unwrap();
+        assert_eq!(uuid[..4], [0xb8, 0x31, 0x38, 0x1d]);
+        assert!(parse_uuid("b831381d-6324").is_err());
+        assert!(parse_uuid("x831381d-6324-4d53-ad4f-8cda48b30811").is_err());
+        let info = ProxyInfo {
+            address_type: AddressType::Domain,
+            address: b"a.com".to_vec(),
+            port: 443,
+        };
+        let header = request_header(&uuid, CMD_TCP, &info).unwrap();
+        assert_eq!(header[0], VERSION);
+        assert_eq!(&header[17..], b"\x00\x01\x01\xbb\x02\x05a.com");
+        let long = ProxyInfo {
+            address: vec![b'a'; 256],
+            ..info
+        };
+        assert_eq!(
+            request_header(&uuid, CMD_TCP, &long).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[tokio::test]
+    async fn tcp_and_udp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let auth = [&[VERSION][..], &parse_uuid(UUID).unwrap()].concat();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let starter = RawActive::new(None, Dialer::default()).unwrap().gen_connector().unwrap();
+                let auth = auth.clone();
+                tokio::spawn(async move { new_vless_proxy(Box::new(stream), starter, &auth, None).await });
+            }
+        });
+        let mut vless = VlessOutProxyStarter {
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            uuid: parse_uuid(UUID).unwrap(),
+            dialer: Dialer::default(),
+            transport: ClientTransport::default(),
+        };
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        let (mut reader, mut writer) = vless.new_connection(ProxyInfo::from(echo_addr)).await.unwrap();
+        writer.write(&mut b"ping".to_vec()).await.unwrap();
+        assert_eq!(reader.read().await.unwrap(), b"ping");
+
+        // Two destinations, each one has its own connection.
+        let (mut reader, mut writer) = vless.new_udp().await.unwrap();
+        for _ in 0..2 {
+            let udp_echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let udp_addr = udp_echo.local_addr().unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                let (size, peer) = udp_echo.recv_from(&mut buf).await.unwrap();
+                udp_echo.send_to(&buf[..size], peer).await.unwrap();
+            });
+            writer.write_to(b"hello", &ProxyInfo::from(udp_addr)).await.unwrap();
+            let (data, source) = reader.read_from().await.unwrap();
+            assert_eq!(data, b"hello");
+            assert_eq!(source.port, udp_addr.port());
+        }
+    }
+}
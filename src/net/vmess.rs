@@ -0,0 +1,349 @@
+use std::io;
+use std::io::{Error, ErrorKind};
+
+use async_trait::async_trait;
+use log::debug;
+use rand::{Rng, RngCore};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::core::profile::{VmessActiveConfig, VmessSecurity};
+use crate::encrypt::vmess::{
+    cmd_key, open_response_header, open_response_length, response_key, seal_header, BodyCipher, Security, SEALED_LENGTH_SIZE,
+    TAG_SIZE,
+};
+use crate::net::dialer::Dialer;
+use crate::net::proxy::{OutProxyStarter, OutputProxy, ProxyInfo, ProxyReader, ProxyWriter};
+use crate::net::ss_stream::change_error;
+use crate::net::stream::{split, StreamReadHalf, StreamWriteHalf};
+use crate::net::transport::ClientTransport;
+use crate::net::vless::{encode_addr, parse_uuid};
+use crate::util::address::Address;
+
+const VERSION: u8 = 1;
+const CMD_TCP: u8 = 0x01;
+const OPTION_CHUNK_STREAM: u8 = 0x01;
+const OPTION_CHUNK_MASKING: u8 = 0x04;
+const OPTION_GLOBAL_PADDING: u8 = 0x08;
+/// Data of a chunk, small enough for the 8 KiB buffers of v2ray with the tag, the length and the padding.
+const MAX_CHUNK_DATA: usize = 8192 - TAG_SIZE - 2 - 64;
+/// A chunk or the sealed response header.
+const READ_BUFFER_SIZE: usize = u16::MAX as usize + TAG_SIZE;
+
+/// The parts of a request chosen by the client.
+struct Request {
+    key: [u8; 16],
+    iv: [u8; 16],
+    /// The server returns it at the start of the response.
+    response_auth: u8,
+    option: u8,
+    security: Security,
+}
+
+impl Request {
+    fn new(option: u8, security: Security) -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            key: rng.gen(),
+            iv: rng.gen(),
+            response_auth: rng.gen(),
+            option,
+            security,
+        }
+    }
+}
+
+/// FNV-1a 32 of the header, at its end.
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193))
+}
+
+/// The header before it is sealed. The padding is less than 16 bytes.
+fn request_header(request: &Request, padding: &[u8], proxy_info: &ProxyInfo) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(64 + proxy_info.address.len());
+    header.push(VERSION);
+    header.extend_from_slice(&request.iv);
+    header.extend_from_slice(&request.key);
+    header.extend_from_slice(&[
+        request.response_auth,
+        request.option,
+        (padding.len() as u8) << 4 | request.security.id(),
+        0,
+        CMD_TCP,
+    ]);
+    encode_addr(&mut header, proxy_info)?;
+    header.extend_from_slice(padding);
+    let hash = fnv1a(&header);
+    header.extend_from_slice(&hash.to_be_bytes());
+    Ok(header)
+}
+
+//------------------------------VMESS_OUT_PROXY-----------------------------------------
+
+/// VMess with the AEAD header, alterId 0.
+pub struct VmessActive {
+    host: String,
+    port: u16,
+    cmd_key: [u8; 16],
+    security: Security,
+    option: u8,
+    dialer: Dialer,
+    transport: ClientTransport,
+}
+
+impl VmessActive {
+    pub fn new(active: &VmessActiveConfig, dialer: Dialer) -> io::Result<Self> {
+        let base = &active.base;
+        let uuid = base
+            .password
+            .as_deref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "VMess must have a UUID as the password"))?;
+        let security = match active.security.unwrap_or(VmessSecurity::Aes128Gcm) {
+            VmessSecurity::Aes128Gcm => Security::Aes128Gcm,
+            VmessSecurity::Chacha20Poly1305 => Security::Chacha20Poly1305,
+            VmessSecurity::None => Security::None,
+        };
+        let mut option = OPTION_CHUNK_STREAM;
+        if active.chunk_masking.unwrap_or(true) {
+            option |= OPTION_CHUNK_MASKING;
+            // There is nothing to hide without the encryption.
+            if active.global_padding.unwrap_or(false) && security != Security::None {
+                option |= OPTION_GLOBAL_PADDING;
+            }
+        }
+        Ok(Self {
+            host: base.remote_host.clone(),
+            port: base.remote_port,
+            cmd_key: cmd_key(&parse_uuid(uuid)?),
+            security,
+            option,
+            dialer,
+            transport: ClientTransport::new(base.transport.as_ref(), &base.remote_host, base.remote_port)?,
+        })
+    }
+}
+
+impl OutputProxy for VmessActive {
+    fn gen_connector(&mut self) -> io::Result<Box<dyn OutProxyStarter>> {
+        Ok(Box::new(VmessOutProxyStarter {
+            host: self.host.clone(),
+            port: self.port,
+            cmd_key: self.cmd_key,
+            security: self.security,
+            option: self.option,
+            dialer: self.dialer.clone(),
+            transport: self.transport.clone(),
+        }))
+    }
+}
+
+struct VmessOutProxyStarter {
+    host: String,
+    port: u16,
+    cmd_key: [u8; 16],
+    security: Security,
+    option: u8,
+    dialer: Dialer,
+    transport: ClientTransport,
+}
+
+#[async_trait]
+impl OutProxyStarter for VmessOutProxyStarter {
+    async fn new_connection(&mut self, proxy_info: ProxyInfo) -> io::Result<(Box<dyn ProxyReader>, Box<dyn ProxyWriter>)> {
+        debug!(
+            "new connect to {}",
+            Address::ip_str(&proxy_info.address, proxy_info.port, &proxy_info.address_type)
+        );
+        let request = Request::new(self.option, self.security);
+        let header = {
+            let mut rng = rand::thread_rng();
+            let mut padding = vec![0u8; rng.gen_range(0..16)];
+            rng.fill_bytes(&mut padding);
+            seal_header(&self.cmd_key, &request_header(&request, &padding, &proxy_info)?).map_err(change_error)?
+        };
+
+        let stream = self.transport.connect(self.dialer.dial(&self.host, self.port).await?).await?;
+        let (read_half, mut write_half) = split(stream);
+        // Sent at once, the remote may speak first, such as SMTP and SSH.
+        write_half.write_all(&header).await?;
+        Ok((
+            Box::new(VmessReader::new(read_half, &request)),
+            Box::new(VmessWriter::new(write_half, &request)),
+        ))
+    }
+}
+
+fn new_body_cipher(request: &Request, key: &[u8; 16], iv: &[u8; 16]) -> BodyCipher {
+    BodyCipher::new(
+        request.security,
+        key,
+        iv,
+        request.option & OPTION_CHUNK_MASKING != 0,
+        request.option & OPTION_GLOBAL_PADDING != 0,
+    )
+}
+
+struct VmessReader {
+    read_half: StreamReadHalf,
+    key: [u8; 16],
+    iv: [u8; 16],
+    response_auth: Option<u8>,
+    cipher: BodyCipher,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl VmessReader {
+    fn new(read_half: StreamReadHalf, request: &Request) -> Self {
+        let key = response_key(&request.key);
+        let iv = response_key(&request.iv);
+        Self {
+            read_half,
+            key,
+            iv,
+            response_auth: Some(request.response_auth),
+            cipher: new_body_cipher(request, &key, &iv),
+            buffer: vec![0u8; READ_BUFFER_SIZE],
+            eof: false,
+        }
+    }
+
+    /// The response header starts with the byte chosen in the request, the commands after it are ignored.
+    async fn read_response_header(&mut self, response_auth: u8) -> io::Result<()> {
+        let mut length = [0u8; SEALED_LENGTH_SIZE];
+        self.read_half.read_exact(&mut length).await?;
+        let length = open_response_length(&self.key, &self.iv, &mut length).map_err(change_error)?;
+        let sealed = &mut self.buffer[..length + TAG_SIZE];
+        self.read_half.read_exact(sealed).await?;
+        let header = open_response_header(&self.key, &self.iv, sealed).map_err(change_error)?;
+        if header.first() != Some(&response_auth) {
+            return Err(Error::new(ErrorKind::InvalidData, "Unexpected VMess response header"));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ProxyReader for VmessReader {
+    async fn read(&mut self) -> io::Result<&mut [u8]> {
+        if let Some(response_auth) = self.response_auth.take() {
+            self.read_response_header(response_auth).await?;
+        }
+        if self.eof {
+            return Ok(&mut []);
+        }
+        let mut size = [0u8; 2];
+        match self.read_half.read_exact(&mut size).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                self.eof = true;
+                return Ok(&mut []);
+            }
+            Err(e) => return Err(e),
+        }
+        let (size, padding) = self.cipher.open_size(size).map_err(change_error)?;
+        self.read_half.read_exact(&mut self.buffer[..size]).await?;
+        let data = self.cipher.open_chunk(&mut self.buffer[..size - padding]).map_err(change_error)?;
+        self.eof = data.is_empty();
+        Ok(data)
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct VmessWriter {
+    write_half: StreamWriteHalf,
+    cipher: BodyCipher,
+    buffer: Vec<u8>,
+}
+
+impl VmessWriter {
+    fn new(write_half: StreamWriteHalf, request: &Request) -> Self {
+        Self {
+            write_half,
+            cipher: new_body_cipher(request, &request.key, &request.iv),
+            buffer: vec![],
+        }
+    }
+}
+
+#[async_trait]
+impl ProxyWriter for VmessWriter {
+    async fn write(&mut self, raw_data: &mut [u8]) -> io::Result<()> {
+        // An empty chunk would end the body.
+        if raw_data.is_empty() {
+            return Ok(());
+        }
+        self.buffer.clear();
+        for chunk in raw_data.chunks(MAX_CHUNK_DATA) {
+            self.cipher.seal_chunk(chunk, &mut self.buffer).map_err(change_error)?;
+        }
+        self.write_half.write_all(&self.buffer).await
+    }
+
+    /// Send the empty chunk which ends the body.
+    async fn shutdown(&mut self) -> io::Result<()> {
+        self.buffer.clear();
+        self.cipher.seal_chunk(&[], &mut self.buffer).map_err(change_error)?;
+        self.write_half.write_all(&self.buffer).await?;
+        self.write_half.shutdown().await
+    }
+}
+//<--<--<--<--<--<--<--<--<--<--<--<--VMESS_OUT_PROXY--<--<--<--<--<--<--<--<--<--<--<--<
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::encrypt::vmess::Security;
+    use crate::net::proxy::{ProxyInfo, ProxyReader};
+    use crate::net::stream::split;
+    use crate::net::vmess::{request_header, Request, VmessReader};
+    use crate::net::AddressType;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// The same request as the vectors of `encrypt::vmess`, with the masking and the padding.
+    fn request() -> Request {
+        Request {
+            key: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+            iv: [16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31],
+            response_auth: 0x2a,
+            option: 0x0d,
+            security: Security::Aes128Gcm,
+        }
+    }
+
+    #[test]
+    fn header_format() {
+        let info = ProxyInfo {
+            address_type: AddressType::Domain,
+            address: b"a.com".to_vec(),
+            port: 443,
+        };
+        let header = request_header(&request(), &[0, 0, 0], &info).unwrap();
+        assert_eq!(
+            header,
+            unhex("01101112131415161718191a1b1c1d1e1f000102030405060708090a0b0c0d0e0f2a0d33000101bb0205612e636f6d000000190b235c")
+        );
+    }
+
+    #[tokio::test]
+    async fn read_response() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let response = unhex(
+            "936c422a7a3f0c4835561f50648898c4d6e3da10e4a6a796c6b2adda265894aa176da8de0a92c2377ae9414f5ec4bb64b4ba72a7\
+             76673ca2cc04974feeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeedeaaf75b631b09778035360a05d18570\
+             160ceeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee\
+             eeeeeeeeeeeeeeeeee",
+        );
+        server.write_all(&response).await.unwrap();
+        let (read_half, _write_half) = split(Box::new(client));
+        let mut reader = VmessReader::new(read_half, &request());
+        assert_eq!(reader.read().await.unwrap(), b"pong");
+        assert!(reader.read().await.unwrap().is_empty());
+    }
+}
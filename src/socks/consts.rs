@@ -10,42 +10,58 @@ use crate::net::AddressType;
 //     pub address: Box<Vec<u8>>,
 //     pub port: u16,
 // }
-//
-// pub enum Command {
-//     //0x01 连接
-//     Connect,
-//     //0x02 端口监听
-//     Bind,
-//     //0x03 使用UDP
-//     UdpAssociate,
-// }
-//
-// impl Command {
-//     pub fn with_byte(cmd: u8) -> Result<Command, Error> {
-//         match cmd {
-//             0x01 => Ok(Command::Connect),
-//             0x02 => Ok(Command::Bind),
-//             0x03 => Ok(Command::UdpAssociate),
-//             _ => Err(Error::new(ErrorKind::InvalidInput, "暂不支持此方法")),
-//         }
-//     }
-// }
-//
-// /// SOCKS的版本协议，本程序只实现V5版本
-// pub enum SocksVersion {
-//     V5,
-//     V4,
-// }
-//
-// impl SocksVersion {
-//     pub fn with_byte(version: u8) -> SocksVersion {
-//         match version {
-//             0x05 => SocksVersion::V5,
-//             0x04 => SocksVersion::V4,
-//             _ => SocksVersion::V5,
-//         }
-//     }
-// }
+
+/// SOCKS5 请求的命令
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    //0x01 连接
+    Connect,
+    //0x02 端口监听
+    Bind,
+    //0x03 使用UDP
+    UdpAssociate,
+}
+
+impl Command {
+    pub fn with_byte(cmd: u8) -> Option<Command> {
+        match cmd {
+            0x01 => Some(Command::Connect),
+            0x02 => Some(Command::Bind),
+            0x03 => Some(Command::UdpAssociate),
+            _ => None,
+        }
+    }
+}
+
+/// REP of the SOCKS5 replies
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+pub const REPLY_TTL_EXPIRED: u8 = 0x06;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// SOCKS的版本协议, the first byte of a request
+#[derive(Debug, PartialEq)]
+pub enum SocksVersion {
+    V5,
+    V4,
+}
+
+impl SocksVersion {
+    pub fn with_byte(version: u8) -> io::Result<SocksVersion> {
+        match version {
+            0x05 => Ok(SocksVersion::V5),
+            0x04 => Ok(SocksVersion::V4),
+            n => Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("Unsupported SOCKS version {}", n),
+            )),
+        }
+    }
+}
 
 impl AddressType {
     pub fn with_byte(address_type: u8) -> io::Result<AddressType> {
@@ -1,3 +1,4 @@
 pub mod consts;
+pub mod socks4;
 pub mod socks5;
 pub mod socks5_connector;
@@ -0,0 +1,112 @@
+use std::io::{Error, ErrorKind, Result};
+
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::net::proxy::ProxyInfo;
+use crate::net::stream::BoxStream;
+use crate::net::AddressType;
+
+const CMD_CONNECT: u8 = 0x01;
+const REPLY_VERSION: u8 = 0x00;
+const REQUEST_GRANTED: u8 = 90;
+const REQUEST_REJECTED: u8 = 91;
+/// Max length of the user ID and the domain, without the NULL.
+const MAX_FIELD_SIZE: usize = 255;
+
+/// SOCKS4 and SOCKS4a, only CONNECT
+pub struct Socks4Server<'a> {
+    tcp_stream: &'a mut BoxStream,
+}
+
+impl<'a> Socks4Server<'a> {
+    pub fn new(tcp: &'a mut BoxStream) -> Self {
+        Self { tcp_stream: tcp }
+    }
+
+    /// Read the request after the version byte.
+    /// An IP of `0.0.0.x` with a non-zero `x` is SOCKS4a, the domain follows the user ID.
+    pub async fn read_request(&mut self) -> Result<ProxyInfo> {
+        let cmd = self.tcp_stream.read_u8().await?;
+        let port = self.tcp_stream.read_u16().await?;
+        let mut ip = [0u8; 4];
+        self.tcp_stream.read_exact(&mut ip).await?;
+        let user_id = self.read_field().await?;
+        let socks4a = ip[..3] == [0, 0, 0] && ip[3] != 0;
+        let domain = if socks4a { Some(self.read_field().await?) } else { None };
+        if cmd != CMD_CONNECT {
+            self.write_reply(false).await?;
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("Unsupported SOCKS4 command {}", cmd),
+            ));
+        }
+        debug!("SOCKS4 user ID {}", String::from_utf8_lossy(&user_id));
+        Ok(match domain {
+            Some(domain) => ProxyInfo {
+                address_type: AddressType::Domain,
+                address: domain,
+                port,
+            },
+            None => ProxyInfo {
+                address_type: AddressType::IPv4,
+                address: ip.to_vec(),
+                port,
+            },
+        })
+    }
+
+    /// Read a NULL-terminated field.
+    async fn read_field(&mut self) -> Result<Vec<u8>> {
+        let mut field = vec![];
+        loop {
+            match self.tcp_stream.read_u8().await? {
+                0 => return Ok(field),
+                _ if field.len() == MAX_FIELD_SIZE => return Err(Error::new(ErrorKind::InvalidData, "SOCKS4 field is too long")),
+                byte => field.push(byte),
+            }
+        }
+    }
+
+    /// The address of the reply is ignored by the clients of CONNECT.
+    pub async fn write_reply(&mut self, granted: bool) -> Result<()> {
+        let status = if granted { REQUEST_GRANTED } else { REQUEST_REJECTED };
+        self.tcp_stream.write_all(&[REPLY_VERSION, status, 0, 0, 0, 0, 0, 0]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::net::stream::BoxStream;
+    use crate::net::AddressType;
+    use crate::socks::socks4::Socks4Server;
+
+    #[tokio::test]
+    async fn read_requests() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (mut client, mut server): (BoxStream, BoxStream) = (Box::new(client), Box::new(server));
+        // SOCKS4 to 1.2.3.4:80, then SOCKS4a to a.com:443, after the version bytes.
+        client.write_all(b"\x01\x00\x50\x01\x02\x03\x04user\x00").await.unwrap();
+        client.write_all(b"\x01\x01\xbb\x00\x00\x00\x01\x00a.com\x00").await.unwrap();
+        client.write_all(b"\x02\x00\x50\x01\x02\x03\x04\x00").await.unwrap();
+
+        let mut socks4 = Socks4Server::new(&mut server);
+        let info = socks4.read_request().await.unwrap();
+        assert_eq!(
+            (info.address_type, info.address, info.port),
+            (AddressType::IPv4, vec![1, 2, 3, 4], 80)
+        );
+        let info = socks4.read_request().await.unwrap();
+        assert_eq!(
+            (info.address_type, info.address, info.port),
+            (AddressType::Domain, b"a.com".to_vec(), 443)
+        );
+        // BIND is rejected.
+        assert!(socks4.read_request().await.is_err());
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0, 91, 0, 0, 0, 0, 0, 0]);
+    }
+}
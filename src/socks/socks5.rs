@@ -1,43 +1,96 @@
 use std::borrow::Borrow;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 use crate::net::proxy::ProxyInfo;
 use crate::net::AddressType;
 
 pub struct Socks5 {}
 
+/// Errors of parsing a socks5 address.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The bytes are too short, at least this many bytes are needed.
+    Incomplete(usize),
+    UnknownAddressType(u8),
+    /// The domain is empty or isn't UTF-8.
+    InvalidDomain,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete(size) => write!(f, "Incomplete socks5 address, {} bytes are needed", size),
+            ParseError::UnknownAddressType(byte) => write!(f, "Unknown socks5 address type {}", byte),
+            ParseError::InvalidDomain => write!(f, "Invalid socks5 domain"),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> Self {
+        let kind = match e {
+            ParseError::Incomplete(_) => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
 impl Socks5 {
+    /// Parse a socks5 address and its port from the start of the bytes.
     /// # Return value
-    /// - `String` Host address
-    /// - `u8` Number of bytes read
-    pub fn read_to_socket_addrs(bytes: &[u8]) -> (ProxyInfo, usize) {
-        let addr_type = bytes[0];
-        match addr_type {
-            0x01 => (
-                ProxyInfo {
-                    address_type: AddressType::IPv4,
-                    address: vec![bytes[1], bytes[2], bytes[3], bytes[4]],
-                    port: u16::from_be_bytes([bytes[5], bytes[6]]),
-                },
-                7,
-            ),
-            0x04 => (
-                ProxyInfo {
-                    address_type: AddressType::IPv6,
-                    address: bytes[1..17].to_vec(),
-                    port: u16::from_be_bytes([bytes[17], bytes[18]]),
-                },
-                19,
-            ),
-            _ => {
-                let domain_len = bytes[1] as usize;
-                (
-                    ProxyInfo {
-                        address_type: AddressType::IPv6,
-                        address: bytes[2..(domain_len + 2)].to_vec(),
-                        port: u16::from_be_bytes([bytes[domain_len + 2], bytes[domain_len + 3]]),
-                    },
-                    4 + domain_len,
-                )
+    /// - `ProxyInfo` The address
+    /// - `usize` Number of bytes parsed
+    pub fn parse_addr(bytes: &[u8]) -> Result<(ProxyInfo, usize), ParseError> {
+        let address_type = match bytes.first() {
+            None => return Err(ParseError::Incomplete(1)),
+            Some(0x01) => AddressType::IPv4,
+            Some(0x03) => AddressType::Domain,
+            Some(0x04) => AddressType::IPv6,
+            Some(&byte) => return Err(ParseError::UnknownAddressType(byte)),
+        };
+        let (start, address_len) = match address_type {
+            AddressType::IPv4 => (1, 4),
+            AddressType::IPv6 => (1, 16),
+            AddressType::Domain => match bytes.get(1) {
+                None => return Err(ParseError::Incomplete(2)),
+                Some(&len) => (2, len as usize),
+            },
+        };
+        let size = start + address_len + 2;
+        if bytes.len() < size {
+            return Err(ParseError::Incomplete(size));
+        }
+        let address = &bytes[start..start + address_len];
+        if address_type == AddressType::Domain && (address.is_empty() || std::str::from_utf8(address).is_err()) {
+            return Err(ParseError::InvalidDomain);
+        }
+        let info = ProxyInfo {
+            address_type,
+            address: address.to_vec(),
+            port: u16::from_be_bytes([bytes[size - 2], bytes[size - 1]]),
+        };
+        Ok((info, size))
+    }
+
+    /// Read a socks5 address and its port from a stream.
+    pub async fn read_addr<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<ProxyInfo> {
+        let mut buf = vec![];
+        loop {
+            match Self::parse_addr(&buf) {
+                Ok((info, _)) => return Ok(info),
+                Err(ParseError::Incomplete(size)) => {
+                    let read = buf.len();
+                    buf.resize(size, 0);
+                    reader.read_exact(&mut buf[read..]).await?;
+                }
+                Err(e) => return Err(e.into()),
             }
         }
     }
@@ -45,14 +98,22 @@ impl Socks5 {
     /// Change address to socks5 bytes.
     /// # Example
     /// host:127.0.0.1 port:80 ---->  [1,127,0,0,1,0,80]
-    pub fn socks5_addr_arr(host: &Vec<u8>, port: u16, addr_type: &AddressType) -> Box<[u8]> {
-        match addr_type {
+    ///
+    /// A domain longer than 255 bytes doesn't fit in the length byte and is rejected.
+    pub fn socks5_addr_arr(host: &Vec<u8>, port: u16, addr_type: &AddressType) -> io::Result<Box<[u8]>> {
+        Ok(match addr_type {
             AddressType::IPv4 => {
                 let port_byte: [u8; 2] = port.to_be_bytes();
                 [1, host[0], host[1], host[2], host[3], port_byte[0], port_byte[1]].into()
             }
             AddressType::Domain => {
                 let host_len = host.len();
+                if host_len > u8::MAX as usize {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("The domain is {} bytes, longer than 255", host_len),
+                    ));
+                }
                 let mut vec = vec![0u8; host_len + 4];
                 vec[0] = 0x03;
                 vec[1] = host_len as u8;
@@ -66,19 +127,64 @@ impl Socks5 {
                 arr[17..19].copy_from_slice(&port.to_be_bytes());
                 arr.into()
             }
-        }
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::socks::socks5::Socks5;
+    use crate::net::AddressType;
+    use crate::socks::socks5::{ParseError, Socks5};
+
+    #[test]
+    fn parse_addr() {
+        let (info, size) = Socks5::parse_addr(&[1, 127, 0, 0, 1, 0, 80, 0xff]).unwrap();
+        assert_eq!(
+            (info.address_type, info.address, info.port, size),
+            (AddressType::IPv4, vec![127, 0, 0, 1], 80, 7)
+        );
+        let (info, size) = Socks5::parse_addr(b"\x03\x05a.com\x01\xbb").unwrap();
+        assert_eq!(
+            (info.address_type, info.address, info.port, size),
+            (AddressType::Domain, b"a.com".to_vec(), 443, 9)
+        );
+        let mut ipv6 = [0u8; 19];
+        ipv6[0] = 4;
+        ipv6[16] = 1;
+        let (info, size) = Socks5::parse_addr(&ipv6).unwrap();
+        assert_eq!((info.address_type, info.port, size), (AddressType::IPv6, 0, 19));
+
+        assert_eq!(Socks5::parse_addr(&[]).err(), Some(ParseError::Incomplete(1)));
+        assert_eq!(Socks5::parse_addr(&[3]).err(), Some(ParseError::Incomplete(2)));
+        assert_eq!(Socks5::parse_addr(b"\x03\x05a.c").err(), Some(ParseError::Incomplete(9)));
+        assert_eq!(
+            Socks5::parse_addr(&[1, 127, 0, 0, 1, 0]).err(),
+            Some(ParseError::Incomplete(7))
+        );
+        assert_eq!(Socks5::parse_addr(&[2, 0, 0]).err(), Some(ParseError::UnknownAddressType(2)));
+        assert_eq!(Socks5::parse_addr(&[3, 0, 0, 80]).err(), Some(ParseError::InvalidDomain));
+        assert_eq!(
+            Socks5::parse_addr(&[3, 1, 0xff, 0, 80]).err(),
+            Some(ParseError::InvalidDomain)
+        );
+    }
+
+    #[tokio::test]
+    async fn read_addr() {
+        let mut reader: &[u8] = b"\x03\x05a.com\x01\xbbdata";
+        let info = Socks5::read_addr(&mut reader).await.unwrap();
+        assert_eq!((info.address, info.port), (b"a.com".to_vec(), 443));
+        assert_eq!(reader, b"data");
+        let mut reader: &[u8] = &[1, 127, 0];
+        assert!(Socks5::read_addr(&mut reader).await.is_err());
+    }
 
     #[test]
-    fn test() {
-        let mut bytes = [0u8; 128];
-        bytes[0] = 1;
-        let (info, _size) = Socks5::read_to_socket_addrs(&bytes);
-        println!("{:?}", info);
+    fn socks5_addr_arr() {
+        let arr = Socks5::socks5_addr_arr(&b"a.com".to_vec(), 443, &AddressType::Domain).unwrap();
+        assert_eq!(&*arr, b"\x03\x05a.com\x01\xbb");
+        assert!(Socks5::socks5_addr_arr(&vec![b'a'; 255], 80, &AddressType::Domain).is_ok());
+        let err = Socks5::socks5_addr_arr(&vec![b'a'; 256], 80, &AddressType::Domain).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
     }
 }
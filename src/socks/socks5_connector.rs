@@ -1,41 +1,44 @@
 use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
 
 use crate::net::proxy::ProxyInfo;
+use crate::net::stream::BoxStream;
 use crate::net::AddressType;
+use crate::socks::consts::{
+    Command, REPLY_COMMAND_NOT_SUPPORTED, REPLY_CONNECTION_REFUSED, REPLY_HOST_UNREACHABLE, REPLY_NETWORK_UNREACHABLE,
+    REPLY_NOT_ALLOWED, REPLY_SUCCEEDED, REPLY_TTL_EXPIRED,
+};
+use crate::socks::socks5::Socks5;
 
 /// Socks5 协议
 pub struct Socks5Server<'a> {
-    tcp_stream: &'a mut TcpStream,
+    tcp_stream: &'a mut BoxStream,
 }
 
 impl<'a> Socks5Server<'a> {
-    pub fn new(tcp: &'a mut TcpStream) -> Self {
+    pub fn new(tcp: &'a mut BoxStream) -> Self {
         Self { tcp_stream: tcp }
     }
 
-    fn method_size(socks5_head: &[u8]) -> Result<u8> {
-        if socks5_head[0] != 5 {
-            let err_str = format!("Unsupport Socks5 version:'{}", socks5_head[0]);
-            return Err(Error::new(ErrorKind::ConnectionAborted, err_str));
-        }
-        Ok(socks5_head[1])
-    }
-
-    /// 检验协议头并建立连接的主要方法
-    pub async fn accept_check(&mut self) -> Result<ProxyInfo> {
-        let mut head = vec![0u8; 2];
-        self.tcp_stream.read_exact(&mut head).await?;
-        let method_size = Socks5Server::method_size(head.as_slice())?;
+    /// 检验协议头并建立连接的主要方法, after the version byte.
+    /// Unknown commands are answered and returned as errors, the caller replies to the others.
+    pub async fn accept_check(&mut self) -> Result<(Command, ProxyInfo)> {
+        let method_size = self.tcp_stream.read_u8().await?;
         //read client methods
         let mut first_method_arr = vec![0u8; method_size as usize];
         self.tcp_stream.read_exact(&mut first_method_arr).await?;
         //write server methods
         self.write_server_methods().await?;
-        let info = self.read_address().await?;
-        Ok(info)
+        let (cmd, info) = self.read_address().await?;
+        match Command::with_byte(cmd) {
+            Some(command) => Ok((command, info)),
+            None => {
+                self.write_reply(REPLY_COMMAND_NOT_SUPPORTED, SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+                Err(Error::new(ErrorKind::Unsupported, format!("Unknown SOCKS5 command {}", cmd)))
+            }
+        }
     }
 
     /// 向client端写入server端支持的方法
@@ -45,63 +48,29 @@ impl<'a> Socks5Server<'a> {
         self.tcp_stream.write_all(&server_mthod).await
     }
 
-    /// 从TCP流中读取发送过来的地址信息
-    async fn read_address(&mut self) -> Result<ProxyInfo> {
-        let mut address_head = [0u8; 4];
-        self.tcp_stream.read(&mut address_head).await?;
-        let address_type_byte = address_head[3];
-        let address_type = AddressType::with_byte(address_type_byte)?;
-        let address = match address_type {
-            AddressType::IPv4 => self.read_ipv4_address().await,
-            AddressType::Domain => self.read_domain_address().await,
-            _ => return Err(Error::new(ErrorKind::InvalidInput, "不支持的地址类型")),
-        };
-        let port = self.read_port().await?;
-        self.write_success_connect(port).await?;
-        Ok(ProxyInfo {
-            address_type,
-            address: address?,
-            port,
-        })
-    }
-
-    /// 从TCP流中读取4个字节并返回
-    async fn read_ipv4_address(&mut self) -> Result<Vec<u8>> {
-        let mut ip_arr = vec![0u8; 4];
-        self.tcp_stream.read_exact(ip_arr.as_mut()).await?;
-        Ok(ip_arr)
-    }
-
-    /// 从TCP流中读取域名版的地址
-    async fn read_domain_address(&mut self) -> Result<Vec<u8>> {
-        let length = self.tcp_stream.read_u8().await?;
-        let mut domain_addr = vec![0u8; length as usize];
-        self.tcp_stream.read_exact(domain_addr.as_mut()).await?;
-        Ok(domain_addr)
-    }
-
-    /// 从TCP流中读取端口号
-    async fn read_port(&mut self) -> Result<u16> {
-        let len = self.tcp_stream.read_u16().await?;
-        Ok(len)
+    /// 从TCP流中读取发送过来的命令和地址信息
+    async fn read_address(&mut self) -> Result<(u8, ProxyInfo)> {
+        let mut head = [0u8; 3];
+        self.tcp_stream.read_exact(&mut head).await?;
+        let info = Socks5::read_addr(&mut self.tcp_stream).await?;
+        Ok((head[1], info))
     }
 
-    /// 向客户端写入连接成功的消息
-    async fn write_success_connect(&mut self, port: u16) -> Result<()> {
-        //连接成功的字节中，前8位是固定的，后两位是端口
-        let mut head: [u8; 10] = [5, 0, 0, 1, 0, 0, 0, 0, 0, 0];
-        let port_arr = port.to_be_bytes();
-        head[8..10].copy_from_slice(&port_arr);
+    /// Write a reply with the address bound by the server, which is unspecified for the failures.
+    pub async fn write_reply(&mut self, reply: u8, addr: SocketAddr) -> Result<()> {
+        let info = ProxyInfo::from(addr);
+        let mut head = vec![5, reply, 0];
+        head.extend_from_slice(&Socks5::socks5_addr_arr(&info.address, info.port, &info.address_type)?);
         self.tcp_stream.write_all(&head).await
     }
 }
 
 pub struct Sock5ClientConnector<'a> {
-    tcp_stream: &'a mut TcpStream,
+    tcp_stream: &'a mut BoxStream,
 }
 
 impl<'a> Sock5ClientConnector<'a> {
-    pub fn new(tcp: &'a mut TcpStream) -> Self {
+    pub fn new(tcp: &'a mut BoxStream) -> Self {
         Self { tcp_stream: tcp }
     }
 
@@ -115,46 +84,84 @@ impl<'a> Sock5ClientConnector<'a> {
             return Err(Error::new(ErrorKind::InvalidInput, "Connect socks5 server error."));
         }
 
-        let (socks5_type_byte, socks5_addr_bytes) = match proxy_info.address_type {
-            AddressType::IPv4 => {
-                let mut bytes = vec![0u8; 4];
-                bytes.copy_from_slice(&proxy_info.address[..4]);
-                (1u8, bytes)
-            }
-            AddressType::Domain => {
-                let mut domain_bytes = vec![0u8; 1 + proxy_info.address.len()];
-                domain_bytes[0] = proxy_info.address.len() as u8;
-                domain_bytes[1..].copy_from_slice(&proxy_info.address);
-                (3u8, domain_bytes)
-            }
-            AddressType::IPv6 => {
-                let mut bytes = vec![0u8; 16];
-                bytes.copy_from_slice(&proxy_info.address[..16]);
-                (4u8, bytes)
-            }
-        };
         // Write proxy info
-        let fixed = [5, 1, 0, socks5_type_byte];
-        let mut second_write = vec![0u8; 4 + socks5_addr_bytes.len() + 2];
-        second_write[..4].copy_from_slice(&fixed);
-        second_write[4..4 + socks5_addr_bytes.len()].copy_from_slice(&socks5_addr_bytes);
-        second_write[4 + socks5_addr_bytes.len()..].copy_from_slice(&proxy_info.port.to_be_bytes());
+        let mut second_write = vec![5u8, 1, 0];
+        second_write.extend_from_slice(&Socks5::socks5_addr_arr(
+            &proxy_info.address,
+            proxy_info.port,
+            &proxy_info.address_type,
+        )?);
         self.tcp_stream.write_all(&second_write).await?;
         //  read connect success info
         let mut address_head = [0u8; 4];
-        self.tcp_stream.read(&mut address_head).await?;
-        if address_head[0] != 5 || address_head[1] != 0 || address_head[2] != 0 {
+        self.tcp_stream.read_exact(&mut address_head).await?;
+        if address_head[0] != 5 || address_head[2] != 0 {
             return Err(Error::new(ErrorKind::InvalidInput, "Connect socks5 server failed."));
         }
+        if address_head[1] != REPLY_SUCCEEDED {
+            return Err(Error::new(
+                reply_error_kind(address_head[1]),
+                format!("Socks5 server replied {}", address_head[1]),
+            ));
+        }
         let address_type_byte = address_head[3];
         let address_type = AddressType::with_byte(address_type_byte)?;
         let address_len = match address_type {
-            AddressType::IPv4 => 4 as usize,
+            AddressType::IPv4 => 4,
             AddressType::Domain => self.tcp_stream.read_u8().await? as usize,
-            AddressType::IPv6 => 16 as usize,
+            AddressType::IPv6 => 16,
         };
         let mut addr_port_vec = vec![0u8; address_len + 2];
         let _size = self.tcp_stream.read_exact(&mut addr_port_vec).await?;
         Ok(())
     }
 }
+
+/// The error of a failure reply, the reverse of the reply written by the socks5 input.
+fn reply_error_kind(reply: u8) -> ErrorKind {
+    match reply {
+        REPLY_NOT_ALLOWED => ErrorKind::PermissionDenied,
+        REPLY_NETWORK_UNREACHABLE => ErrorKind::NetworkUnreachable,
+        REPLY_HOST_UNREACHABLE => ErrorKind::HostUnreachable,
+        REPLY_CONNECTION_REFUSED => ErrorKind::ConnectionRefused,
+        REPLY_TTL_EXPIRED => ErrorKind::TimedOut,
+        REPLY_COMMAND_NOT_SUPPORTED => ErrorKind::Unsupported,
+        _ => ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::net::proxy::ProxyInfo;
+    use crate::net::stream::BoxStream;
+    use crate::socks::socks5_connector::Sock5ClientConnector;
+
+    #[tokio::test]
+    async fn failure_replies() {
+        for (rep, kind) in [
+            (2, ErrorKind::PermissionDenied),
+            (5, ErrorKind::ConnectionRefused),
+            (6, ErrorKind::TimedOut),
+        ] {
+            let (client, mut server) = tokio::io::duplex(1024);
+            let mut client: BoxStream = Box::new(client);
+            let info = ProxyInfo::from(SocketAddr::from(([127, 0, 0, 1], 80)));
+            let server = tokio::spawn(async move {
+                let mut request = [0u8; 13];
+                server.read_exact(&mut request[..3]).await.unwrap();
+                server.write_all(&[5, 0]).await.unwrap();
+                server.read_exact(&mut request[3..]).await.unwrap();
+                server.write_all(&[5, rep, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+                request
+            });
+            let err = Sock5ClientConnector::new(&mut client).try_connect(&info).await.unwrap_err();
+            assert_eq!(err.kind(), kind);
+            assert_eq!(server.await.unwrap(), [5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80]);
+        }
+    }
+}
@@ -3,6 +3,8 @@ use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 
 use tokio::net::TcpStream;
 
+use crate::net::dialer::Dialer;
+use crate::net::dns::DnsResolver;
 use crate::net::AddressType;
 
 pub struct Address {}
@@ -24,22 +26,22 @@ impl Address {
         }
     }
 
-    /// Creat a new TCP connect with [AddressType]
-    pub async fn new_connect(ip_data: &[u8], port: u16, addr_type: &AddressType) -> io::Result<TcpStream> {
+    /// Creat a new TCP connect with [AddressType], domains are resolved by the global resolver.
+    pub async fn new_connect(dialer: &Dialer, ip_data: &[u8], port: u16, addr_type: &AddressType) -> io::Result<TcpStream> {
         match addr_type {
             AddressType::IPv4 => {
                 let ipv4_addr = Ipv4Addr::new(ip_data[0], ip_data[1], ip_data[2], ip_data[3]);
-                TcpStream::connect(SocketAddrV4::new(ipv4_addr, port)).await
+                dialer.connect(SocketAddrV4::new(ipv4_addr, port).into()).await
             }
             AddressType::Domain => {
-                let addr = format!("{}:{}", String::from_utf8_lossy(ip_data), port);
-                TcpStream::connect(addr.as_str()).await
+                let domain = String::from_utf8_lossy(ip_data);
+                dialer.connect_host(DnsResolver::global(), &domain, port).await
             }
             AddressType::IPv6 => {
                 let mut ip_arr = [0u8; 16];
                 ip_arr.copy_from_slice(&ip_data[..16]);
                 let ipv6_addr = Ipv6Addr::from(ip_arr);
-                TcpStream::connect(SocketAddrV6::new(ipv6_addr, port, 0, 0)).await
+                dialer.connect(SocketAddrV6::new(ipv6_addr, port, 0, 0).into()).await
             }
         }
     }